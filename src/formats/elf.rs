@@ -0,0 +1,556 @@
+//! Conversion of executable modules to ELF
+//!
+//! NSO and NRO modules are loaded from three segments, `.text`, `.rodata` and `.data`, plus
+//! a zero-filled `.bss`. A [`ModuleImage`] holds those segments at their memory offsets and
+//! can be written out as an AArch64 ELF64 shared object, so tools like `readelf` and
+//! `objdump` can load it.
+//!
+//! The dynamic section is found through the MOD0 header, whose offset is stored in the
+//! second word of `.text`. The section headers are rebuilt from the dynamic section.
+
+use binrw::prelude::*;
+use std::io::Cursor;
+
+use crate::error::Error;
+
+/// Largest module image accepted, far above any real NSO or NRO
+///
+/// Segment offsets come straight from the file, so this keeps a corrupt header from
+/// making [`ModuleImage::to_flat`] allocate gigabytes.
+pub const MAX_MODULE_SIZE: u64 = 0x1000_0000;
+
+/// File offset of the image in the ELF, leaving room for the headers before it
+const IMAGE_FILE_OFFSET: u64 = 0x1000;
+const PAGE_SIZE: u64 = 0x1000;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_GNU_EH_FRAME: u32 = 0x6474E550;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_HASH: u32 = 5;
+const SHT_DYNAMIC: u32 = 6;
+const SHT_NOBITS: u32 = 8;
+const SHT_DYNSYM: u32 = 11;
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+
+const DT_NULL: u64 = 0;
+const DT_PLTRELSZ: u64 = 2;
+const DT_HASH: u64 = 4;
+const DT_STRTAB: u64 = 5;
+const DT_SYMTAB: u64 = 6;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_STRSZ: u64 = 10;
+const DT_JMPREL: u64 = 23;
+
+const DYNAMIC_ENTRY_SIZE: u64 = 0x10;
+const SYMBOL_SIZE: u64 = 0x18;
+const RELA_SIZE: u64 = 0x18;
+
+/// The MOD0 header of a module
+#[binrw]
+#[brw(little, magic = b"MOD0")]
+#[derive(Debug, Clone, Copy)]
+pub struct Mod0Header {
+    /// Offsets are relative to the MOD0 header
+    pub dynamic_offset: i32,
+    pub bss_start_offset: i32,
+    pub bss_end_offset: i32,
+    pub eh_frame_hdr_start_offset: i32,
+    pub eh_frame_hdr_end_offset: i32,
+    pub module_object_offset: i32,
+}
+
+#[binrw]
+#[brw(little)]
+struct ElfHeader {
+    ident: [u8; 0x10],
+    elf_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    program_header_offset: u64,
+    section_header_offset: u64,
+    flags: u32,
+    header_size: u16,
+    program_header_size: u16,
+    program_header_count: u16,
+    section_header_size: u16,
+    section_header_count: u16,
+    section_name_index: u16,
+}
+
+#[binrw]
+#[brw(little)]
+struct ProgramHeader {
+    segment_type: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    file_size: u64,
+    memory_size: u64,
+    align: u64,
+}
+
+#[binrw]
+#[brw(little)]
+#[derive(Default)]
+struct SectionHeader {
+    name: u32,
+    section_type: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64,
+}
+
+const ELF_HEADER_SIZE: u16 = 0x40;
+const PROGRAM_HEADER_SIZE: u16 = 0x38;
+const SECTION_HEADER_SIZE: u16 = 0x40;
+
+/// A decompressed segment placed at its memory offset
+#[derive(Debug, Clone, Default)]
+pub struct ModuleSegment {
+    pub memory_offset: u32,
+    pub data: Vec<u8>,
+}
+
+impl ModuleSegment {
+    fn end(&self) -> u64 {
+        self.memory_offset as u64 + self.data.len() as u64
+    }
+}
+
+/// The loaded segments of an NSO or NRO
+#[derive(Debug, Clone, Default)]
+pub struct ModuleImage {
+    pub text: ModuleSegment,
+    pub rodata: ModuleSegment,
+    pub data: ModuleSegment,
+    pub bss_size: u32,
+}
+
+impl ModuleImage {
+    /// The segments laid out at their memory offsets, without `.bss`
+    ///
+    /// Fails if the segments overlap, or the image with `.bss` is over [`MAX_MODULE_SIZE`].
+    pub fn to_flat(&self) -> Result<Vec<u8>, Error> {
+        if self.text.end() > self.rodata.memory_offset as u64
+            || self.rodata.end() > self.data.memory_offset as u64
+        {
+            return Err(Error::InvalidData(
+                "Module segments overlap or are out of order".to_string(),
+            ));
+        }
+        if self.data.end() + self.bss_size as u64 > MAX_MODULE_SIZE {
+            return Err(Error::InvalidData(format!(
+                "Module image is 0x{:X} bytes, more than the 0x{:X} byte limit",
+                self.data.end() + self.bss_size as u64,
+                MAX_MODULE_SIZE
+            )));
+        }
+
+        let mut image = vec![0; self.data.end() as usize];
+        for segment in [&self.text, &self.rodata, &self.data] {
+            let start = segment.memory_offset as usize;
+            image[start..start + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        Ok(image)
+    }
+
+    /// Offset of the MOD0 header, from the second word of `.text`
+    pub fn mod0_offset(&self) -> Result<u32, Error> {
+        let word = self.text.data.get(4..8).ok_or_else(|| {
+            Error::InvalidData(".text is too small for a MOD0 offset".to_string())
+        })?;
+        Ok(self.text.memory_offset + u32::from_le_bytes(word.try_into().unwrap()))
+    }
+
+    /// Convert the module to an AArch64 ELF64 shared object
+    pub fn to_elf(&self) -> Result<Vec<u8>, Error> {
+        let image = self.to_flat()?;
+        let mod0_offset = self.mod0_offset()? as u64;
+        let mod0: Mod0Header = Cursor::new(slice(&image, mod0_offset, 0x1C)?).read_le()?;
+        let relative = |offset: i32| mod0_offset.wrapping_add_signed(offset as i64);
+
+        // Collect the dynamic entries up to DT_NULL
+        let dynamic_addr = relative(mod0.dynamic_offset);
+        let mut dynamic = Vec::new();
+        loop {
+            let entry = slice(
+                &image,
+                dynamic_addr + dynamic.len() as u64 * DYNAMIC_ENTRY_SIZE,
+                DYNAMIC_ENTRY_SIZE,
+            )?;
+            let tag = u64::from_le_bytes(entry[..8].try_into().unwrap());
+            let value = u64::from_le_bytes(entry[8..].try_into().unwrap());
+            dynamic.push((tag, value));
+            if tag == DT_NULL {
+                break;
+            }
+        }
+        let dynamic_value = |tag: u64| {
+            dynamic
+                .iter()
+                .find(|(entry_tag, _)| *entry_tag == tag)
+                .map(|(_, value)| *value)
+        };
+
+        let mut sections = SectionTable::default();
+        sections.push(
+            ".text",
+            SectionHeader {
+                section_type: SHT_PROGBITS,
+                flags: SHF_ALLOC | SHF_EXECINSTR,
+                addr: self.text.memory_offset as u64,
+                size: self.text.data.len() as u64,
+                align: PAGE_SIZE,
+                ..Default::default()
+            },
+        );
+        sections.push(
+            ".rodata",
+            SectionHeader {
+                section_type: SHT_PROGBITS,
+                flags: SHF_ALLOC,
+                addr: self.rodata.memory_offset as u64,
+                size: self.rodata.data.len() as u64,
+                align: PAGE_SIZE,
+                ..Default::default()
+            },
+        );
+        sections.push(
+            ".data",
+            SectionHeader {
+                section_type: SHT_PROGBITS,
+                flags: SHF_ALLOC | SHF_WRITE,
+                addr: self.data.memory_offset as u64,
+                size: self.data.data.len() as u64,
+                align: PAGE_SIZE,
+                ..Default::default()
+            },
+        );
+        let bss_index = sections.push(
+            ".bss",
+            SectionHeader {
+                section_type: SHT_NOBITS,
+                flags: SHF_ALLOC | SHF_WRITE,
+                addr: self.data.end(),
+                size: self.bss_size as u64,
+                align: 8,
+                ..Default::default()
+            },
+        );
+
+        let dynstr_index = dynamic_value(DT_STRTAB).map(|addr| {
+            sections.push(
+                ".dynstr",
+                SectionHeader {
+                    section_type: SHT_STRTAB,
+                    flags: SHF_ALLOC,
+                    addr,
+                    size: dynamic_value(DT_STRSZ).unwrap_or(0),
+                    align: 1,
+                    ..Default::default()
+                },
+            )
+        });
+        let dynsym_index = match dynamic_value(DT_SYMTAB) {
+            Some(addr) => {
+                let count = symbol_count(&image, addr, &dynamic_value)?;
+                Some(sections.push(
+                    ".dynsym",
+                    SectionHeader {
+                        section_type: SHT_DYNSYM,
+                        flags: SHF_ALLOC,
+                        addr,
+                        size: count * SYMBOL_SIZE,
+                        link: dynstr_index.unwrap_or(0),
+                        info: first_global_symbol(&image, addr, count)?,
+                        align: 8,
+                        entry_size: SYMBOL_SIZE,
+                        ..Default::default()
+                    },
+                ))
+            }
+            None => None,
+        };
+        if let Some(addr) = dynamic_value(DT_HASH) {
+            let counts = slice(&image, addr, 8)?;
+            let bucket_count = u32::from_le_bytes(counts[..4].try_into().unwrap()) as u64;
+            let chain_count = u32::from_le_bytes(counts[4..].try_into().unwrap()) as u64;
+            sections.push(
+                ".hash",
+                SectionHeader {
+                    section_type: SHT_HASH,
+                    flags: SHF_ALLOC,
+                    addr,
+                    size: (2 + bucket_count + chain_count) * 4,
+                    link: dynsym_index.unwrap_or(0),
+                    align: 4,
+                    entry_size: 4,
+                    ..Default::default()
+                },
+            );
+        }
+        for (name, addr_tag, size_tag) in [
+            (".rela.dyn", DT_RELA, DT_RELASZ),
+            (".rela.plt", DT_JMPREL, DT_PLTRELSZ),
+        ] {
+            if let Some(addr) = dynamic_value(addr_tag) {
+                sections.push(
+                    name,
+                    SectionHeader {
+                        section_type: SHT_RELA,
+                        flags: SHF_ALLOC,
+                        addr,
+                        size: dynamic_value(size_tag).unwrap_or(0),
+                        link: dynsym_index.unwrap_or(0),
+                        align: 8,
+                        entry_size: RELA_SIZE,
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+        let dynamic_size = dynamic.len() as u64 * DYNAMIC_ENTRY_SIZE;
+        sections.push(
+            ".dynamic",
+            SectionHeader {
+                section_type: SHT_DYNAMIC,
+                flags: SHF_ALLOC | SHF_WRITE,
+                addr: dynamic_addr,
+                size: dynamic_size,
+                link: dynstr_index.unwrap_or(0),
+                align: 8,
+                entry_size: DYNAMIC_ENTRY_SIZE,
+                ..Default::default()
+            },
+        );
+        let eh_frame_hdr_addr = relative(mod0.eh_frame_hdr_start_offset);
+        let eh_frame_hdr_size =
+            relative(mod0.eh_frame_hdr_end_offset).saturating_sub(eh_frame_hdr_addr);
+        if eh_frame_hdr_size != 0 {
+            sections.push(
+                ".eh_frame_hdr",
+                SectionHeader {
+                    section_type: SHT_PROGBITS,
+                    flags: SHF_ALLOC,
+                    addr: eh_frame_hdr_addr,
+                    size: eh_frame_hdr_size,
+                    align: 4,
+                    ..Default::default()
+                },
+            );
+        }
+
+        // Everything that's loaded sits at IMAGE_FILE_OFFSET + its address
+        for section in sections.headers.iter_mut().skip(1) {
+            section.offset = IMAGE_FILE_OFFSET + section.addr;
+        }
+        sections.headers[bss_index as usize].offset = IMAGE_FILE_OFFSET + self.data.end();
+
+        let mut program_headers = Vec::new();
+        for (segment, flags, memory_size) in [
+            (&self.text, PF_R | PF_X, self.text.data.len() as u64),
+            (&self.rodata, PF_R, self.rodata.data.len() as u64),
+            (
+                &self.data,
+                PF_R | PF_W,
+                self.data.data.len() as u64 + self.bss_size as u64,
+            ),
+        ] {
+            program_headers.push(ProgramHeader {
+                segment_type: PT_LOAD,
+                flags,
+                offset: IMAGE_FILE_OFFSET + segment.memory_offset as u64,
+                vaddr: segment.memory_offset as u64,
+                paddr: segment.memory_offset as u64,
+                file_size: segment.data.len() as u64,
+                memory_size,
+                align: PAGE_SIZE,
+            });
+        }
+        program_headers.push(ProgramHeader {
+            segment_type: PT_DYNAMIC,
+            flags: PF_R | PF_W,
+            offset: IMAGE_FILE_OFFSET + dynamic_addr,
+            vaddr: dynamic_addr,
+            paddr: dynamic_addr,
+            file_size: dynamic_size,
+            memory_size: dynamic_size,
+            align: 8,
+        });
+        if eh_frame_hdr_size != 0 {
+            program_headers.push(ProgramHeader {
+                segment_type: PT_GNU_EH_FRAME,
+                flags: PF_R,
+                offset: IMAGE_FILE_OFFSET + eh_frame_hdr_addr,
+                vaddr: eh_frame_hdr_addr,
+                paddr: eh_frame_hdr_addr,
+                file_size: eh_frame_hdr_size,
+                memory_size: eh_frame_hdr_size,
+                align: 4,
+            });
+        }
+
+        // The section name table and section headers go after the image
+        let shstrtab_offset = IMAGE_FILE_OFFSET + image.len() as u64;
+        let shstrtab_index = sections.push(
+            ".shstrtab",
+            SectionHeader {
+                section_type: SHT_STRTAB,
+                offset: shstrtab_offset,
+                align: 1,
+                ..Default::default()
+            },
+        );
+        let names = std::mem::take(&mut sections.names);
+        sections.headers[shstrtab_index as usize].size = names.len() as u64;
+        let section_header_offset = (shstrtab_offset + names.len() as u64).next_multiple_of(8);
+
+        let header = ElfHeader {
+            ident: *b"\x7FELF\x02\x01\x01\0\0\0\0\0\0\0\0\0",
+            elf_type: 3,  // ET_DYN
+            machine: 183, // EM_AARCH64
+            version: 1,
+            entry: self.text.memory_offset as u64,
+            program_header_offset: ELF_HEADER_SIZE as u64,
+            section_header_offset,
+            flags: 0,
+            header_size: ELF_HEADER_SIZE,
+            program_header_size: PROGRAM_HEADER_SIZE,
+            program_header_count: program_headers.len() as u16,
+            section_header_size: SECTION_HEADER_SIZE,
+            section_header_count: sections.headers.len() as u16,
+            section_name_index: shstrtab_index as u16,
+        };
+
+        let mut out = Cursor::new(Vec::new());
+        header.write_le(&mut out)?;
+        for program_header in &program_headers {
+            program_header.write_le(&mut out)?;
+        }
+        let mut out = out.into_inner();
+        out.resize(IMAGE_FILE_OFFSET as usize, 0);
+        out.extend_from_slice(&image);
+        out.extend_from_slice(&names);
+        out.resize(section_header_offset as usize, 0);
+
+        let mut out = Cursor::new(out);
+        out.set_position(section_header_offset);
+        for section in &sections.headers {
+            section.write_le(&mut out)?;
+        }
+        Ok(out.into_inner())
+    }
+}
+
+/// Section headers and their name table, built together
+struct SectionTable {
+    headers: Vec<SectionHeader>,
+    names: Vec<u8>,
+}
+
+impl Default for SectionTable {
+    fn default() -> Self {
+        Self {
+            headers: vec![SectionHeader::default()],
+            names: vec![0],
+        }
+    }
+}
+
+impl SectionTable {
+    fn push(&mut self, name: &str, mut header: SectionHeader) -> u32 {
+        header.name = self.names.len() as u32;
+        self.names.extend_from_slice(name.as_bytes());
+        self.names.push(0);
+        self.headers.push(header);
+        (self.headers.len() - 1) as u32
+    }
+}
+
+fn slice(image: &[u8], addr: u64, size: u64) -> Result<&[u8], Error> {
+    addr.checked_add(size)
+        .and_then(|end| image.get(addr as usize..end as usize))
+        .ok_or_else(|| {
+            Error::InvalidData(format!(
+                "0x{:X} bytes at 0x{:X} are outside the module",
+                size, addr
+            ))
+        })
+}
+
+/// Number of `.dynsym` entries, from the hash table or else from where `.dynstr` starts
+fn symbol_count(
+    image: &[u8],
+    symtab: u64,
+    dynamic_value: &impl Fn(u64) -> Option<u64>,
+) -> Result<u64, Error> {
+    if let Some(hash) = dynamic_value(DT_HASH) {
+        let chain_count = slice(image, hash + 4, 4)?;
+        return Ok(u32::from_le_bytes(chain_count.try_into().unwrap()) as u64);
+    }
+    match dynamic_value(DT_STRTAB) {
+        Some(strtab) if strtab > symtab => Ok((strtab - symtab) / SYMBOL_SIZE),
+        _ => Err(Error::InvalidData(
+            "Can't tell the size of .dynsym without DT_HASH".to_string(),
+        )),
+    }
+}
+
+/// Index of the first non-local symbol, which ELF wants as `sh_info` of `.dynsym`
+fn first_global_symbol(image: &[u8], symtab: u64, count: u64) -> Result<u32, Error> {
+    for index in 0..count {
+        // st_info is the byte after st_name, with the binding in the upper nibble
+        let info = slice(image, symtab + index * SYMBOL_SIZE + 4, 1)?[0];
+        if info >> 4 != 0 {
+            return Ok(index as u32);
+        }
+    }
+    Ok(count as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::formats::test_support::{build_module, check_elf};
+
+    #[test]
+    fn test_module_to_elf() {
+        let module = build_module();
+        let elf = module.to_elf().unwrap();
+        check_elf(&elf, &module);
+    }
+
+    #[test]
+    fn test_module_without_mod0() {
+        let mut module = build_module();
+        module.rodata.data[..4].copy_from_slice(b"XXXX");
+        assert!(module.to_elf().is_err());
+    }
+
+    #[test]
+    fn test_module_too_large() {
+        let mut module = build_module();
+        module.data.memory_offset = u32::MAX - 0x100;
+        assert!(module.to_flat().is_err());
+
+        let mut module = build_module();
+        module.bss_size = u32::MAX;
+        assert!(module.to_flat().is_err());
+    }
+}
@@ -12,6 +12,7 @@ use crate::{
     io::{SharedReader, SubFile},
 };
 use binrw::prelude::*;
+use sha2::{Digest, Sha256};
 use std::io::{Read, Seek, SeekFrom};
 
 /// Nintendo Switch HFS0 (Hashed File System 0) header structure
@@ -43,7 +44,28 @@ pub struct Hfs0Header {
     // We will seek and read as needed
 }
 
-#[derive(Debug)]
+impl Hfs0Header {
+    /// Size of the serialized header, including the string table
+    pub fn size(&self) -> u64 {
+        0x10 + (self.file_entries.len() * HFS0_ENTRY_SIZE) as u64 + self.string_table_size as u64
+    }
+
+    /// Serializes the header
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut cursor = binrw::io::Cursor::new(Vec::new());
+        self.write(&mut cursor)
+            .expect("Failed to serialize HFS0 header");
+        cursor.into_inner()
+    }
+}
+
+/// Size of a serialized [`Hfs0Entry`]
+const HFS0_ENTRY_SIZE: usize = 0x40;
+
+/// Alignment of the HFS0 header size and of each file's data
+pub const HFS0_ALIGNMENT: u64 = 0x200;
+
+#[derive(Debug, Clone)]
 #[binrw]
 #[brw(little)]
 pub struct Hfs0Entry {
@@ -72,6 +94,89 @@ pub struct Hfs0File {
     /// In our case, this offset is absolute to the start of the HFS0 file.
     pub offset: u64,
     pub hash: [u8; 0x20],
+    /// Size of the region at the start of the file covered by `hash`
+    pub hashed_region_size: u32,
+}
+
+/// Result of checking one file of an HFS0 against the hash in its entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hfs0FileVerification {
+    pub name: String,
+    /// Whether the first `hashed_region_size` bytes of the file match the entry's hash
+    pub valid: bool,
+}
+
+/// Builds the header of a new HFS0 archive
+///
+/// Files are laid out in the order they are added, each starting on a 0x200 byte
+/// boundary. The caller writes [`Hfs0Builder::header`] followed by the file data,
+/// zero-padding each file up to the offset of the next one.
+#[derive(Debug, Default)]
+pub struct Hfs0Builder {
+    entries: Vec<Hfs0Entry>,
+    string_table: Vec<u8>,
+    data_size: u64,
+}
+
+impl Hfs0Builder {
+    /// Create an empty HFS0 builder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a file, returning its offset from the start of the data region
+    ///
+    /// `sha256` is the hash of the first `hashed_region_size` bytes of the file.
+    pub fn add_file(
+        &mut self,
+        name: &str,
+        size: u64,
+        hashed_region_size: u32,
+        sha256: [u8; 0x20],
+    ) -> u64 {
+        let offset = self.data_size.next_multiple_of(HFS0_ALIGNMENT);
+
+        self.entries.push(Hfs0Entry {
+            offset,
+            size,
+            filename_offset: self.string_table.len() as u32,
+            hashed_region_size,
+            _reserved: 0,
+            sha256,
+        });
+        self.string_table.extend_from_slice(name.as_bytes());
+        self.string_table.push(0);
+        self.data_size = offset + size;
+
+        offset
+    }
+
+    /// Size of the data region, up to the end of the last file
+    pub fn data_size(&self) -> u64 {
+        self.data_size
+    }
+
+    /// Build the header, padding the string table so file data starts on a 0x200 byte boundary
+    pub fn header(&self) -> Hfs0Header {
+        let unpadded = 0x10 + self.entries.len() * HFS0_ENTRY_SIZE + self.string_table.len();
+        let padded = (unpadded as u64).next_multiple_of(HFS0_ALIGNMENT) as usize;
+
+        let mut string_table = self.string_table.clone();
+        string_table.resize(self.string_table.len() + padded - unpadded, 0);
+
+        Hfs0Header {
+            file_count: self.entries.len() as u32,
+            string_table_size: string_table.len() as u32,
+            _reserved: 0,
+            file_entries: self.entries.clone(),
+            string_table,
+        }
+    }
+
+    /// Total size of the archive, header included
+    pub fn total_size(&self) -> u64 {
+        self.header().size() + self.data_size
+    }
 }
 
 #[derive(Debug)]
@@ -154,6 +259,33 @@ impl<R: Read + Seek> Hfs0<R> {
         Ok(())
     }
 
+    /// Check the hashed region of every file against the hash in its entry
+    ///
+    /// A hashed region extending past the end of its file counts as a mismatch.
+    pub fn verify(&mut self) -> Result<Vec<Hfs0FileVerification>, crate::error::Error> {
+        let mut results = Vec::with_capacity(self.header.file_entries.len());
+
+        for file in self.list_files()? {
+            let valid = if file.hashed_region_size as u64 > file.size {
+                false
+            } else {
+                let mut hashed = vec![0u8; file.hashed_region_size as usize];
+                self.read_buf(&file, &mut hashed)?;
+                <[u8; 0x20]>::from(Sha256::digest(&hashed)) == file.hash
+            };
+
+            if !valid {
+                tracing::warn!("Hash mismatch for HFS0 file {}", file.name);
+            }
+            results.push(Hfs0FileVerification {
+                name: file.name,
+                valid,
+            });
+        }
+
+        Ok(results)
+    }
+
     /// Create a SubFile reader for a given file entry
     pub fn subfile(&mut self, file: &Hfs0File) -> SubFile<R>
     where
@@ -217,6 +349,7 @@ impl<R: Read + Seek> Hfs0<R> {
                         size: entry.size,
                         offset: entry.offset + header_size as u64,
                         hash: entry.sha256,
+                        hashed_region_size: entry.hashed_region_size,
                     })
                 } else {
                     None
@@ -0,0 +1,102 @@
+//! Combined key context
+//!
+//! Most operations on encrypted content need both the [`Keyset`] and the [`TitleKeys`]
+//! database. [`KeyContext`] bundles the two, and can load both from their default
+//! locations in one go.
+
+use tracing::warn;
+
+use super::{KeyEnvironment, Keyset};
+use crate::formats::TitleKeys;
+use crate::formats::title_keyset::KeyError;
+
+/// The keyset together with the (optional) title keys database
+#[derive(Debug, Default)]
+pub struct KeyContext {
+    /// Console keys (`prod.keys` or `dev.keys`)
+    pub keyset: Keyset,
+    /// Title keys (`title.keys`), if any were found
+    pub title_keys: Option<TitleKeys>,
+}
+
+impl KeyContext {
+    /// Create a new key context from an already-loaded keyset and title keys
+    pub fn new(keyset: Keyset, title_keys: Option<TitleKeys>) -> Self {
+        Self { keyset, title_keys }
+    }
+
+    /// Load the keyset and title keys from their default locations
+    ///
+    /// See [`Keyset::load_default`] and [`TitleKeys::load_default`] for where each
+    /// is looked up. A missing keyset is an error, while missing title keys are not,
+    /// since content without a rights ID can be decrypted without them.
+    ///
+    /// # Errors
+    /// * [`KeyError::NotFoundInDefaultLocations`] - If no keyset was found, listing every path tried
+    /// * [`KeyError::LoadFailed`] - If every key file found failed to load
+    pub fn load_default() -> Result<Self, KeyError> {
+        let keyset = Keyset::load_default()?;
+        Ok(Self {
+            keyset,
+            title_keys: Self::load_default_title_keys()?,
+        })
+    }
+
+    /// Load the keyset for a specific environment and the title keys from their default locations
+    ///
+    /// See [`Keyset::load_default_for`].
+    pub fn load_default_for(environment: KeyEnvironment) -> Result<Self, KeyError> {
+        let keyset = Keyset::load_default_for(environment)?;
+        Ok(Self {
+            keyset,
+            title_keys: Self::load_default_title_keys()?,
+        })
+    }
+
+    /// Whether this context holds retail or development keys
+    pub fn environment(&self) -> KeyEnvironment {
+        self.keyset.environment
+    }
+
+    /// Load the title keys, treating a missing `title.keys` as no title keys
+    fn load_default_title_keys() -> Result<Option<TitleKeys>, KeyError> {
+        match TitleKeys::load_default() {
+            Ok(title_keys) => Ok(Some(title_keys)),
+            Err(e @ KeyError::NotFoundInDefaultLocations { .. }) => {
+                warn!("{}, continuing without title keys", e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::keyset::default_key_paths;
+
+    #[test]
+    fn test_default_key_paths() {
+        let paths = default_key_paths(&["prod.keys", "dev.keys"], "NX_ARCHIVE_TEST_UNSET_VAR");
+
+        assert!(paths.ends_with(&["prod.keys".into(), "dev.keys".into()]));
+        if let Some(home_dir) = dirs::home_dir() {
+            assert_eq!(paths[0], home_dir.join(".switch").join("prod.keys"));
+            assert_eq!(paths[1], home_dir.join(".switch").join("dev.keys"));
+        }
+    }
+
+    #[test]
+    fn test_not_found_error_lists_paths() {
+        let err = KeyError::NotFoundInDefaultLocations {
+            file: "prod.keys".to_string(),
+            tried: vec!["/a/prod.keys".into(), "/b/dev.keys".into()],
+        };
+
+        assert_eq!(
+            err.to_string(),
+            "No prod.keys found in default locations, tried: /a/prod.keys, /b/dev.keys"
+        );
+    }
+}
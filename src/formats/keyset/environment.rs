@@ -0,0 +1,344 @@
+//! Key environments
+//!
+//! Retail consoles and development units use entirely separate keys: a `dev.keys` file has
+//! its own header key, master keys and key area keys, and devkit content is signed with a
+//! different set of fixed RSA keys than retail content. [`KeyEnvironment`] records which of
+//! the two a [`Keyset`] belongs to, and [`FixedKeys`] holds the public keys used to verify
+//! signatures in that environment.
+//!
+//! The fixed moduli are not bundled with the crate. They are read from the key file itself,
+//! so a `prod.keys` carries the retail moduli and a `dev.keys` the development ones. Any
+//! signature whose modulus is missing is reported as unchecked:
+//!
+//! | Key name                          | Size  | Used for                                   |
+//! |-----------------------------------|-------|--------------------------------------------|
+//! | `nca_hdr_fixed_key_modulus_XX`    | 0x100 | NCA `header_sig`, by signature key generation |
+//! | `acid_fixed_key_modulus_XX`       | 0x100 | NPDM ACID signature, by signature key generation |
+//! | `nrr_fixed_key_modulus_XX`        | 0x100 | NRR certification signature, by key generation |
+//! | `root_cert_modulus`               | 0x200 | The `Root` certificate of the ticket and certificate chain |
+//! | `xci_header_fixed_key_modulus`    | 0x100 | XCI header signature                       |
+//! | `xci_cert_fixed_key_modulus`      | 0x100 | Gamecard certificate signature             |
+
+use rsa::{BigUint, Pkcs1v15Sign, Pss, RsaPublicKey};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use super::Keyset;
+use crate::error::Error;
+
+/// Key name prefix of the NCA header fixed key moduli
+pub const NCA_HEADER_MODULUS_KEY: &str = "nca_hdr_fixed_key_modulus";
+/// Key name prefix of the ACID fixed key moduli
+pub const ACID_MODULUS_KEY: &str = "acid_fixed_key_modulus";
+/// Key name prefix of the NRR certification fixed key moduli
+pub const NRR_MODULUS_KEY: &str = "nrr_fixed_key_modulus";
+/// Key name of the `Root` certificate modulus
+pub const ROOT_CERT_MODULUS_KEY: &str = "root_cert_modulus";
+/// Key name of the XCI header modulus
+pub const XCI_HEADER_MODULUS_KEY: &str = "xci_header_fixed_key_modulus";
+/// Key name of the gamecard certificate modulus
+pub const XCI_CERT_MODULUS_KEY: &str = "xci_cert_fixed_key_modulus";
+
+/// Public exponent shared by all of Nintendo's fixed RSA keys
+const RSA_PUBLIC_EXPONENT: u32 = 0x10001;
+
+/// The environment a keyset belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum KeyEnvironment {
+    /// Retail consoles (`prod.keys`)
+    #[default]
+    Retail,
+    /// Development units (`dev.keys`)
+    Development,
+}
+
+impl KeyEnvironment {
+    /// The conventional key file name for this environment
+    pub fn key_file_name(self) -> &'static str {
+        match self {
+            KeyEnvironment::Retail => "prod.keys",
+            KeyEnvironment::Development => "dev.keys",
+        }
+    }
+
+    /// Guess the environment from a key file path
+    ///
+    /// Returns `None` if the file name isn't one of the conventional names.
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        match path.as_ref().file_name()?.to_str()? {
+            "prod.keys" => Some(KeyEnvironment::Retail),
+            "dev.keys" => Some(KeyEnvironment::Development),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for KeyEnvironment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyEnvironment::Retail => write!(f, "retail"),
+            KeyEnvironment::Development => write!(f, "development"),
+        }
+    }
+}
+
+/// Fixed public keys used to verify signatures in a single key environment
+#[derive(Debug, Clone, Default)]
+pub struct FixedKeys {
+    /// The environment these keys belong to
+    pub environment: KeyEnvironment,
+    /// NCA header signing moduli, by signature key generation
+    pub nca_header_moduli: HashMap<u8, [u8; 0x100]>,
+    /// ACID signing moduli, by signature key generation
+    pub acid_moduli: HashMap<u8, [u8; 0x100]>,
+    /// NRR certification signing moduli, by key generation
+    pub nrr_moduli: HashMap<u8, [u8; 0x100]>,
+    /// Modulus of the `Root` certificate
+    pub root_cert_modulus: Option<[u8; 0x200]>,
+    /// XCI header signing modulus
+    pub xci_header_modulus: Option<[u8; 0x100]>,
+    /// Gamecard certificate signing modulus
+    pub xci_cert_modulus: Option<[u8; 0x100]>,
+}
+
+impl FixedKeys {
+    /// Get the NCA header modulus for a signature key generation
+    pub fn nca_header_modulus(&self, generation: u8) -> Result<&[u8; 0x100], Error> {
+        self.nca_header_moduli.get(&generation).ok_or_else(|| {
+            Error::KeyLookupError(format!(
+                "No {} NCA header modulus ({}_{:02x})",
+                self.environment, NCA_HEADER_MODULUS_KEY, generation
+            ))
+        })
+    }
+
+    /// Get the ACID modulus for a signature key generation
+    pub fn acid_modulus(&self, generation: u8) -> Result<&[u8; 0x100], Error> {
+        self.acid_moduli.get(&generation).ok_or_else(|| {
+            Error::KeyLookupError(format!(
+                "No {} ACID modulus ({}_{:02x})",
+                self.environment, ACID_MODULUS_KEY, generation
+            ))
+        })
+    }
+
+    /// Get the NRR certification modulus for a key generation
+    pub fn nrr_modulus(&self, generation: u8) -> Result<&[u8; 0x100], Error> {
+        self.nrr_moduli.get(&generation).ok_or_else(|| {
+            Error::KeyLookupError(format!(
+                "No {} NRR modulus ({}_{:02x})",
+                self.environment, NRR_MODULUS_KEY, generation
+            ))
+        })
+    }
+
+    /// Get the `Root` certificate modulus
+    pub fn root_cert_modulus(&self) -> Result<&[u8; 0x200], Error> {
+        self.root_cert_modulus.as_ref().ok_or_else(|| {
+            Error::KeyLookupError(format!(
+                "No {} Root certificate modulus ({})",
+                self.environment, ROOT_CERT_MODULUS_KEY
+            ))
+        })
+    }
+
+    /// Get the XCI header modulus
+    pub fn xci_header_modulus(&self) -> Result<&[u8; 0x100], Error> {
+        self.xci_header_modulus.as_ref().ok_or_else(|| {
+            Error::KeyLookupError(format!(
+                "No {} XCI header modulus ({})",
+                self.environment, XCI_HEADER_MODULUS_KEY
+            ))
+        })
+    }
+
+    /// Get the gamecard certificate modulus
+    pub fn xci_cert_modulus(&self) -> Result<&[u8; 0x100], Error> {
+        self.xci_cert_modulus.as_ref().ok_or_else(|| {
+            Error::KeyLookupError(format!(
+                "No {} gamecard certificate modulus ({})",
+                self.environment, XCI_CERT_MODULUS_KEY
+            ))
+        })
+    }
+}
+
+/// Outcome of checking a single signature
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureStatus {
+    /// The signature matches
+    Valid,
+    /// The signature doesn't match
+    Invalid,
+    /// The signature couldn't be checked, usually because the key is missing
+    Unchecked(String),
+}
+
+impl SignatureStatus {
+    /// Check a signature with a key that may not be available
+    pub(crate) fn check(
+        modulus: Result<&[u8; 0x100], Error>,
+        verify: impl FnOnce(&[u8]) -> bool,
+    ) -> Self {
+        match modulus {
+            Ok(modulus) if verify(modulus) => SignatureStatus::Valid,
+            Ok(_) => SignatureStatus::Invalid,
+            Err(e) => SignatureStatus::Unchecked(e.to_string()),
+        }
+    }
+
+    /// Whether the signature was checked and matches
+    pub fn is_valid(&self) -> bool {
+        matches!(self, SignatureStatus::Valid)
+    }
+}
+
+impl fmt::Display for SignatureStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureStatus::Valid => write!(f, "valid"),
+            SignatureStatus::Invalid => write!(f, "invalid"),
+            SignatureStatus::Unchecked(reason) => write!(f, "unchecked ({})", reason),
+        }
+    }
+}
+
+impl Keyset {
+    /// The fixed signature keys for this keyset's environment, read from the key file
+    pub fn fixed_keys(&self) -> FixedKeys {
+        FixedKeys {
+            environment: self.environment,
+            nca_header_moduli: self.get_indexed_keys(NCA_HEADER_MODULUS_KEY),
+            acid_moduli: self.get_indexed_keys(ACID_MODULUS_KEY),
+            nrr_moduli: self.get_indexed_keys(NRR_MODULUS_KEY),
+            root_cert_modulus: self.get_key(ROOT_CERT_MODULUS_KEY),
+            xci_header_modulus: self.get_key(XCI_HEADER_MODULUS_KEY),
+            xci_cert_modulus: self.get_key(XCI_CERT_MODULUS_KEY),
+        }
+    }
+}
+
+/// Verify an RSA-PSS signature with SHA-256 and a 0x20 byte salt
+///
+/// Returns `false` for a bad signature as well as for a malformed modulus.
+pub(crate) fn verify_rsa_pss_sha256(modulus: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let Ok(key) = RsaPublicKey::new(
+        BigUint::from_bytes_be(modulus),
+        BigUint::from(RSA_PUBLIC_EXPONENT),
+    ) else {
+        return false;
+    };
+
+    key.verify(Pss::new::<Sha256>(), &Sha256::digest(message), signature)
+        .is_ok()
+}
+
+/// Verify an RSA PKCS#1 v1.5 signature with SHA-256
+///
+/// Returns `false` for a bad signature as well as for a malformed modulus.
+pub(crate) fn verify_rsa_pkcs1_sha256(modulus: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let Ok(key) = RsaPublicKey::new(
+        BigUint::from_bytes_be(modulus),
+        BigUint::from(RSA_PUBLIC_EXPONENT),
+    ) else {
+        return false;
+    };
+
+    key.verify(
+        Pkcs1v15Sign::new::<Sha256>(),
+        &Sha256::digest(message),
+        signature,
+    )
+    .is_ok()
+}
+
+/// Modulus of a throwaway 2048-bit test key, used to sign test vectors, not one of Nintendo's
+#[cfg(test)]
+pub(crate) const TEST_RSA_MODULUS: [u8; 0x100] = hex_literal::hex!(
+    "b720de22599b0f154f92c3a2a5053fc9bdad90dff9c4f6396fe1691d32677010f259d8bc96e9231e412fdbb430e62e3d161073a3d64d2e9da571d98cb1f091b1043a18bff0cf55c2f173f65313909859551fe13cdb420172dabaf1d2c3197587aea5f20b335178def3cb60d5bc06e09333f92fe3cb6aa441d57550a30ded486d9fc9cb470b6ce7526ef3217961644486c105f742785316b8b6a4c1ed43cf93923472e0a297f3149b770151c4a9faf83a22da9a9d3dc3f823c6a9ce55462a5225ee22a1cfea2c82a3cc33e8e04221ada719bd3ae62b9c2896f48e80bfd84d73abb682bed53e0aca00f6cb7ee564b000067473a3aa4e441921edd4b319a77fe82f"
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_MESSAGE: &[u8] = b"nx-archive signature test";
+    const TEST_PSS_SIGNATURE: [u8; 0x100] = hex_literal::hex!(
+        "4cb4beba9e78ac3ef9ce81a9c6e7e773190e9c83b39244024bebfa2a79a3ed428387d25c394f2a37970ad99a68cd5d01fa110775bd8795569c148e1efd50f49d2f3d89db5d4811d18c5ed7dd62c909a7b1c66b35bf2a74863c1f5243445c081b6a29c615415dfc4cb302a47651fe5f6477a9d5380b69f8a24cb0fd5b53c8bb80c21f4db5aa6d8290ec51686567d87ad38635a76dd10556adb3df377761f72d900254dc85649bceefe8f35022b1a44dd30e19bfb04aeafe6293ee72504bf3f6526d93b86f12df228ca6b5261005bd410bbf8f181f02934c0c88b4436cd26e35900fc5d54e1b3be8c9ec9f33d6d9e5f4440e2953afcf09064c06cf759e0cdbed87"
+    );
+
+    const TEST_PKCS1_SIGNATURE: [u8; 0x100] = hex_literal::hex!(
+        "a1ad398fd4dd991d5ee2fe2e47a6d3294c6fdbeb72f2c3f03aeebae9f355e467f32df7b59104aba48f3a5c9d58b99b54ec72bbbe37446664dc96f95844c38eacb74a6d16a9b1c229445ff813fab61ceb1588fe25fb7ad344c12a640da0b2c84fc0ce98ed0ee2b12fe23d634d0df3adea3721ae58a7357f6ee3e013bf55a7adaad05dd04fadd153a6f8eecf61c3f96d5ad38339f6fc1e232c0a333b716b2dd978eefbcc1d8fe6af4817b0fa118dd9e83206f1ec31b62b0f5a2bcdc989e549ba336e41be1b70c23bd9619520155e0ed8709a65a600299c78b6679b7f7c57e0b2fa45678c7327e6a8f94430654c6cda577fb699d3223ba27c0dc1ab5b49ff8050bd"
+    );
+
+    #[test]
+    fn test_environment_from_path() {
+        assert_eq!(
+            KeyEnvironment::from_path("/home/user/.switch/dev.keys"),
+            Some(KeyEnvironment::Development)
+        );
+        assert_eq!(
+            KeyEnvironment::from_path("prod.keys"),
+            Some(KeyEnvironment::Retail)
+        );
+        assert_eq!(KeyEnvironment::from_path("keys.txt"), None);
+    }
+
+    #[test]
+    fn test_fixed_keys_follow_environment() {
+        let mut keyset = Keyset {
+            environment: KeyEnvironment::Development,
+            ..Default::default()
+        };
+        keyset.raw_keys.insert(
+            format!("{}_01", NCA_HEADER_MODULUS_KEY),
+            TEST_RSA_MODULUS.to_vec().into(),
+        );
+
+        let fixed_keys = keyset.fixed_keys();
+        assert_eq!(fixed_keys.environment, KeyEnvironment::Development);
+        assert_eq!(fixed_keys.nca_header_modulus(1).unwrap(), &TEST_RSA_MODULUS);
+
+        let err = fixed_keys.nca_header_modulus(0).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("No development NCA header modulus (nca_hdr_fixed_key_modulus_00)")
+        );
+        assert!(fixed_keys.root_cert_modulus().is_err());
+    }
+
+    #[test]
+    fn test_verify_rsa_pss_sha256() {
+        assert!(verify_rsa_pss_sha256(
+            &TEST_RSA_MODULUS,
+            TEST_MESSAGE,
+            &TEST_PSS_SIGNATURE
+        ));
+        assert!(!verify_rsa_pss_sha256(
+            &TEST_RSA_MODULUS,
+            b"something else",
+            &TEST_PSS_SIGNATURE
+        ));
+        assert!(!verify_rsa_pss_sha256(
+            &[0; 0x100],
+            TEST_MESSAGE,
+            &TEST_PSS_SIGNATURE
+        ));
+    }
+
+    #[test]
+    fn test_verify_rsa_pkcs1_sha256() {
+        assert!(verify_rsa_pkcs1_sha256(
+            &TEST_RSA_MODULUS,
+            TEST_MESSAGE,
+            &TEST_PKCS1_SIGNATURE
+        ));
+        assert!(!verify_rsa_pkcs1_sha256(
+            &TEST_RSA_MODULUS,
+            TEST_MESSAGE,
+            &TEST_PSS_SIGNATURE
+        ));
+    }
+}
@@ -0,0 +1,890 @@
+use aes::Aes128;
+use cipher::{KeyInit, generic_array::GenericArray};
+use hex::FromHex;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Result, Seek, Write};
+use std::path::{Path, PathBuf};
+use xts_mode::Xts128;
+use zeroize::Zeroizing;
+
+use super::title_keyset::KeyError;
+
+mod context;
+mod environment;
+mod validation;
+pub use context::*;
+pub use environment::*;
+pub use validation::*;
+
+/// Environment variable that overrides where [`Keyset::load_default`] looks for the keyset
+pub const KEYSET_PATH_ENV: &str = "NX_ARCHIVE_KEYS";
+
+/// Returns the default locations to look for key files with the given names
+///
+/// If `env_var` is set, its value is the only path returned. Otherwise each file name
+/// is looked up in `~/.switch`, `$XDG_CONFIG_HOME/switch` (`~/.config/switch` if the
+/// variable is unset, on every platform) and the current directory, in that order.
+pub(crate) fn default_key_paths(file_names: &[&str], env_var: &str) -> Vec<PathBuf> {
+    key_paths(
+        file_names,
+        std::env::var_os(env_var),
+        std::env::var_os("XDG_CONFIG_HOME"),
+        dirs::home_dir(),
+    )
+}
+
+/// [`default_key_paths`], with the environment and home directory passed in
+fn key_paths(
+    file_names: &[&str],
+    override_path: Option<OsString>,
+    xdg_config_home: Option<OsString>,
+    home_dir: Option<PathBuf>,
+) -> Vec<PathBuf> {
+    if let Some(path) = override_path {
+        return vec![PathBuf::from(path)];
+    }
+
+    let mut paths = Vec::new();
+
+    if let Some(home_dir) = &home_dir {
+        paths.extend(file_names.iter().map(|f| home_dir.join(".switch").join(f)));
+    }
+
+    let config_dir = xdg_config_home
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home_dir.map(|home_dir| home_dir.join(".config")));
+    if let Some(config_dir) = config_dir {
+        paths.extend(file_names.iter().map(|f| config_dir.join("switch").join(f)));
+    }
+
+    paths.extend(file_names.iter().map(PathBuf::from));
+
+    paths
+}
+
+/// Load the first of `paths` that exists
+///
+/// Paths that don't exist are skipped, and a path that fails to load doesn't stop the
+/// search.
+///
+/// # Errors
+/// * [`KeyError::LoadFailed`] - If every existing path failed to load, with each failure
+/// * [`KeyError::NotFoundInDefaultLocations`] - If none of the paths exist
+pub(crate) fn load_first_existing<T>(
+    paths: Vec<PathBuf>,
+    file_name: &str,
+    load: impl Fn(&Path) -> std::result::Result<T, KeyError>,
+) -> std::result::Result<T, KeyError> {
+    let mut failures = Vec::new();
+
+    for path in &paths {
+        if path.exists() {
+            match load(path) {
+                Ok(loaded) => {
+                    tracing::info!("Successfully loaded {}", path.display());
+                    return Ok(loaded);
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to load {}: {}", path.display(), e);
+                    failures.push((path.clone(), e));
+                }
+            }
+        }
+    }
+
+    if failures.is_empty() {
+        Err(KeyError::NotFoundInDefaultLocations {
+            file: file_name.to_string(),
+            tried: paths,
+        })
+    } else {
+        Err(KeyError::LoadFailed {
+            file: file_name.to_string(),
+            failures,
+        })
+    }
+}
+
+/// Wrapper for printing key material in logs and `Debug` output
+///
+/// Keys are printed as `<redacted N bytes>`, unless the `log-keys` feature is enabled,
+/// in which case they are printed as hex.
+#[derive(Clone, Copy)]
+pub struct RedactedKey<'a>(pub &'a [u8]);
+
+impl fmt::Display for RedactedKey<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if cfg!(feature = "log-keys") {
+            write!(f, "{}", hex::encode(self.0))
+        } else {
+            write!(f, "<redacted {} bytes>", self.0.len())
+        }
+    }
+}
+
+impl fmt::Debug for RedactedKey<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Splits a key name into its prefix and hex index, if it has one
+///
+/// For example, `key_area_key_application_0a` becomes `("key_area_key_application", Some(0x0A))`,
+/// while `header_key` is returned as-is with no index.
+pub(crate) fn split_key_index(name: &str) -> (&str, Option<u8>) {
+    if let Some((prefix, last)) = name.rsplit_once('_')
+        && let Ok(idx) = u8::from_str_radix(last, 16)
+    {
+        return (prefix, Some(idx));
+    }
+    (name, None)
+}
+
+/// Builds a tweak for Nintendo XTS encryption
+/// This is a non-standard tweak that has reversed endianness compared to normal XTS
+pub fn get_nintendo_tweak(sector_index: u128) -> [u8; 16] {
+    sector_index.to_be_bytes()
+}
+
+/// A key present in both sides of a merge, with different values
+#[derive(Clone, PartialEq, Eq)]
+pub struct KeyConflict {
+    /// Name of the key (or rights ID, for title keys)
+    pub name: String,
+    /// The value that was kept
+    pub existing: Zeroizing<Vec<u8>>,
+    /// The conflicting value that was not merged
+    pub incoming: Zeroizing<Vec<u8>>,
+}
+
+impl fmt::Debug for KeyConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyConflict")
+            .field("name", &self.name)
+            .field("existing", &RedactedKey(&self.existing))
+            .field("incoming", &RedactedKey(&self.incoming))
+            .finish()
+    }
+}
+
+/// Outcome of merging one key collection into another
+#[derive(Debug, Clone, Default)]
+pub struct KeyMergeReport {
+    /// Names of the keys that were added
+    pub added: Vec<String>,
+    /// Keys present on both sides with different values
+    ///
+    /// The existing value is always kept, so a merge never silently replaces a key.
+    pub conflicts: Vec<KeyConflict>,
+}
+
+impl KeyMergeReport {
+    /// Whether the merge completed without any conflicts
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Keyset {
+    /// Whether these are retail or development keys
+    pub environment: KeyEnvironment,
+
+    // Raw storage for all keys, wiped when dropped
+    pub raw_keys: HashMap<String, Zeroizing<Vec<u8>>>,
+
+    // Keep cached versions of frequently accessed keys for performance
+    pub header_key_cache: Option<Zeroizing<[u8; 0x20]>>,
+}
+
+impl fmt::Debug for Keyset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Group keys by prefix for easier reading
+        let mut grouped_keys: HashMap<&str, Vec<(&String, &[u8])>> = HashMap::new();
+
+        for (key, value) in &self.raw_keys {
+            let prefix = key.split('_').next().unwrap_or("");
+            grouped_keys
+                .entry(prefix)
+                .or_default()
+                .push((key, value.as_slice()));
+        }
+
+        // Sort entries for consistent output
+        let mut groups: Vec<_> = grouped_keys.into_iter().collect();
+        groups.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut debug_struct = f.debug_struct("Keyset");
+
+        debug_struct.field("environment", &self.environment);
+
+        // Add count of total keys
+        debug_struct.field("total_keys", &self.raw_keys.len());
+
+        // Add each group
+        for (prefix, entries) in groups {
+            let mut sorted_entries = entries;
+            sorted_entries.sort_by(|a, b| a.0.cmp(b.0));
+
+            let formatted_entries: Vec<_> = sorted_entries
+                .iter()
+                .map(|(key, value)| (key.to_string(), RedactedKey(value)))
+                .collect();
+
+            debug_struct.field(prefix, &formatted_entries);
+        }
+
+        debug_struct.finish()
+    }
+}
+
+impl Display for Keyset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format_indices = |keys: &HashMap<u8, [u8; 0x10]>,
+                              name: &str,
+                              f: &mut fmt::Formatter<'_>|
+         -> fmt::Result {
+            let mut indices: Vec<_> = keys.keys().collect();
+            indices.sort();
+            write!(
+                f,
+                "{} keys: {}",
+                name,
+                indices
+                    .iter()
+                    .map(|i| format!("{:02x}", i))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        };
+
+        format_indices(&self.key_area_keys_application(), "Application", f)?;
+        writeln!(f)?;
+        format_indices(&self.key_area_keys_ocean(), "Ocean", f)?;
+        writeln!(f)?;
+        format_indices(&self.key_area_keys_system(), "System", f)?;
+        writeln!(f)?;
+        format_indices(&self.title_keks(), "Title KEK", f)
+    }
+}
+
+impl Keyset {
+    /// Create a new keyset from a file path
+    ///
+    /// A file named `dev.keys` is loaded as a [`KeyEnvironment::Development`] keyset,
+    /// anything else as [`KeyEnvironment::Retail`].
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path.as_ref())?;
+        let mut keyset = Self::from_reader(file)?;
+        keyset.environment = KeyEnvironment::from_path(path).unwrap_or_default();
+        Ok(keyset)
+    }
+
+    /// Load the keyset from the default locations
+    ///
+    /// If the `NX_ARCHIVE_KEYS` environment variable is set, only that path is tried.
+    /// Otherwise `prod.keys` and `dev.keys` are looked up in `~/.switch`,
+    /// `$XDG_CONFIG_HOME/switch` (default `~/.config/switch`) and the current directory,
+    /// in that order.
+    ///
+    /// # Errors
+    /// * [`KeyError::NotFoundInDefaultLocations`] - If no key file exists, listing every path tried
+    /// * [`KeyError::LoadFailed`] - If every key file found failed to load, with each failure
+    pub fn load_default() -> std::result::Result<Self, KeyError> {
+        let possible_paths = default_key_paths(&["prod.keys", "dev.keys"], KEYSET_PATH_ENV);
+        Self::load_first(possible_paths, "prod.keys")
+    }
+
+    /// Load the keyset for a specific environment from the default locations
+    ///
+    /// Like [`Keyset::load_default`], but only looks for the key file of the given
+    /// environment (`prod.keys` or `dev.keys`). A file given through `NX_ARCHIVE_KEYS`
+    /// is assumed to belong to the requested environment.
+    pub fn load_default_for(environment: KeyEnvironment) -> std::result::Result<Self, KeyError> {
+        let file_name = environment.key_file_name();
+        let possible_paths = default_key_paths(&[file_name], KEYSET_PATH_ENV);
+
+        let mut keyset = Self::load_first(possible_paths, file_name)?;
+        keyset.environment = environment;
+        Ok(keyset)
+    }
+
+    /// Load the first keyset that exists out of a list of paths
+    fn load_first(
+        possible_paths: Vec<PathBuf>,
+        file_name: &str,
+    ) -> std::result::Result<Self, KeyError> {
+        load_first_existing(possible_paths, file_name, |path| Ok(Self::from_file(path)?))
+    }
+
+    /// Parse a key file to extract Nintendo Switch keys
+    pub fn from_reader(reader: impl Read + Seek) -> Result<Self> {
+        let lines = BufReader::new(reader).lines();
+        let mut keyset = Keyset::default();
+        let mut keys_loaded = 0;
+
+        for line in lines {
+            let line_str = line?;
+
+            // Skip empty lines or comments
+            if line_str.trim().is_empty() || line_str.trim().starts_with(';') {
+                continue;
+            }
+
+            let line_parts: Vec<_> = line_str.split('=').collect();
+            if line_parts.len() != 2 {
+                continue;
+            }
+
+            let key = line_parts[0].trim().to_string();
+            let value = line_parts[1].trim().split(';').next().unwrap_or("").trim();
+
+            let key_data = match Vec::from_hex(value) {
+                Ok(data) => data,
+                Err(e) => {
+                    tracing::warn!("Invalid hex value for key {}: {}", key, e);
+                    continue;
+                }
+            };
+
+            // Store the raw key
+            keyset.raw_keys.insert(key, Zeroizing::new(key_data));
+            keys_loaded += 1;
+        }
+
+        // Cache frequently used keys
+        keyset.update_caches();
+
+        tracing::info!(
+            "Loaded {} keys with {} distinct prefixes",
+            keys_loaded,
+            keyset.get_key_prefixes().len()
+        );
+
+        Ok(keyset)
+    }
+
+    /// Write the keyset out in the `prod.keys` format
+    ///
+    /// Keys are sorted by prefix and then by index, so the output is the same
+    /// regardless of the order the keys were loaded in.
+    pub fn write_to(&self, mut writer: impl Write) -> Result<()> {
+        let mut names: Vec<&String> = self.raw_keys.keys().collect();
+        names.sort_by_key(|&name| split_key_index(name));
+
+        for name in names {
+            writeln!(writer, "{} = {}", name, hex::encode(&self.raw_keys[name]))?;
+        }
+
+        Ok(())
+    }
+
+    /// Merge the keys from another keyset into this one
+    ///
+    /// Keys missing from this keyset are added. Keys present in both with different
+    /// values are left untouched and reported as conflicts.
+    pub fn merge(&mut self, other: &Keyset) -> KeyMergeReport {
+        let mut report = KeyMergeReport::default();
+
+        if self.environment != other.environment {
+            tracing::warn!(
+                "Merging {} keys into a {} keyset",
+                other.environment,
+                self.environment
+            );
+        }
+
+        let mut names: Vec<&String> = other.raw_keys.keys().collect();
+        names.sort_by_key(|&name| split_key_index(name));
+
+        for name in names {
+            let incoming = &other.raw_keys[name];
+            match self.raw_keys.get(name) {
+                Some(existing) if existing != incoming => {
+                    tracing::warn!("Conflicting values for key {}, keeping existing", name);
+                    report.conflicts.push(KeyConflict {
+                        name: name.clone(),
+                        existing: existing.clone(),
+                        incoming: incoming.clone(),
+                    });
+                }
+                Some(_) => {}
+                None => {
+                    self.raw_keys.insert(name.clone(), incoming.clone());
+                    report.added.push(name.clone());
+                }
+            }
+        }
+
+        self.update_caches();
+
+        report
+    }
+
+    /// Update internal caches for frequently accessed keys
+    fn update_caches(&mut self) {
+        // Cache header key
+        if let Some(key_data) = self.raw_keys.get("header_key") {
+            if key_data.len() == 0x20 {
+                let mut header_key = Zeroizing::new([0u8; 0x20]);
+                header_key.copy_from_slice(key_data);
+                self.header_key_cache = Some(header_key);
+            }
+        }
+    }
+
+    /// Creates an XTS128 cipher for NCA header encryption/decryption
+    ///
+    /// The header key is split into two 128-bit keys for XTS, with the first half used for the data unit key
+    /// and the second half used for the tweak key.
+    pub fn header_crypt(&self) -> Option<Xts128<Aes128>> {
+        self.header_key_cache.as_ref().map(|header_key| {
+            let cipher_1 = Aes128::new(GenericArray::from_slice(&header_key[..0x10]));
+            let cipher_2 = Aes128::new(GenericArray::from_slice(&header_key[0x10..]));
+            Xts128::new(cipher_1, cipher_2)
+        })
+    }
+
+    /// Get a list of all key prefixes in the keyset
+    pub fn get_key_prefixes(&self) -> Vec<String> {
+        let mut prefixes = std::collections::HashSet::new();
+
+        for key in self.raw_keys.keys() {
+            // For keys with format like key_area_key_application_00,
+            // we want the prefix to be key_area_key_application.
+            // Non-indexed keys are inserted as a whole.
+            let (prefix, _) = split_key_index(key);
+            prefixes.insert(prefix.to_string());
+        }
+
+        let mut prefix_list: Vec<String> = prefixes.into_iter().collect();
+        prefix_list.sort();
+        prefix_list
+    }
+
+    /// Get all keys with a specific prefix
+    pub fn get_keys_with_prefix(&self, prefix: &str) -> HashMap<String, Zeroizing<Vec<u8>>> {
+        self.raw_keys
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Try to get a raw key by its exact name
+    pub fn get_raw_key(&self, key_name: &str) -> Option<&[u8]> {
+        self.raw_keys.get(key_name).map(|v| v.as_slice())
+    }
+
+    /// Try to get a fixed-size key by its exact name
+    pub fn get_key<const N: usize>(&self, key_name: &str) -> Option<[u8; N]> {
+        self.raw_keys.get(key_name).and_then(|data| {
+            if data.len() == N {
+                let mut result = [0u8; N];
+                result.copy_from_slice(data);
+                Some(result)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Get the header key (cached for performance)
+    pub fn header_key(&self) -> Option<&[u8; 0x20]> {
+        self.header_key_cache.as_deref()
+    }
+
+    /// Extract indexes from keys with a specific format (e.g., titlekek_10 => 0x10)
+    pub fn get_indexed_keys<const N: usize>(&self, prefix: &str) -> HashMap<u8, [u8; N]> {
+        let mut result = HashMap::new();
+        let prefix_with_underscore = format!("{}_", prefix);
+
+        for (key, value) in &self.raw_keys {
+            if key.starts_with(&prefix_with_underscore) && value.len() == N {
+                // Extract the index part (everything after the last underscore)
+                if let Some(idx_str) = key.split('_').last() {
+                    if let Ok(idx) = u8::from_str_radix(idx_str, 16) {
+                        let mut fixed_arr = [0u8; N];
+                        fixed_arr.copy_from_slice(value);
+                        result.insert(idx, fixed_arr);
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Get an application key area key by index
+    pub fn get_key_area_key_application(&self, idx: usize) -> Option<[u8; 0x10]> {
+        let key_name = format!("key_area_key_application_{:02x}", idx as u8);
+        self.get_key(&key_name)
+    }
+
+    /// Get an ocean key area key by index
+    pub fn get_key_area_key_ocean(&self, idx: usize) -> Option<[u8; 0x10]> {
+        let key_name = format!("key_area_key_ocean_{:02x}", idx as u8);
+        self.get_key(&key_name)
+    }
+
+    /// Get a system key area key by index
+    pub fn get_key_area_key_system(&self, idx: usize) -> Option<[u8; 0x10]> {
+        let key_name = format!("key_area_key_system_{:02x}", idx as u8);
+        self.get_key(&key_name)
+    }
+
+    /// Get the title KEK for the specified key generation
+    pub fn get_title_kek(&self, key_generation: usize) -> Option<[u8; 0x10]> {
+        let key_name = format!("titlekek_{:02x}", key_generation as u8);
+        self.get_key(&key_name)
+    }
+
+    /// Get the game card title key KEK by index, used to decrypt the XCI initial data
+    pub fn get_xci_titlekey_kek(&self, idx: usize) -> Option<[u8; 0x10]> {
+        let key_name = format!("xci_t1_titlekey_kek_{:02x}", idx as u8);
+        self.get_key(&key_name)
+    }
+
+    /// Get all title KEKs as a HashMap indexed by generation
+    pub fn title_keks(&self) -> HashMap<u8, [u8; 0x10]> {
+        self.get_indexed_keys("titlekek")
+    }
+
+    /// Get all application key area keys as a HashMap indexed by generation
+    pub fn key_area_keys_application(&self) -> HashMap<u8, [u8; 0x10]> {
+        self.get_indexed_keys("key_area_key_application")
+    }
+
+    /// Get all ocean key area keys as a HashMap indexed by generation
+    pub fn key_area_keys_ocean(&self) -> HashMap<u8, [u8; 0x10]> {
+        self.get_indexed_keys("key_area_key_ocean")
+    }
+
+    /// Get all system key area keys as a HashMap indexed by generation
+    pub fn key_area_keys_system(&self) -> HashMap<u8, [u8; 0x10]> {
+        self.get_indexed_keys("key_area_key_system")
+    }
+
+    /// Check if the keyset has the necessary keys for NCA decryption
+    pub fn has_required_nca_keys(&self) -> bool {
+        self.header_key_cache.is_some()
+            && !self.key_area_keys_application().is_empty()
+            && !self.title_keks().is_empty()
+    }
+
+    /// Get key area key by key type and crypto index
+    pub fn get_key_area_key(&self, key_type: u8, crypto_type: u8) -> Option<[u8; 0x10]> {
+        match key_type {
+            0 => self.get_key_area_key_application(crypto_type as usize),
+            1 => self.get_key_area_key_ocean(crypto_type as usize),
+            2 => self.get_key_area_key_system(crypto_type as usize),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    #[test]
+    #[traced_test]
+    fn test_load_keyset() {
+        let keyset = Keyset::from_file("prod.keys").unwrap();
+
+        tracing::info!("{:#?}", keyset);
+
+        // Print all available key indices for debugging
+        tracing::info!("Available key indices:\n{}", keyset);
+
+        tracing::info!(
+            appkey_len = keyset.key_area_keys_application().len(),
+            "Loaded {} application key(s)",
+            keyset.key_area_keys_application().len()
+        );
+
+        // Test various key generations including beyond 0xF
+        for idx in [0, 1, 10, 16, 17, 18] {
+            let app_key = keyset.get_key_area_key_application(idx);
+            tracing::info!(
+                idx = idx,
+                has_key = app_key.is_some(),
+                "Application key generation {:02x}",
+                idx
+            );
+
+            let title_kek = keyset.get_title_kek(idx);
+            tracing::info!(
+                idx = idx,
+                has_key = title_kek.is_some(),
+                "Title KEK generation {:02x}",
+                idx
+            );
+        }
+
+        tracing::info!("Keyset loaded successfully");
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_key_parsing_edge_cases() {
+        // Create a test key file with keys for 0x10-0x12
+        let test_keys = r#"
+        header_key = 0000000000000000000000000000000000000000000000000000000000000000
+        key_area_key_application_00 = 00000000000000000000000000000000
+        key_area_key_application_10 = 1010101010101010101010101010101a
+        key_area_key_application_11 = 1111111111111111111111111111111b
+        key_area_key_application_12 = 1212121212121212121212121212121c
+        titlekek_00 = 00000000000000000000000000000000
+        titlekek_10 = 1010101010101010101010101010101a
+        titlekek_11 = 1111111111111111111111111111111b
+        titlekek_12 = 1212121212121212121212121212121c
+        ; Add some custom keys to test the new flexible system
+        custom_test_key = aabbccddeeff00112233445566778899
+        another_custom_key = 99887766554433221100ffeeddccbbaa
+        "#;
+
+        let cursor = std::io::Cursor::new(test_keys);
+        let keyset = Keyset::from_reader(cursor).unwrap();
+
+        // Test the indexed keys
+        let app_keys = keyset.key_area_keys_application();
+        let title_keks = keyset.title_keks();
+
+        assert!(app_keys.contains_key(&0));
+        assert!(app_keys.contains_key(&16)); // 0x10
+        assert!(app_keys.contains_key(&17)); // 0x11
+        assert!(app_keys.contains_key(&18)); // 0x12
+
+        assert!(title_keks.contains_key(&0));
+        assert!(title_keks.contains_key(&16)); // 0x10
+        assert!(title_keks.contains_key(&17)); // 0x11
+        assert!(title_keks.contains_key(&18)); // 0x12
+
+        // Check values of indexed keys
+        if let Some(key) = keyset.get_key_area_key_application(16) {
+            let expected_last_byte = 0x1a;
+            assert_eq!(
+                key[15], expected_last_byte,
+                "Key value mismatch for app key 0x10"
+            );
+        }
+
+        if let Some(key) = keyset.get_title_kek(16) {
+            let expected_last_byte = 0x1a;
+            assert_eq!(
+                key[15], expected_last_byte,
+                "Key value mismatch for titlekek 0x10"
+            );
+        }
+
+        // Test custom keys
+        let custom_key = keyset.get_key::<16>("custom_test_key");
+        assert!(custom_key.is_some(), "Custom key should be loaded");
+
+        if let Some(key) = custom_key {
+            assert_eq!(key[0], 0xaa);
+            assert_eq!(key[15], 0x99);
+        }
+
+        // Test prefixes
+        let prefixes = keyset.get_key_prefixes();
+        assert!(prefixes.contains(&"key_area_key_application".to_string()));
+        assert!(prefixes.contains(&"titlekek".to_string()));
+        assert!(prefixes.contains(&"custom_test_key".to_string()));
+        assert!(prefixes.contains(&"another_custom_key".to_string()));
+
+        // Test getting keys by prefix
+        let custom_keys = keyset.get_keys_with_prefix("custom");
+        assert_eq!(custom_keys.len(), 1); // Fix: only "custom_test_key" starts with prefix "custom"
+
+        let title_keys = keyset.get_keys_with_prefix("titlekek");
+        assert_eq!(title_keys.len(), 4);
+    }
+
+    #[test]
+    fn test_write_to_is_sorted() {
+        let test_keys = r#"
+        titlekek_10 = 1010101010101010101010101010101a
+        header_key = 0000000000000000000000000000000000000000000000000000000000000001
+        titlekek_02 = 02020202020202020202020202020202
+        key_area_key_application_00 = 00000000000000000000000000000000
+        "#;
+
+        let keyset = Keyset::from_reader(std::io::Cursor::new(test_keys)).unwrap();
+
+        let mut out = Vec::new();
+        keyset.write_to(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(
+            out,
+            "header_key = 0000000000000000000000000000000000000000000000000000000000000001\n\
+             key_area_key_application_00 = 00000000000000000000000000000000\n\
+             titlekek_02 = 02020202020202020202020202020202\n\
+             titlekek_10 = 1010101010101010101010101010101a\n"
+        );
+
+        // Round-trip through the parser
+        let reparsed = Keyset::from_reader(std::io::Cursor::new(out)).unwrap();
+        assert_eq!(reparsed.raw_keys, keyset.raw_keys);
+    }
+
+    #[test]
+    fn test_merge_reports_conflicts() {
+        let mut keyset = Keyset::from_reader(std::io::Cursor::new(
+            "titlekek_00 = 00000000000000000000000000000000\n",
+        ))
+        .unwrap();
+        let other = Keyset::from_reader(std::io::Cursor::new(
+            "titlekek_00 = 11111111111111111111111111111111\n\
+             header_key = 0000000000000000000000000000000000000000000000000000000000000001\n",
+        ))
+        .unwrap();
+
+        let report = keyset.merge(&other);
+
+        assert!(!report.is_clean());
+        assert_eq!(report.added, vec!["header_key".to_string()]);
+        assert_eq!(report.conflicts.len(), 1);
+        assert_eq!(report.conflicts[0].name, "titlekek_00");
+        assert_eq!(keyset.get_title_kek(0), Some([0; 0x10]));
+        assert!(keyset.header_key().is_some());
+    }
+
+    #[test]
+    #[cfg(not(feature = "log-keys"))]
+    fn test_debug_redacts_keys() {
+        let keyset = Keyset::from_reader(std::io::Cursor::new(
+            "titlekek_00 = 0123456789abcdef0123456789abcdef\n",
+        ))
+        .unwrap();
+
+        let debug = format!("{:?}", keyset);
+        assert!(debug.contains("titlekek_00"));
+        assert!(debug.contains("<redacted 16 bytes>"));
+        assert!(!debug.to_lowercase().contains("0123456789abcdef"));
+
+        let report = keyset.clone().merge(
+            &Keyset::from_reader(std::io::Cursor::new(
+                "titlekek_00 = fedcba9876543210fedcba9876543210\n",
+            ))
+            .unwrap(),
+        );
+        let debug = format!("{:?}", report);
+        assert!(debug.contains("titlekek_00"));
+        assert!(!debug.to_lowercase().contains("0123456789abcdef"));
+        assert!(!debug.to_lowercase().contains("fedcba9876543210"));
+    }
+
+    #[test]
+    fn test_header_key_and_crypt() {
+        let test_keys = r#"
+        header_key = 0000000000000000000000000000000000000000000000000000000000000001
+        "#;
+
+        let cursor = std::io::Cursor::new(test_keys);
+        let keyset = Keyset::from_reader(cursor).unwrap();
+
+        // Check if header key was loaded and cached
+        let header_key = keyset.header_key();
+        assert!(header_key.is_some(), "Header key should be cached");
+
+        if let Some(key) = header_key {
+            assert_eq!(key[31], 0x01);
+        }
+
+        // Check if header_crypt returns a valid cipher
+        let cipher = keyset.header_crypt();
+        assert!(cipher.is_some(), "Header cipher should be created");
+    }
+
+    #[test]
+    fn test_key_paths() {
+        let files = ["prod.keys", "dev.keys"];
+        let home = PathBuf::from("/home/user");
+
+        assert_eq!(
+            key_paths(
+                &files,
+                Some("/keys/my.keys".into()),
+                None,
+                Some(home.clone())
+            ),
+            [PathBuf::from("/keys/my.keys")]
+        );
+        assert_eq!(
+            key_paths(&files, None, Some("/xdg".into()), Some(home.clone())),
+            [
+                home.join(".switch/prod.keys"),
+                home.join(".switch/dev.keys"),
+                PathBuf::from("/xdg/switch/prod.keys"),
+                PathBuf::from("/xdg/switch/dev.keys"),
+                PathBuf::from("prod.keys"),
+                PathBuf::from("dev.keys"),
+            ]
+        );
+        // An empty XDG_CONFIG_HOME counts as unset
+        assert_eq!(
+            key_paths(&["dev.keys"], None, Some("".into()), Some(home.clone())),
+            [
+                home.join(".switch/dev.keys"),
+                home.join(".config/switch/dev.keys"),
+                PathBuf::from("dev.keys"),
+            ]
+        );
+        assert_eq!(
+            key_paths(&["dev.keys"], None, None, None),
+            [PathBuf::from("dev.keys")]
+        );
+    }
+
+    #[test]
+    fn test_load_first() {
+        /// Removes the directory when the test ends, even if an assert failed
+        struct TempDir(PathBuf);
+        impl Drop for TempDir {
+            fn drop(&mut self) {
+                let _ = std::fs::remove_dir_all(&self.0);
+            }
+        }
+
+        let dir =
+            TempDir(std::env::temp_dir().join(format!("nx-archive-keys-{}", std::process::id())));
+        std::fs::create_dir_all(&dir.0).unwrap();
+        let keys_path = dir.0.join("dev.keys");
+        std::fs::write(&keys_path, format!("header_key = {}\n", "11".repeat(0x20))).unwrap();
+        let missing_path = dir.0.join("missing.keys");
+
+        // Missing paths are skipped
+        let keyset =
+            Keyset::load_first(vec![missing_path.clone(), keys_path], "prod.keys").unwrap();
+        assert_eq!(keyset.environment, KeyEnvironment::Development);
+        assert_eq!(keyset.header_key().unwrap(), &[0x11; 0x20]);
+
+        match Keyset::load_first(vec![missing_path.clone()], "prod.keys") {
+            Err(KeyError::NotFoundInDefaultLocations { file, tried }) => {
+                assert_eq!(file, "prod.keys");
+                assert_eq!(tried, vec![missing_path]);
+            }
+            other => panic!("Expected NotFoundInDefaultLocations, got {:?}", other),
+        }
+
+        // A directory exists, but can't be read as a key file
+        match Keyset::load_first(vec![dir.0.clone()], "prod.keys") {
+            Err(KeyError::LoadFailed { failures, .. }) => {
+                assert_eq!(failures.len(), 1);
+                assert_eq!(failures[0].0, dir.0);
+            }
+            other => panic!("Expected LoadFailed, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,599 @@
+//! Keyset validation
+//!
+//! A `prod.keys` file is just a list of names and hex strings, so a typo or a truncated
+//! line is only noticed once something tries to decrypt with it. This module checks a
+//! loaded [`Keyset`] up front and produces a [`KeyValidationReport`] describing:
+//!
+//! - Keys whose length doesn't match what their name implies
+//! - Keys whose SHA-256 doesn't match a known fingerprint
+//! - Derived keys (master keys, title KEKs, key area keys, the header key, ...) that don't
+//!   agree with the source keys they are derived from
+//! - Which key generations are actually usable for header, key area and title key decryption
+
+use aes::Aes128;
+use cipher::{BlockDecrypt, KeyInit, generic_array::GenericArray};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+
+use super::{Keyset, split_key_index};
+
+/// Known SHA-256 fingerprints of keys, by key name
+///
+/// These are the published fingerprints of the key sources, which are the same on every
+/// console and in both environments. Fingerprints of other keys can be supplied at runtime
+/// with [`Keyset::validate_with_fingerprints`].
+pub static KNOWN_KEY_FINGERPRINTS: &[(&str, [u8; 0x20])] = &[
+    (
+        "aes_kek_generation_source",
+        hex_literal::hex!("fc02b9d37b42d7a1452e71444f1f700311d1132e301a83b16062e72a78175085"),
+    ),
+    (
+        "aes_key_generation_source",
+        hex_literal::hex!("fbd10056999edc7acdb96098e47e2c3606230270d23281e671f0f389fc5bc585"),
+    ),
+    (
+        "header_kek_source",
+        hex_literal::hex!("1888caed5551b3ede01499e87ce0d86827f80820efb275921055aa4e2abdffc2"),
+    ),
+    (
+        "header_key_source",
+        hex_literal::hex!("8f783e46852df6be0ba4e19273c4adbaee16380043e1b8c418c4089a8bd64aa6"),
+    ),
+    (
+        "key_area_key_application_source",
+        hex_literal::hex!("04ad66143c726b2a139fb6b21128b46f56c553b2b3887110304298d8d0092d9e"),
+    ),
+    (
+        "key_area_key_ocean_source",
+        hex_literal::hex!("fd434000c8ff2b26f8e9a9d2d2c12f6be5773cbb9dc86300e1bd99f8ea33a417"),
+    ),
+    (
+        "key_area_key_system_source",
+        hex_literal::hex!("1f17b1fd51ad1c2379b58f152ca4912ec2106441e51722f38700d5937a1162f7"),
+    ),
+    (
+        "titlekek_source",
+        hex_literal::hex!("c48b619827986c7f4e3081d59db2b460c84312650e9a8e6b458e53e8cbca4e87"),
+    ),
+];
+
+/// Expected key lengths, by key name or by key prefix for indexed keys (e.g. `titlekek_XX`)
+const KEY_LENGTHS: &[(&str, usize)] = &[
+    ("acid_fixed_key_modulus", 0x100),
+    ("aes_kek_generation_source", 0x10),
+    ("aes_key_generation_source", 0x10),
+    ("bis_kek_source", 0x10),
+    ("bis_key", 0x20),
+    ("bis_key_source", 0x20),
+    ("device_key", 0x10),
+    ("encrypted_keyblob", 0xB0),
+    ("eticket_rsa_kek", 0x10),
+    ("eticket_rsa_kek_source", 0x10),
+    ("eticket_rsa_kekek_source", 0x10),
+    ("header_kek_source", 0x10),
+    ("header_key", 0x20),
+    ("header_key_source", 0x20),
+    ("key_area_key_application", 0x10),
+    ("key_area_key_application_source", 0x10),
+    ("key_area_key_ocean", 0x10),
+    ("key_area_key_ocean_source", 0x10),
+    ("key_area_key_system", 0x10),
+    ("key_area_key_system_source", 0x10),
+    ("keyblob", 0x90),
+    ("keyblob_key", 0x10),
+    ("keyblob_key_source", 0x10),
+    ("keyblob_mac_key", 0x10),
+    ("keyblob_mac_key_source", 0x10),
+    ("mariko_bek", 0x10),
+    ("mariko_kek", 0x10),
+    ("mariko_master_kek_source", 0x10),
+    ("master_kek", 0x10),
+    ("master_kek_source", 0x10),
+    ("master_key", 0x10),
+    ("master_key_source", 0x10),
+    ("nca_hdr_fixed_key_modulus", 0x100),
+    ("nrr_fixed_key_modulus", 0x100),
+    ("package1_key", 0x10),
+    ("package2_key", 0x10),
+    ("package2_key_source", 0x10),
+    ("per_console_key_source", 0x10),
+    ("retail_specific_aes_key_source", 0x10),
+    ("root_cert_modulus", 0x200),
+    ("save_mac_kek_source", 0x10),
+    ("save_mac_key", 0x10),
+    ("save_mac_key_source", 0x10),
+    ("sd_card_kek_source", 0x10),
+    ("sd_card_nca_key_source", 0x20),
+    ("sd_card_save_key_source", 0x20),
+    ("sd_seed", 0x10),
+    ("secure_boot_key", 0x10),
+    ("ssl_rsa_kek", 0x10),
+    ("titlekek", 0x10),
+    ("titlekek_source", 0x10),
+    ("tsec_key", 0x10),
+    ("tsec_root_key", 0x10),
+    ("xci_cert_fixed_key_modulus", 0x100),
+    ("xci_header_fixed_key_modulus", 0x100),
+    ("xci_header_key", 0x10),
+    ("xci_t1_titlekey_kek", 0x10),
+];
+
+/// Key area key types, in the order of their key area encryption key index
+const KEY_AREA_TYPES: [&str; 3] = ["application", "ocean", "system"];
+
+/// Returns the expected length of a key by its name, if known
+pub fn expected_key_length(name: &str) -> Option<usize> {
+    let lookup = |n: &str| {
+        KEY_LENGTHS
+            .iter()
+            .find(|(key, _)| *key == n)
+            .map(|(_, len)| *len)
+    };
+
+    lookup(name).or_else(|| match split_key_index(name) {
+        (prefix, Some(_)) => lookup(prefix),
+        _ => None,
+    })
+}
+
+/// A single problem found while validating a keyset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyIssue {
+    /// The key's length doesn't match what its name implies
+    InvalidLength {
+        name: String,
+        expected: usize,
+        actual: usize,
+    },
+    /// The key's SHA-256 doesn't match its known fingerprint
+    FingerprintMismatch { name: String },
+    /// The key doesn't match the value derived from its source keys
+    DerivationMismatch {
+        name: String,
+        derived_from: Vec<String>,
+    },
+}
+
+impl fmt::Display for KeyIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyIssue::InvalidLength {
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} has an invalid length: expected 0x{:X} bytes, got 0x{:X}",
+                name, expected, actual
+            ),
+            KeyIssue::FingerprintMismatch { name } => {
+                write!(f, "{} does not match its known SHA-256 fingerprint", name)
+            }
+            KeyIssue::DerivationMismatch { name, derived_from } => write!(
+                f,
+                "{} does not match the value derived from {}",
+                name,
+                derived_from.join(", ")
+            ),
+        }
+    }
+}
+
+/// Result of [`Keyset::validate`]
+#[derive(Debug, Clone, Default)]
+pub struct KeyValidationReport {
+    /// Problems found in the keyset
+    pub issues: Vec<KeyIssue>,
+    /// Keys that matched a known fingerprint
+    pub verified_fingerprints: Vec<String>,
+    /// Derived keys that agree with their source keys
+    pub consistent_derivations: Vec<String>,
+    /// Keys whose expected length is not known, and were not checked
+    pub unchecked: Vec<String>,
+    /// Whether NCA headers can be decrypted (a valid `header_key` is present)
+    pub header_key_usable: bool,
+    /// Key generations usable for application key area decryption
+    pub key_area_application_generations: Vec<u8>,
+    /// Key generations usable for ocean key area decryption
+    pub key_area_ocean_generations: Vec<u8>,
+    /// Key generations usable for system key area decryption
+    pub key_area_system_generations: Vec<u8>,
+    /// Key generations usable for title key decryption
+    pub title_key_generations: Vec<u8>,
+}
+
+impl KeyValidationReport {
+    /// Whether the keyset passed all checks
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Whether the given key was flagged by any of the checks
+    pub fn has_issue(&self, name: &str) -> bool {
+        self.issues.iter().any(|issue| match issue {
+            KeyIssue::InvalidLength { name: n, .. }
+            | KeyIssue::FingerprintMismatch { name: n }
+            | KeyIssue::DerivationMismatch { name: n, .. } => n == name,
+        })
+    }
+}
+
+impl fmt::Display for KeyValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format_gens = |gens: &[u8]| {
+            gens.iter()
+                .map(|g| format!("{:02x}", g))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        writeln!(f, "Header key usable: {}", self.header_key_usable)?;
+        writeln!(
+            f,
+            "Application key area generations: {}",
+            format_gens(&self.key_area_application_generations)
+        )?;
+        writeln!(
+            f,
+            "Ocean key area generations: {}",
+            format_gens(&self.key_area_ocean_generations)
+        )?;
+        writeln!(
+            f,
+            "System key area generations: {}",
+            format_gens(&self.key_area_system_generations)
+        )?;
+        write!(
+            f,
+            "Title key generations: {}",
+            format_gens(&self.title_key_generations)
+        )?;
+
+        for issue in &self.issues {
+            write!(f, "\n{}", issue)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Decrypts `data` with AES-128-ECB
+pub(crate) fn decrypt_ecb(key: &[u8; 0x10], data: &[u8]) -> Vec<u8> {
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+    let mut out = data.to_vec();
+    for block in out.chunks_exact_mut(0x10) {
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+    }
+    out
+}
+
+/// Derives a key encryption key the same way the console's `GenerateAesKek` and
+/// `GenerateAesKey` do: `kek_seed` is decrypted with the master key, `source` with the
+/// result, and finally `key_seed` (if any) with that.
+pub(crate) fn generate_kek(
+    source: &[u8; 0x10],
+    master_key: &[u8; 0x10],
+    kek_seed: &[u8; 0x10],
+    key_seed: Option<&[u8; 0x10]>,
+) -> [u8; 0x10] {
+    let mut kek = [0u8; 0x10];
+    kek.copy_from_slice(&decrypt_ecb(master_key, kek_seed));
+
+    let mut src_kek = [0u8; 0x10];
+    src_kek.copy_from_slice(&decrypt_ecb(&kek, source));
+
+    match key_seed {
+        Some(seed) => {
+            let mut key = [0u8; 0x10];
+            key.copy_from_slice(&decrypt_ecb(&src_kek, seed));
+            key
+        }
+        None => src_kek,
+    }
+}
+
+impl Keyset {
+    /// Validate the keyset against the built-in fingerprints in [`KNOWN_KEY_FINGERPRINTS`]
+    ///
+    /// See [`Keyset::validate_with_fingerprints`] for what is checked.
+    pub fn validate(&self) -> KeyValidationReport {
+        let fingerprints = KNOWN_KEY_FINGERPRINTS
+            .iter()
+            .map(|(name, hash)| (name.to_string(), *hash))
+            .collect();
+        self.validate_with_fingerprints(&fingerprints)
+    }
+
+    /// Validate the keyset, comparing keys against the given SHA-256 fingerprints
+    ///
+    /// This checks:
+    /// - Key lengths, based on the key name
+    /// - SHA-256 fingerprints, for keys present in `fingerprints`
+    /// - That derived keys agree with their sources, when all sources are present
+    /// - Which key generations are usable for each kind of decryption
+    pub fn validate_with_fingerprints(
+        &self,
+        fingerprints: &HashMap<String, [u8; 0x20]>,
+    ) -> KeyValidationReport {
+        let mut report = KeyValidationReport::default();
+
+        let mut names: Vec<&String> = self.raw_keys.keys().collect();
+        names.sort();
+
+        for name in names {
+            let value = &self.raw_keys[name];
+
+            match expected_key_length(name) {
+                Some(expected) if expected != value.len() => {
+                    report.issues.push(KeyIssue::InvalidLength {
+                        name: name.clone(),
+                        expected,
+                        actual: value.len(),
+                    });
+                }
+                Some(_) => {}
+                None => report.unchecked.push(name.clone()),
+            }
+
+            if let Some(fingerprint) = fingerprints.get(name) {
+                if Sha256::digest(value).as_slice() == fingerprint {
+                    report.verified_fingerprints.push(name.clone());
+                } else {
+                    report
+                        .issues
+                        .push(KeyIssue::FingerprintMismatch { name: name.clone() });
+                }
+            }
+        }
+
+        self.check_derivations(&mut report);
+
+        let usable = |keys: HashMap<u8, [u8; 0x10]>, prefix: &str, report: &KeyValidationReport| {
+            let mut gens: Vec<u8> = keys
+                .into_keys()
+                .filter(|idx| !report.has_issue(&format!("{}_{:02x}", prefix, idx)))
+                .collect();
+            gens.sort();
+            gens
+        };
+
+        report.header_key_usable =
+            self.header_key_cache.is_some() && !report.has_issue("header_key");
+        report.key_area_application_generations = usable(
+            self.key_area_keys_application(),
+            "key_area_key_application",
+            &report,
+        );
+        report.key_area_ocean_generations =
+            usable(self.key_area_keys_ocean(), "key_area_key_ocean", &report);
+        report.key_area_system_generations =
+            usable(self.key_area_keys_system(), "key_area_key_system", &report);
+        report.title_key_generations = usable(self.title_keks(), "titlekek", &report);
+
+        for issue in &report.issues {
+            tracing::warn!("Keyset validation: {}", issue);
+        }
+
+        report
+    }
+
+    /// Compares a derived key with its expected value, recording the outcome in the report
+    fn check_derived(
+        &self,
+        report: &mut KeyValidationReport,
+        name: &str,
+        derived: &[u8],
+        derived_from: &[&str],
+    ) {
+        let Some(actual) = self.get_raw_key(name) else {
+            return;
+        };
+
+        if actual == derived {
+            report.consistent_derivations.push(name.to_string());
+        } else {
+            report.issues.push(KeyIssue::DerivationMismatch {
+                name: name.to_string(),
+                derived_from: derived_from.iter().map(|s| s.to_string()).collect(),
+            });
+        }
+    }
+
+    /// Checks derived keys against their source keys, when all of the sources are present
+    fn check_derivations(&self, report: &mut KeyValidationReport) {
+        let master_keys = self.get_indexed_keys::<0x10>("master_key");
+        let kek_seed = self.get_key::<0x10>("aes_kek_generation_source");
+        let key_seed = self.get_key::<0x10>("aes_key_generation_source");
+
+        // master_key_XX = AES-ECB(master_kek_XX, master_key_source)
+        if let Some(source) = self.get_key::<0x10>("master_key_source") {
+            for (idx, master_kek) in self.get_indexed_keys::<0x10>("master_kek") {
+                let name = format!("master_key_{:02x}", idx);
+                let kek_name = format!("master_kek_{:02x}", idx);
+                let derived = decrypt_ecb(&master_kek, &source);
+                self.check_derived(report, &name, &derived, &[&kek_name, "master_key_source"]);
+            }
+        }
+
+        for (idx, master_key) in &master_keys {
+            let master_key_name = format!("master_key_{:02x}", idx);
+
+            // titlekek_XX = AES-ECB(master_key_XX, titlekek_source)
+            if let Some(source) = self.get_key::<0x10>("titlekek_source") {
+                let derived = decrypt_ecb(master_key, &source);
+                self.check_derived(
+                    report,
+                    &format!("titlekek_{:02x}", idx),
+                    &derived,
+                    &[&master_key_name, "titlekek_source"],
+                );
+            }
+
+            // package2_key_XX = AES-ECB(master_key_XX, package2_key_source)
+            if let Some(source) = self.get_key::<0x10>("package2_key_source") {
+                let derived = decrypt_ecb(master_key, &source);
+                self.check_derived(
+                    report,
+                    &format!("package2_key_{:02x}", idx),
+                    &derived,
+                    &[&master_key_name, "package2_key_source"],
+                );
+            }
+
+            // key_area_key_<type>_XX = GenerateAesKek(key_area_key_<type>_source, master_key_XX)
+            if let (Some(kek_seed), Some(key_seed)) = (&kek_seed, &key_seed) {
+                for key_type in KEY_AREA_TYPES {
+                    let source_name = format!("key_area_key_{}_source", key_type);
+                    if let Some(source) = self.get_key::<0x10>(&source_name) {
+                        let derived = generate_kek(&source, master_key, kek_seed, Some(key_seed));
+                        self.check_derived(
+                            report,
+                            &format!("key_area_key_{}_{:02x}", key_type, idx),
+                            &derived,
+                            &[
+                                &master_key_name,
+                                &source_name,
+                                "aes_kek_generation_source",
+                                "aes_key_generation_source",
+                            ],
+                        );
+                    }
+                }
+            }
+        }
+
+        // header_key = AES-ECB(GenerateAesKek(header_kek_source, master_key_00), header_key_source)
+        if let (Some(master_key), Some(kek_seed), Some(key_seed), Some(kek_source), Some(source)) = (
+            master_keys.get(&0),
+            &kek_seed,
+            &key_seed,
+            self.get_key::<0x10>("header_kek_source"),
+            self.get_key::<0x20>("header_key_source"),
+        ) {
+            let header_kek = generate_kek(&kek_source, master_key, kek_seed, Some(key_seed));
+            let derived = decrypt_ecb(&header_kek, &source);
+            self.check_derived(
+                report,
+                "header_key",
+                &derived,
+                &[
+                    "master_key_00",
+                    "header_kek_source",
+                    "header_key_source",
+                    "aes_kek_generation_source",
+                    "aes_key_generation_source",
+                ],
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a small keyset where every derived key is consistent with its sources
+    fn consistent_keyset() -> Keyset {
+        let master_key = [0x11u8; 0x10];
+        let kek_seed = [0x22u8; 0x10];
+        let key_seed = [0x33u8; 0x10];
+        let titlekek_source = [0x44u8; 0x10];
+        let app_source = [0x55u8; 0x10];
+
+        let mut keyset = Keyset::default();
+        let mut insert = |name: &str, value: &[u8]| {
+            keyset
+                .raw_keys
+                .insert(name.to_string(), value.to_vec().into());
+        };
+
+        insert("master_key_00", &master_key);
+        insert("aes_kek_generation_source", &kek_seed);
+        insert("aes_key_generation_source", &key_seed);
+        insert("titlekek_source", &titlekek_source);
+        insert("key_area_key_application_source", &app_source);
+        insert("titlekek_00", &decrypt_ecb(&master_key, &titlekek_source));
+        insert(
+            "key_area_key_application_00",
+            &generate_kek(&app_source, &master_key, &kek_seed, Some(&key_seed)),
+        );
+
+        keyset
+    }
+
+    #[test]
+    fn test_expected_key_length() {
+        assert_eq!(expected_key_length("header_key"), Some(0x20));
+        assert_eq!(expected_key_length("titlekek_0a"), Some(0x10));
+        assert_eq!(expected_key_length("key_area_key_system_10"), Some(0x10));
+        assert_eq!(expected_key_length("encrypted_keyblob_00"), Some(0xB0));
+        assert_eq!(expected_key_length("custom_test_key"), None);
+    }
+
+    #[test]
+    fn test_validate_consistent_keyset() {
+        let keyset = consistent_keyset();
+        // The test keyset uses made-up sources, so leave out the known fingerprints
+        let report = keyset.validate_with_fingerprints(&HashMap::new());
+
+        assert!(report.is_ok(), "{}", report);
+        assert!(
+            report
+                .consistent_derivations
+                .contains(&"titlekek_00".to_string())
+        );
+        assert!(
+            report
+                .consistent_derivations
+                .contains(&"key_area_key_application_00".to_string())
+        );
+        assert_eq!(report.title_key_generations, vec![0]);
+        assert_eq!(report.key_area_application_generations, vec![0]);
+        assert!(!report.header_key_usable);
+    }
+
+    #[test]
+    fn test_validate_detects_problems() {
+        let mut keyset = consistent_keyset();
+        keyset
+            .raw_keys
+            .insert("titlekek_00".to_string(), vec![0xAA; 0x10].into());
+        keyset
+            .raw_keys
+            .insert("master_key_01".to_string(), vec![0xBB; 0x0F].into());
+
+        let fingerprints = HashMap::from([("master_key_00".to_string(), [0u8; 0x20])]);
+        let report = keyset.validate_with_fingerprints(&fingerprints);
+
+        assert!(!report.is_ok());
+        assert!(report.issues.contains(&KeyIssue::InvalidLength {
+            name: "master_key_01".to_string(),
+            expected: 0x10,
+            actual: 0x0F,
+        }));
+        assert!(report.issues.contains(&KeyIssue::FingerprintMismatch {
+            name: "master_key_00".to_string(),
+        }));
+        assert!(report.has_issue("titlekek_00"));
+        assert!(report.title_key_generations.is_empty());
+    }
+
+    #[test]
+    fn test_validate_checks_known_fingerprints() {
+        let mut keyset = consistent_keyset();
+        keyset
+            .raw_keys
+            .insert("header_key_source".to_string(), vec![0xCC; 0x20].into());
+
+        let report = keyset.validate();
+
+        assert!(report.issues.contains(&KeyIssue::FingerprintMismatch {
+            name: "header_key_source".to_string(),
+        }));
+        // The made-up sources in the test keyset don't match the real ones either
+        assert!(report.has_issue("titlekek_source"));
+        assert!(report.has_issue("aes_kek_generation_source"));
+        assert!(report.verified_fingerprints.is_empty());
+    }
+}
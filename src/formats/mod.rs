@@ -6,6 +6,17 @@ pub mod romfs;
 pub mod cnmt;
 pub mod xci;
 pub mod hfs0;
+pub mod ticket;
+pub mod nacp;
+pub mod nso;
+pub mod nro;
+pub mod npdm;
+pub mod nrr;
+pub mod nax0;
+pub mod elf;
 
-pub use keyset::Keyset;
+#[cfg(test)]
+mod test_support;
+
+pub use keyset::{KeyContext, Keyset};
 pub use title_keyset::TitleKeys;
\ No newline at end of file
@@ -0,0 +1,383 @@
+//! Application control properties (NACP)
+//!
+//! Every application has a `control.nacp` in the RomFS of its Control NCA. It holds the
+//! title and publisher in each language, the display version, age ratings, save data
+//! sizes and the other settings the system needs to launch the application.
+//!
+//! Use [`Nca::read_nacp`] to read it straight from a Control NCA, and [`Nca::read_icons`]
+//! for the icons that sit next to it.
+
+use binrw::prelude::*;
+use std::io::{Cursor, Read, Seek};
+
+use crate::error::Error;
+use crate::formats::nca::{ContentType, Nca};
+use crate::formats::romfs::RomFs;
+use crate::io::ReadSeek;
+use crate::util::fixed_string;
+
+/// Size of a `control.nacp`
+pub const NACP_SIZE: usize = 0x4000;
+
+/// Number of title entries, one per language
+pub const NACP_TITLE_COUNT: usize = 0x10;
+
+/// Title and publisher in one language
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone)]
+pub struct ApplicationTitle {
+    pub name: [u8; 0x200],
+    pub publisher: [u8; 0x100],
+}
+
+impl ApplicationTitle {
+    pub fn name_string(&self) -> String {
+        fixed_string(&self.name)
+    }
+
+    pub fn publisher_string(&self) -> String {
+        fixed_string(&self.publisher)
+    }
+
+    /// Whether the entry is unused, which means the language isn't provided
+    pub fn is_empty(&self) -> bool {
+        self.name[0] == 0 && self.publisher[0] == 0
+    }
+}
+
+/// Which user account the application asks for on startup
+#[binrw]
+#[brw(little, repr = u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartupUserAccount {
+    None = 0x00,
+    Required = 0x01,
+    RequiredWithNetworkServiceAccountAvailable = 0x02,
+}
+
+/// Languages of the title entries, in title entry order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NacpLanguage {
+    AmericanEnglish = 0,
+    BritishEnglish = 1,
+    Japanese = 2,
+    French = 3,
+    German = 4,
+    LatinAmericanSpanish = 5,
+    Spanish = 6,
+    Italian = 7,
+    Dutch = 8,
+    CanadianFrench = 9,
+    Portuguese = 10,
+    Russian = 11,
+    Korean = 12,
+    TraditionalChinese = 13,
+    SimplifiedChinese = 14,
+    BrazilianPortuguese = 15,
+}
+
+impl NacpLanguage {
+    /// Every language, in title entry order
+    pub const ALL: [NacpLanguage; NACP_TITLE_COUNT] = [
+        NacpLanguage::AmericanEnglish,
+        NacpLanguage::BritishEnglish,
+        NacpLanguage::Japanese,
+        NacpLanguage::French,
+        NacpLanguage::German,
+        NacpLanguage::LatinAmericanSpanish,
+        NacpLanguage::Spanish,
+        NacpLanguage::Italian,
+        NacpLanguage::Dutch,
+        NacpLanguage::CanadianFrench,
+        NacpLanguage::Portuguese,
+        NacpLanguage::Russian,
+        NacpLanguage::Korean,
+        NacpLanguage::TraditionalChinese,
+        NacpLanguage::SimplifiedChinese,
+        NacpLanguage::BrazilianPortuguese,
+    ];
+
+    /// Name of the language, as used in Control NCA file names
+    pub fn name(self) -> &'static str {
+        match self {
+            NacpLanguage::AmericanEnglish => "AmericanEnglish",
+            NacpLanguage::BritishEnglish => "BritishEnglish",
+            NacpLanguage::Japanese => "Japanese",
+            NacpLanguage::French => "French",
+            NacpLanguage::German => "German",
+            NacpLanguage::LatinAmericanSpanish => "LatinAmericanSpanish",
+            NacpLanguage::Spanish => "Spanish",
+            NacpLanguage::Italian => "Italian",
+            NacpLanguage::Dutch => "Dutch",
+            NacpLanguage::CanadianFrench => "CanadianFrench",
+            NacpLanguage::Portuguese => "Portuguese",
+            NacpLanguage::Russian => "Russian",
+            NacpLanguage::Korean => "Korean",
+            NacpLanguage::TraditionalChinese => "TraditionalChinese",
+            NacpLanguage::SimplifiedChinese => "SimplifiedChinese",
+            NacpLanguage::BrazilianPortuguese => "BrazilianPortuguese",
+        }
+    }
+
+    /// Path of the language's icon in the Control NCA RomFS
+    pub fn icon_path(self) -> String {
+        format!("/icon_{}.dat", self.name())
+    }
+}
+
+/// Rating organizations, in the order of [`Nacp::rating_age`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RatingOrganization {
+    Cero = 0,
+    Grac = 1,
+    Gsrmr = 2,
+    Esrb = 3,
+    ClassInd = 4,
+    Usk = 5,
+    Pegi = 6,
+    PegiPortugal = 7,
+    PegiBbfc = 8,
+    Russian = 9,
+    Acb = 10,
+    Oflc = 11,
+    IarcGeneric = 12,
+}
+
+/// The `control.nacp` of an application
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone)]
+pub struct Nacp {
+    pub titles: [ApplicationTitle; NACP_TITLE_COUNT],
+    pub isbn: [u8; 0x25],
+    pub startup_user_account: StartupUserAccount,
+    pub user_account_switch_lock: u8,
+    pub add_on_content_registration_type: u8,
+    pub attribute_flag: u32,
+    /// Bit N is set if the [`NacpLanguage`] with value N is supported
+    pub supported_language_flag: u32,
+    pub parental_control_flag: u32,
+    pub screenshot: u8,
+    pub video_capture: u8,
+    pub data_loss_confirmation: u8,
+    pub play_log_policy: u8,
+    pub presence_group_id: u64,
+    /// Minimum age per rating organization, `-1` if the application isn't rated by it
+    pub rating_age: [i8; 0x20],
+    pub display_version: [u8; 0x10],
+    pub add_on_content_base_id: u64,
+    pub save_data_owner_id: u64,
+    pub user_account_save_data_size: i64,
+    pub user_account_save_data_journal_size: i64,
+    pub device_save_data_size: i64,
+    pub device_save_data_journal_size: i64,
+    pub bcat_delivery_cache_storage_size: i64,
+    pub application_error_code_category: [u8; 8],
+    pub local_communication_id: [u64; 8],
+    pub logo_type: u8,
+    pub logo_handling: u8,
+    pub runtime_add_on_content_install: u8,
+    pub runtime_parameter_delivery: u8,
+    pub _reserved_30f4: [u8; 2],
+    pub crash_report: u8,
+    pub hdcp: u8,
+    pub seed_for_pseudo_device_id: u64,
+    pub bcat_passphrase: [u8; 0x41],
+    pub startup_user_account_option: u8,
+    pub _reserved_3142: [u8; 6],
+    pub user_account_save_data_size_max: i64,
+    pub user_account_save_data_journal_size_max: i64,
+    pub device_save_data_size_max: i64,
+    pub device_save_data_journal_size_max: i64,
+    pub temporary_storage_size: i64,
+    pub cache_storage_size: i64,
+    pub cache_storage_journal_size: i64,
+    pub cache_storage_data_and_journal_size_max: i64,
+    pub cache_storage_index_max: u16,
+    pub _reserved_318a: [u8; 6],
+    pub play_log_queryable_application_id: [u64; 0x10],
+    pub play_log_query_capability: u8,
+    pub repair_flag: u8,
+    pub program_index: u8,
+    pub required_network_service_license_on_launch: u8,
+    pub _reserved_3214: [u8; 4],
+    pub neighbor_detection_client_configuration: [u8; 0x198],
+    pub jit_configuration_flag: u64,
+    pub jit_memory_size: u64,
+    pub required_add_on_contents_set_binary_descriptors: [u16; 0x20],
+    pub play_report_permission: u8,
+    pub crash_screenshot_for_prod: u8,
+    pub crash_screenshot_for_dev: u8,
+    pub contents_availability_transition_policy: u8,
+    pub _reserved_3404: [u8; 0xBFC],
+}
+
+impl Nacp {
+    /// Parse a `control.nacp`
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<Self, Error> {
+        Ok(reader.read_le()?)
+    }
+
+    /// Parse a `control.nacp` from its bytes
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < NACP_SIZE {
+            return Err(Error::InvalidData(format!(
+                "NACP is 0x{:X} bytes, expected 0x{:X}",
+                data.len(),
+                NACP_SIZE
+            )));
+        }
+        Self::from_reader(&mut Cursor::new(data))
+    }
+
+    /// The first non-empty title entry, which is what the system shows as a fallback
+    pub fn default_title(&self) -> Option<&ApplicationTitle> {
+        self.titles.iter().find(|title| !title.is_empty())
+    }
+
+    /// Title entry for a language, `None` if it's empty
+    pub fn title(&self, language: NacpLanguage) -> Option<&ApplicationTitle> {
+        Some(&self.titles[language as usize]).filter(|title| !title.is_empty())
+    }
+
+    /// Whether a language is marked as supported
+    pub fn supports_language(&self, language: NacpLanguage) -> bool {
+        self.supported_language_flag & (1 << language as u32) != 0
+    }
+
+    /// Version shown to the user, such as `1.0.2`
+    pub fn display_version_string(&self) -> String {
+        fixed_string(&self.display_version)
+    }
+
+    pub fn isbn_string(&self) -> String {
+        fixed_string(&self.isbn)
+    }
+
+    pub fn bcat_passphrase_string(&self) -> String {
+        fixed_string(&self.bcat_passphrase)
+    }
+
+    pub fn application_error_code_category_string(&self) -> String {
+        fixed_string(&self.application_error_code_category)
+    }
+
+    /// Minimum age given by a rating organization, `None` if it didn't rate the application
+    pub fn rating_age(&self, organization: RatingOrganization) -> Option<u8> {
+        u8::try_from(self.rating_age[organization as usize]).ok()
+    }
+}
+
+impl<R: Read + Seek> Nca<R> {
+    fn open_control_romfs(&mut self) -> Result<RomFs<Box<dyn ReadSeek + '_>>, Error> {
+        if self.header.content_type != ContentType::Control {
+            return Err(Error::InvalidOperation(format!(
+                "Expected a Control NCA, got {:?}",
+                self.header.content_type
+            )));
+        }
+        self.open_romfs_filesystem(0)
+    }
+
+    /// Read the `control.nacp` of a Control NCA
+    pub fn read_nacp(&mut self) -> Result<Nacp, Error> {
+        let data = self
+            .open_control_romfs()?
+            .read_to_vec("/control.nacp")?
+            .ok_or_else(|| Error::NotFound("control.nacp".to_string()))?;
+        Nacp::from_bytes(&data)
+    }
+
+    /// Read every icon of a Control NCA, in title entry order
+    ///
+    /// Icons are JPEGs, returned as-is.
+    pub fn read_icons(&mut self) -> Result<Vec<(NacpLanguage, Vec<u8>)>, Error> {
+        let mut romfs = self.open_control_romfs()?;
+        let mut icons = Vec::new();
+        for language in NacpLanguage::ALL {
+            if let Some(icon) = romfs.read_to_vec(&language.icon_path())? {
+                icons.push((language, icon));
+            }
+        }
+        Ok(icons)
+    }
+
+    /// Read the icon for a language, falling back to the first language that has one
+    ///
+    /// Returns the language of the icon that was found along with the JPEG, or `None` if
+    /// the Control NCA has no icons.
+    pub fn read_icon(
+        &mut self,
+        language: NacpLanguage,
+    ) -> Result<Option<(NacpLanguage, Vec<u8>)>, Error> {
+        let mut romfs = self.open_control_romfs()?;
+        if let Some(icon) = romfs.read_to_vec(&language.icon_path())? {
+            return Ok(Some((language, icon)));
+        }
+        for fallback in NacpLanguage::ALL {
+            if let Some(icon) = romfs.read_to_vec(&fallback.icon_path())? {
+                return Ok(Some((fallback, icon)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nacp() {
+        let mut data = vec![0u8; NACP_SIZE];
+        // British English, the second entry
+        data[0x300..0x30B].copy_from_slice(b"Test Title!");
+        data[0x500..0x508].copy_from_slice(b"ACME Ltd");
+        data[0x3025] = 0x01;
+        data[0x302C..0x3030].copy_from_slice(&0b10u32.to_le_bytes());
+        data[0x3038..0x3040].copy_from_slice(&0x0100000000001000u64.to_le_bytes());
+        data[0x3040..0x3060].fill(0xFF);
+        data[0x3043] = 12;
+        data[0x3060..0x3065].copy_from_slice(b"1.0.2");
+        data[0x3080..0x3088].copy_from_slice(&0x400000i64.to_le_bytes());
+        data[0x3210] = 0x02;
+
+        let nacp = Nacp::from_bytes(&data).unwrap();
+        assert!(nacp.titles[0].is_empty());
+        let title = nacp.default_title().unwrap();
+        assert_eq!(title.name_string(), "Test Title!");
+        assert_eq!(title.publisher_string(), "ACME Ltd");
+        assert!(nacp.title(NacpLanguage::AmericanEnglish).is_none());
+        assert!(nacp.title(NacpLanguage::BritishEnglish).is_some());
+        assert!(nacp.supports_language(NacpLanguage::BritishEnglish));
+        assert!(!nacp.supports_language(NacpLanguage::AmericanEnglish));
+        assert_eq!(nacp.startup_user_account, StartupUserAccount::Required);
+        assert_eq!(nacp.presence_group_id, 0x0100000000001000);
+        assert_eq!(nacp.rating_age(RatingOrganization::Esrb), Some(12));
+        assert_eq!(nacp.rating_age(RatingOrganization::Cero), None);
+        assert_eq!(nacp.display_version_string(), "1.0.2");
+        assert_eq!(nacp.user_account_save_data_size, 0x400000);
+        assert_eq!(nacp.play_log_query_capability, 0x02);
+
+        let mut written = Cursor::new(Vec::new());
+        nacp.write_le(&mut written).unwrap();
+        assert_eq!(written.into_inner(), data);
+    }
+
+    #[test]
+    fn test_language_order() {
+        for (index, language) in NacpLanguage::ALL.into_iter().enumerate() {
+            assert_eq!(language as usize, index);
+        }
+        assert_eq!(
+            NacpLanguage::TraditionalChinese.icon_path(),
+            "/icon_TraditionalChinese.dat"
+        );
+    }
+
+    #[test]
+    fn test_nacp_too_short() {
+        assert!(Nacp::from_bytes(&[0; 0x3000]).is_err());
+    }
+}
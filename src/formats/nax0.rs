@@ -0,0 +1,393 @@
+//! SD card encrypted content (NAX0)
+//!
+//! Content installed to the SD card is stored under `Nintendo/Contents` wrapped in NAX0: a
+//! small header followed by the content, encrypted with AES-XTS in 0x4000 byte sectors.
+//!
+//! Each file has its own XTS keys, stored in the header encrypted with keys derived from the
+//! console's SD card NCA key and the file's path. The header MAC covers the decrypted keys,
+//! so it only matches when the path and SD seed are right, and a wrong path is caught before
+//! any content is read.
+
+use aes::Aes128;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, KeyInit};
+use binrw::prelude::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use xts_mode::Xts128;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::error::Error;
+use crate::formats::Keyset;
+use crate::formats::keyset::{decrypt_ecb, generate_kek};
+use crate::io::{align_down, get_nintendo_tweak};
+
+/// Size of the NAX0 header
+pub const NAX0_HEADER_SIZE: usize = 0x80;
+/// Offset of the encrypted content
+pub const NAX0_DATA_OFFSET: u64 = 0x4000;
+/// Size of an AES-XTS sector
+pub const NAX0_SECTOR_SIZE: u64 = 0x4000;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 0x20] {
+    let mut mac =
+        <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// NAX0 header
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone)]
+pub struct Nax0Header {
+    /// HMAC-SHA256 of the second half of the SD card NCA key, keyed with the rest of the
+    /// header with the XTS keys decrypted
+    pub mac: [u8; 0x20],
+    #[brw(magic = b"NAX0")]
+    pub _reserved: u32,
+    /// The two XTS keys, each encrypted with AES-ECB
+    pub encrypted_keys: [[u8; 0x10]; 2],
+    /// Size of the decrypted content
+    pub size: u64,
+    pub _reserved2: [u8; 0x30],
+}
+
+impl Keyset {
+    /// Derive the SD card NCA key
+    ///
+    /// Needs `sd_card_kek_source`, `sd_card_nca_key_source`, the console's `sd_seed`, and
+    /// `master_key_00` with the AES key generation sources.
+    pub fn sd_card_nca_key(&self) -> Result<[u8; 0x20], Error> {
+        fn require<const N: usize>(keyset: &Keyset, name: &str) -> Result<[u8; N], Error> {
+            keyset
+                .get_key::<N>(name)
+                .ok_or_else(|| Error::KeyLookupError(format!("Missing {}", name)))
+        }
+
+        let sd_kek = generate_kek(
+            &require(self, "sd_card_kek_source")?,
+            &require(self, "master_key_00")?,
+            &require(self, "aes_kek_generation_source")?,
+            Some(&require(self, "aes_key_generation_source")?),
+        );
+
+        let seed: [u8; 0x10] = require(self, "sd_seed")?;
+        let mut source: [u8; 0x20] = require(self, "sd_card_nca_key_source")?;
+        for (byte, seed) in source.iter_mut().zip(seed.iter().cycle()) {
+            *byte ^= seed;
+        }
+
+        let mut key = [0u8; 0x20];
+        key.copy_from_slice(&decrypt_ecb(&sd_kek, &source));
+        source.zeroize();
+        Ok(key)
+    }
+}
+
+/// Path of a file relative to `Nintendo/Contents`, as used to derive its NAX0 keys
+///
+/// For example `/sd/Nintendo/Contents/registered/000000AB/<id>.nca` gives
+/// `/registered/000000AB/<id>.nca`. Returns `None` if the path isn't under `Contents`.
+pub fn sd_content_path(path: impl AsRef<Path>) -> Option<String> {
+    let components: Vec<_> = path
+        .as_ref()
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect();
+    let contents = components.iter().rposition(|c| c == "Contents")?;
+    if contents + 1 == components.len() {
+        return None;
+    }
+
+    Some(
+        components[contents + 1..]
+            .iter()
+            .fold(String::new(), |path, component| path + "/" + component),
+    )
+}
+
+/// Decrypted view of a NAX0 file
+///
+/// Implements `Read` and `Seek` over the decrypted content, so it can be passed to other
+/// readers such as [`crate::formats::nca::Nca::from_reader`].
+pub struct Nax0<R: Read + Seek> {
+    reader: R,
+    pub header: Nax0Header,
+    /// The decrypted XTS keys
+    keys: [u8; 0x20],
+    position: u64,
+    /// Index and decrypted contents of the last sector read
+    sector: Option<(u64, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> Nax0<R> {
+    /// Open a NAX0 file, deriving its keys from the keyset
+    ///
+    /// `path` is the file's path relative to `Nintendo/Contents`, see [`sd_content_path`].
+    ///
+    /// # Errors
+    /// [`Error::KeyLookupError`] if the SD card NCA key can't be derived, and
+    /// [`Error::InvalidData`] if the header MAC doesn't match, usually because of a wrong
+    /// path or SD seed.
+    pub fn from_reader(reader: R, keyset: &Keyset, path: &str) -> Result<Self, Error> {
+        let mut sd_key = keyset.sd_card_nca_key()?;
+        let result = Self::from_reader_with_key(reader, &sd_key, path);
+        sd_key.zeroize();
+        result
+    }
+
+    /// Open a NAX0 file with an already derived SD card NCA key
+    pub fn from_reader_with_key(
+        mut reader: R,
+        sd_card_nca_key: &[u8; 0x20],
+        path: &str,
+    ) -> Result<Self, Error> {
+        let mut header_bytes = [0u8; NAX0_HEADER_SIZE];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut header_bytes)?;
+        let header: Nax0Header = Cursor::new(&header_bytes).read_le()?;
+
+        // The first half of the SD card key and the path give the key encryption keys
+        let mut kek = hmac_sha256(&sd_card_nca_key[..0x10], path.as_bytes());
+        let mut keys = [0u8; 0x20];
+        for (i, encrypted) in header.encrypted_keys.iter().enumerate() {
+            let cipher = Aes128::new(GenericArray::from_slice(&kek[i * 0x10..(i + 1) * 0x10]));
+            let mut block = GenericArray::clone_from_slice(encrypted);
+            cipher.decrypt_block(&mut block);
+            keys[i * 0x10..(i + 1) * 0x10].copy_from_slice(&block);
+        }
+        kek.zeroize();
+
+        // The MAC is keyed with the header after it, holding the decrypted keys, over the
+        // second half of the SD card key
+        let mut mac_key = Zeroizing::new(header_bytes[0x20..].to_vec());
+        mac_key[0x8..0x28].copy_from_slice(&keys);
+        if hmac_sha256(&mac_key, &sd_card_nca_key[0x10..]) != header.mac {
+            keys.zeroize();
+            return Err(Error::InvalidData(format!(
+                "NAX0 header MAC mismatch, is {} the right path and the SD seed correct?",
+                path
+            )));
+        }
+
+        Ok(Self {
+            reader,
+            header,
+            keys,
+            position: 0,
+            sector: None,
+        })
+    }
+
+    /// Size of the decrypted content
+    pub fn size(&self) -> u64 {
+        self.header.size
+    }
+
+    /// Read and decrypt a sector, caching it for the following reads
+    fn read_sector(&mut self, index: u64) -> io::Result<&[u8]> {
+        if self
+            .sector
+            .as_ref()
+            .is_none_or(|(cached, _)| *cached != index)
+        {
+            let mut data = Vec::with_capacity(NAX0_SECTOR_SIZE as usize);
+            self.reader
+                .seek(SeekFrom::Start(NAX0_DATA_OFFSET + index * NAX0_SECTOR_SIZE))?;
+            self.reader
+                .by_ref()
+                .take(NAX0_SECTOR_SIZE)
+                .read_to_end(&mut data)?;
+            data.truncate(align_down(data.len() as u64, 0x10) as usize);
+
+            let xts = Xts128::new(
+                Aes128::new(GenericArray::from_slice(&self.keys[..0x10])),
+                Aes128::new(GenericArray::from_slice(&self.keys[0x10..])),
+            );
+            xts.decrypt_area(
+                &mut data,
+                NAX0_SECTOR_SIZE as usize,
+                index as u128,
+                get_nintendo_tweak,
+            );
+            self.sector = Some((index, data));
+        }
+
+        Ok(&self.sector.as_ref().unwrap().1)
+    }
+}
+
+impl<R: Read + Seek> Drop for Nax0<R> {
+    fn drop(&mut self) {
+        self.keys.zeroize();
+    }
+}
+
+impl<R: Read + Seek> Read for Nax0<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.size();
+        if buf.is_empty() || self.position >= size {
+            return Ok(0);
+        }
+
+        let position = self.position;
+        let offset = (position % NAX0_SECTOR_SIZE) as usize;
+        let sector = self.read_sector(position / NAX0_SECTOR_SIZE)?;
+        if offset >= sector.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "NAX0 content is shorter than its header says",
+            ));
+        }
+
+        let len = buf
+            .len()
+            .min(sector.len() - offset)
+            .min((size - position) as usize);
+        buf[..len].copy_from_slice(&sector[offset..offset + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for Nax0<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        self.position = new_pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot seek before the start of the stream",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+
+    // The vectors below come from a separate implementation of hactool's NAX0 code (Python,
+    // with the `cryptography` package), not from this module
+    const PATH: &str = "/registered/000000AB/0123456789abcdef0123456789abcdef.nca";
+    const SIZE: u64 = 0x4200;
+    const SD_CARD_NCA_KEY: [u8; 0x20] =
+        hex!("366de00c60e6d543e647f03e5e0cf94afcbb02d598777d35c6c57ce1890221e6");
+    /// The XTS keys `10..1F` and `20..2F`, encrypted for [`PATH`]
+    const ENCRYPTED_KEYS: [u8; 0x20] =
+        hex!("d1906f69d6b334c9e542dd330db2814af620e996570db757a0dc2498e9374498");
+    const MAC: [u8; 0x20] =
+        hex!("f93605df2f164400201f5e934ea624f36b1f1795ad88c0b32a1e7baee8157f69");
+    /// Start of the second sector of the encrypted content
+    const SECTOR_1_START: [u8; 0x20] =
+        hex!("deccfe350cf3797257d5fb44cc14204c1da76b1ce1c5820f7c79923e7eafe282");
+
+    fn test_keyset() -> Keyset {
+        let mut keyset = Keyset::default();
+        for (name, value) in [
+            ("master_key_00", vec![0x01; 0x10]),
+            ("aes_kek_generation_source", vec![0x02; 0x10]),
+            ("aes_key_generation_source", vec![0x03; 0x10]),
+            ("sd_card_kek_source", vec![0x04; 0x10]),
+            ("sd_card_nca_key_source", (0x80..0xA0).collect()),
+            ("sd_seed", (0x60..0x70).collect()),
+        ] {
+            keyset.raw_keys.insert(name.to_string(), value.into());
+        }
+        keyset
+    }
+
+    fn plaintext() -> Vec<u8> {
+        (0..SIZE).map(|i| (i * 7) as u8).collect()
+    }
+
+    fn build_nax0() -> Vec<u8> {
+        let mut nax0 = MAC.to_vec();
+        nax0.extend_from_slice(b"NAX0");
+        nax0.extend_from_slice(&[0; 4]);
+        nax0.extend_from_slice(&ENCRYPTED_KEYS);
+        nax0.extend_from_slice(&SIZE.to_le_bytes());
+        nax0.resize(NAX0_DATA_OFFSET as usize, 0);
+
+        let xts = Xts128::new(
+            Aes128::new(&hex!("101112131415161718191a1b1c1d1e1f").into()),
+            Aes128::new(&hex!("202122232425262728292a2b2c2d2e2f").into()),
+        );
+        let mut content = plaintext();
+        xts.encrypt_area(
+            &mut content,
+            NAX0_SECTOR_SIZE as usize,
+            0,
+            get_nintendo_tweak,
+        );
+        nax0.extend_from_slice(&content);
+        nax0
+    }
+
+    #[test]
+    fn test_sd_card_nca_key() {
+        let keyset = test_keyset();
+        assert_eq!(keyset.sd_card_nca_key().unwrap(), SD_CARD_NCA_KEY);
+
+        let mut keyset = keyset;
+        keyset.raw_keys.remove("sd_seed");
+        assert!(matches!(
+            keyset.sd_card_nca_key(),
+            Err(Error::KeyLookupError(_))
+        ));
+    }
+
+    #[test]
+    fn test_sd_content_path() {
+        assert_eq!(
+            sd_content_path(format!("/mnt/sd/Nintendo/Contents{}", PATH)).as_deref(),
+            Some(PATH)
+        );
+        assert_eq!(sd_content_path("/mnt/sd/Nintendo/Contents"), None);
+        assert_eq!(
+            sd_content_path("/mnt/sd/Nintendo/save/8000000000000000"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_nax0() {
+        let bytes = build_nax0();
+        assert_eq!(&bytes[0x8000..0x8020], &SECTOR_1_START);
+
+        let mut nax0 = Nax0::from_reader(Cursor::new(bytes.clone()), &test_keyset(), PATH).unwrap();
+        assert_eq!(nax0.size(), SIZE);
+
+        let mut content = Vec::new();
+        nax0.read_to_end(&mut content).unwrap();
+        assert_eq!(content, plaintext());
+
+        // Across the sector boundary
+        let mut buf = [0; 0x20];
+        nax0.seek(SeekFrom::Start(0x3FF0)).unwrap();
+        nax0.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, plaintext()[0x3FF0..0x4010]);
+
+        nax0.seek(SeekFrom::End(-0x10)).unwrap();
+        assert_eq!(nax0.read(&mut buf).unwrap(), 0x10);
+        assert_eq!(nax0.read(&mut buf).unwrap(), 0);
+
+        // The keys are tied to the path
+        let other = PATH.replace("000000AB", "000000AC");
+        assert!(matches!(
+            Nax0::from_reader_with_key(Cursor::new(bytes), &SD_CARD_NCA_KEY, &other),
+            Err(Error::InvalidData(_))
+        ));
+    }
+}
@@ -2,12 +2,14 @@ use super::KeyArea;
 use super::NcaHeader;
 use super::types::*;
 use crate::error::Error;
+use crate::formats::keyset::RedactedKey;
 use crate::formats::{Keyset, TitleKeys};
 use tracing;
+use zeroize::Zeroizing;
 
 pub struct NcaKeyManagement {
-    dec_title_key: Option<[u8; 0x10]>,
-    dec_key_area: KeyArea,
+    dec_title_key: Option<Zeroizing<[u8; 0x10]>>,
+    dec_key_area: Zeroizing<KeyArea>,
     key_status: bool,
 }
 
@@ -17,7 +19,7 @@ impl NcaKeyManagement {
         keyset: &Keyset,
         title_keys: Option<&TitleKeys>,
     ) -> Result<Self, Error> {
-        let mut dec_key_area = KeyArea::default();
+        let mut dec_key_area = Zeroizing::new(KeyArea::default());
         let mut key_status = true;
 
         // Process key decryption based on rights ID
@@ -29,7 +31,7 @@ impl NcaKeyManagement {
         };
 
         Ok(Self {
-            dec_title_key,
+            dec_title_key: dec_title_key.map(Zeroizing::new),
             dec_key_area,
             key_status,
         })
@@ -50,7 +52,7 @@ impl NcaKeyManagement {
             let title_kek = keyset.get_title_kek(key_gen as usize);
             tracing::trace!(
                 key_gen = %key_gen,
-                title_kek = ?title_kek,
+                title_kek = ?title_kek.as_ref().map(|k| RedactedKey(k)),
                 "Title KEK obtained"
             );
 
@@ -90,31 +92,32 @@ impl NcaKeyManagement {
         let key_area_key = match header.key_area_appkey_index {
             KeyAreaEncryptionKeyIndex::Application => {
                 let key = keyset.get_key_area_key_application(key_gen as usize);
-                tracing::trace!(key_gen = %key_gen, key_type = "Application", key = ?key, "Key area key obtained");
+                tracing::trace!(key_gen = %key_gen, key_type = "Application", key = ?key.as_ref().map(|k| RedactedKey(k)), "Key area key obtained");
                 key
             }
             KeyAreaEncryptionKeyIndex::Ocean => {
                 let key = keyset.get_key_area_key_ocean(key_gen as usize);
-                tracing::trace!(key_gen = %key_gen, key_type = "Ocean", key = ?key, "Key area key obtained");
+                tracing::trace!(key_gen = %key_gen, key_type = "Ocean", key = ?key.as_ref().map(|k| RedactedKey(k)), "Key area key obtained");
                 key
             }
             KeyAreaEncryptionKeyIndex::System => {
                 let key = keyset.get_key_area_key_system(key_gen as usize);
-                tracing::trace!(key_gen = %key_gen, key_type = "System", key = ?key, "Key area key obtained");
+                tracing::trace!(key_gen = %key_gen, key_type = "System", key = ?key.as_ref().map(|k| RedactedKey(k)), "Key area key obtained");
                 key
             }
         };
 
         if let Some(key) = key_area_key {
             tracing::trace!(
-                encrypted_key = %hex::encode(header.encrypted_keys.aes_ctr_key),
+                encrypted_key = %RedactedKey(&header.encrypted_keys.aes_ctr_key),
                 "Decrypting key area"
             );
 
             use cipher::BlockDecryptMut;
             use cipher::KeyInit;
 
-            let mut key_area_copy = header.encrypted_keys.clone();
+            // Decrypt in place, so the plaintext never sits outside the zeroized key area
+            *dec_key_area = header.encrypted_keys.clone();
             type Aes128EcbDec = ecb::Decryptor<aes::Aes128>;
 
             let mut decryptor = Aes128EcbDec::new_from_slice(&key)
@@ -122,15 +125,13 @@ impl NcaKeyManagement {
 
             decryptor.decrypt_blocks_mut(unsafe {
                 core::slice::from_raw_parts_mut(
-                    &mut key_area_copy as *mut KeyArea as *mut aes::Block,
+                    dec_key_area as *mut KeyArea as *mut aes::Block,
                     std::mem::size_of::<KeyArea>() / 16,
                 )
             });
 
-            *dec_key_area = key_area_copy;
-
             tracing::trace!(
-                decrypted_key = %hex::encode(dec_key_area.aes_ctr_key),
+                decrypted_key = %RedactedKey(&dec_key_area.aes_ctr_key),
                 "Key area decrypted"
             );
             Ok(())
@@ -151,9 +152,9 @@ impl NcaKeyManagement {
 
     pub fn get_aes_ctr_decrypt_key(&self, rights_id: &[u8; 0x10]) -> Result<[u8; 0x10], Error> {
         if !rights_id.iter().all(|&b| b == 0) {
-            if let Some(dec_key) = self.dec_title_key {
-                tracing::trace!(key = %hex::encode(dec_key), "Using decrypted title key");
-                return Ok(dec_key);
+            if let Some(dec_key) = &self.dec_title_key {
+                tracing::trace!(key = %RedactedKey(&**dec_key), "Using decrypted title key");
+                return Ok(**dec_key);
             }
 
             let rights_id_hex = hex::encode(rights_id).to_uppercase();
@@ -169,7 +170,7 @@ impl NcaKeyManagement {
             ));
         }
 
-        tracing::trace!(key = %hex::encode(self.dec_key_area.aes_ctr_key), "Using decrypted key area key");
+        tracing::trace!(key = %RedactedKey(&self.dec_key_area.aes_ctr_key), "Using decrypted key area key");
         Ok(self.dec_key_area.aes_ctr_key)
     }
 }
@@ -34,20 +34,23 @@ use binrw::prelude::*;
 use std::io::{Read, Seek};
 
 mod keys;
+mod requirements;
 mod types;
 
 // Add tracing instrument import
 use tracing::instrument;
 
 // Use the ReadSeek trait from io module instead of from crate root
+use crate::KeyRequirementsExt;
 use crate::io::{Aes128CtrReader, ReadSeek, SubFile};
 
-use super::keyset::get_nintendo_tweak;
+use super::keyset::{FixedKeys, RedactedKey, get_nintendo_tweak, verify_rsa_pss_sha256};
 use super::pfs0::Pfs0;
 use super::romfs::RomFs; // Add import for RomFs
 use super::{Keyset, TitleKeys};
 use keys::NcaKeyManagement;
-use types::*;
+pub use requirements::*;
+pub use types::*;
 
 // Constants for NCA structure
 const NCA_HEADER_SIZE: usize = 0x400;
@@ -86,7 +89,10 @@ pub fn decrypt_with_header_key(
 ) -> Result<Vec<u8>, crate::error::Error> {
     let mut decrypted = data.to_vec();
     let xts = keyset.header_crypt().ok_or_else(|| {
-        crate::error::Error::CryptoError("Failed to get header crypt".to_string())
+        crate::error::Error::CryptoError(format!(
+            "Failed to get header crypt, no {} header_key",
+            keyset.environment
+        ))
     })?;
 
     xts.decrypt_area(
@@ -280,6 +286,52 @@ impl NcaHeader {
         cursor.into_inner()
     }
 
+    /// Verify `header_sig` against the fixed NCA header key for this header's signature key generation
+    ///
+    /// `header_bytes` is the decrypted NCA header, of which bytes 0x200..0x400 are signed.
+    /// Returns `Ok(false)` if the signature doesn't match, and an error if the keyset has no
+    /// modulus for the signature key generation.
+    pub fn verify_signature(
+        &self,
+        header_bytes: &[u8],
+        fixed_keys: &FixedKeys,
+    ) -> Result<bool, crate::error::Error> {
+        let signed = Self::signed_region(header_bytes)?;
+        let modulus = fixed_keys.nca_header_modulus(self.signature_key_generation)?;
+        Ok(verify_rsa_pss_sha256(
+            modulus,
+            signed,
+            self.header_sig.as_bytes(),
+        ))
+    }
+
+    /// Verify `header_key_sig` against the public key from the program's ACID
+    ///
+    /// Only Program NCAs carry this signature. It signs the same bytes as `header_sig`, and is
+    /// only meaningful once the ACID itself has been verified.
+    pub fn verify_key_signature(
+        &self,
+        header_bytes: &[u8],
+        public_key: &[u8],
+    ) -> Result<bool, crate::error::Error> {
+        let signed = Self::signed_region(header_bytes)?;
+        Ok(verify_rsa_pss_sha256(
+            public_key,
+            signed,
+            self.header_key_sig.as_bytes(),
+        ))
+    }
+
+    fn signed_region(header_bytes: &[u8]) -> Result<&[u8], crate::error::Error> {
+        header_bytes.get(0x200..NCA_HEADER_SIZE).ok_or_else(|| {
+            crate::error::Error::InvalidArgument(format!(
+                "NCA header is 0x{:X} bytes, expected 0x{:X}",
+                header_bytes.len(),
+                NCA_HEADER_SIZE
+            ))
+        })
+    }
+
     /// Get the key generation to use (accounting for old key_generation field)
     pub fn get_key_generation(&self) -> u8 {
         let key_gen_old = self.key_generation_old as u8;
@@ -302,7 +354,7 @@ impl NcaHeader {
 }
 #[binrw]
 #[brw(little)]
-#[derive(Debug, Default, Clone)]
+#[derive(Default, Clone)]
 // The key area from the NCA
 pub struct KeyArea {
     /// AES-XTS keys
@@ -313,11 +365,31 @@ pub struct KeyArea {
     pub _reserved: [u8; 0x10],
 }
 
+impl std::fmt::Debug for KeyArea {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyArea")
+            .field("aes_xts_key", &RedactedKey(&self.aes_xts_key))
+            .field("aes_ctr_key", &RedactedKey(&self.aes_ctr_key))
+            .field("_reserved", &RedactedKey(&self._reserved))
+            .finish()
+    }
+}
+
+impl zeroize::Zeroize for KeyArea {
+    fn zeroize(&mut self) {
+        self.aes_xts_key.zeroize();
+        self.aes_ctr_key.zeroize();
+        self._reserved.zeroize();
+    }
+}
+
 pub struct Nca<R: Read + Seek> {
     reader: R,
     pub header: NcaHeader,
     pub fs_headers: Vec<FsHeader>,
     key_management: NcaKeyManagement,
+    /// Decrypted NCA header, kept for signature verification
+    header_bytes: Vec<u8>,
 }
 
 impl<R: Read + Seek> Nca<R> {
@@ -413,6 +485,7 @@ impl<R: Read + Seek> Nca<R> {
             header,
             fs_headers,
             key_management,
+            header_bytes: decrypted[..NCA_HEADER_SIZE].to_vec(),
         })
     }
 
@@ -456,6 +529,45 @@ impl<R: Read + Seek> Nca<R> {
         self.key_management.has_valid_keys()
     }
 
+    /// The decrypted NCA header
+    pub(crate) fn header_bytes(&self) -> &[u8] {
+        &self.header_bytes
+    }
+
+    /// Verify the NCA header signature with the fixed keys of the keyset's environment
+    ///
+    /// See [`NcaHeader::verify_signature`].
+    pub fn verify_header_signature(&self, keyset: &Keyset) -> Result<bool, crate::error::Error> {
+        self.header
+            .verify_signature(&self.header_bytes, &keyset.fixed_keys())
+    }
+
+    /// Verify the NCA header key signature with the public key from the program's ACID
+    ///
+    /// See [`NcaHeader::verify_key_signature`].
+    pub fn verify_header_key_signature(
+        &self,
+        public_key: &[u8],
+    ) -> Result<bool, crate::error::Error> {
+        self.header
+            .verify_key_signature(&self.header_bytes, public_key)
+    }
+
+    /// List the keys needed to decrypt this NCA, and which of them are missing
+    ///
+    /// Unlike [`KeyRequirementsExt::key_requirements`], this only needs a shared reference.
+    pub fn header_key_requirements(
+        &self,
+        keyset: &Keyset,
+        title_keys: Option<&TitleKeys>,
+    ) -> NcaKeyRequirements {
+        let name = format!(
+            "{:016X} ({:?})",
+            self.header.program_id, self.header.content_type
+        );
+        NcaKeyRequirements::from_header(name, &self.header, keyset, title_keys)
+    }
+
     /// Gets the AES-CTR key for decryption
     #[inline]
     pub fn get_aes_ctr_decrypt_key(&self) -> Result<[u8; 0x10], crate::error::Error> {
@@ -522,7 +634,7 @@ impl<R: Read + Seek> Nca<R> {
             EncryptionType::AesCtr => {
                 tracing::trace!("Using AES-CTR decryption");
                 let decrypt_key = self.get_aes_ctr_decrypt_key()?.to_vec();
-                tracing::trace!(decrypt_key = %hex::encode(&decrypt_key), "Decryption key obtained");
+                tracing::trace!(decrypt_key = %RedactedKey(&decrypt_key), "Decryption key obtained");
 
                 let reader = std::io::BufReader::new(self.reader.by_ref());
                 let aes_reader =
@@ -601,6 +713,22 @@ impl<R: Read + Seek> Nca<R> {
     }
 }
 
+impl<R: Read + Seek> KeyRequirementsExt for Nca<R> {
+    fn key_requirements(
+        &mut self,
+        keyset: &Keyset,
+        title_keys: Option<&TitleKeys>,
+    ) -> Result<KeyRequirements, crate::error::Error> {
+        Ok(KeyRequirements {
+            ncas: vec![self.header_key_requirements(keyset, title_keys)],
+        })
+    }
+}
+
+// Shared with the tests of other modules, which need an NCA header to encrypt or sign
+#[cfg(test)]
+pub(crate) use tests::{HEADER_SIG, test_header};
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -766,7 +894,13 @@ mod tests {
         assert_eq!(std::mem::size_of_val(&entry), 16);
     }
 
-    fn test_header() -> NcaHeader {
+    /// Signature of [`test_header`] with signature key generation 1, over bytes 0x200..0x400,
+    /// made with the test key
+    pub(crate) const HEADER_SIG: [u8; 0x100] = hex_literal::hex!(
+        "3a6c4617ebb9e2ff96ee5fc02b5aea99ce8ae7d175bdd98b853b755d4cfe21eaa856c6f9ec03c45ad9ab47962b77cadb24a8edfa1b3791b42d594a68eeb6cee1d86775dc318ae4634b65fe798f02cc536ebcdbba5e4fbe98ee5665d7b73fa69aa7f9efd1399a6f4c79c9fbd4cd94cd59c02787a781e0871865806cb13b61735bc9439601d936c1e655492a731a8541cfc04946d80c1e3978710ed0279707c6dd61a56deadfe5556b78f85340a88cce0dd9ec5eb774d0ed77acdee0cf22b0cd3d3bdf8642dc96e1a91c9dd5d2807c0bd865e30c19f1b3248858bf129b76349ef78e2bf9eda13ed21c7a348232576b6243ee973cbc68af47eb152104a10a4efd05"
+    );
+
+    pub(crate) fn test_header() -> NcaHeader {
         NcaHeader {
             header_sig: RSASignature::default(),
             header_key_sig: RSASignature::default(),
@@ -811,7 +945,7 @@ mod tests {
         let header = test_header();
 
         let keyset = Keyset {
-            header_key_cache: Some([0; 0x20]),
+            header_key_cache: Some([0; 0x20].into()),
             ..Default::default()
         };
 
@@ -838,4 +972,44 @@ mod tests {
 
         assert_eq!(header_bytes, decrypted_header);
     }
+
+    #[test]
+    fn test_header_signature() {
+        use crate::formats::keyset::{KeyEnvironment, TEST_RSA_MODULUS};
+
+        let mut header = test_header();
+        header.signature_key_generation = 1;
+        let mut header_bytes = header.to_bytes();
+        header_bytes.resize(NCA_HEADER_SIZE, 0);
+
+        for (chunk, src) in header
+            .header_sig
+            .signature
+            .iter_mut()
+            .zip(HEADER_SIG.chunks(0x20))
+        {
+            chunk.copy_from_slice(src);
+        }
+
+        // No modulus for signature key generation 1
+        assert!(
+            header
+                .verify_signature(&header_bytes, &FixedKeys::default())
+                .is_err()
+        );
+
+        let mut keyset = Keyset {
+            environment: KeyEnvironment::Development,
+            ..Default::default()
+        };
+        keyset.raw_keys.insert(
+            "nca_hdr_fixed_key_modulus_01".to_string(),
+            TEST_RSA_MODULUS.to_vec().into(),
+        );
+        let fixed_keys = keyset.fixed_keys();
+        assert!(header.verify_signature(&header_bytes, &fixed_keys).unwrap());
+
+        header_bytes[0x210] ^= 1;
+        assert!(!header.verify_signature(&header_bytes, &fixed_keys).unwrap());
+    }
 }
@@ -0,0 +1,305 @@
+//! Key requirement reports
+//!
+//! When an NCA can't be decrypted, [`Nca::has_valid_keys`](super::Nca::has_valid_keys) only
+//! says *that* something is missing. The types in this module say *what*: which header key,
+//! key area key, title KEK and title keys a piece of content needs, and which of those the
+//! supplied [`Keyset`] and [`TitleKeys`] lack.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{Read, Seek};
+
+use super::{KeyAreaEncryptionKeyIndex, NCA_HEADER_SIZE, NcaHeader, decrypt_with_header_key};
+use crate::error::Error;
+use crate::formats::{Keyset, TitleKeys};
+
+/// A single key needed to decrypt some content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RequiredKey {
+    /// The NCA header key (`header_key`)
+    HeaderKey,
+    /// A key area key (`key_area_key_<type>_<generation>`)
+    KeyAreaKey {
+        key_type: KeyAreaEncryptionKeyIndex,
+        generation: u8,
+    },
+    /// A title KEK (`titlekek_<generation>`)
+    TitleKek { generation: u8 },
+    /// The title key for a rights ID, from `title.keys` or a ticket
+    TitleKey { rights_id: [u8; 0x10] },
+}
+
+impl RequiredKey {
+    /// Whether the key is available in the given keyset and title keys
+    pub fn is_available(&self, keyset: &Keyset, title_keys: Option<&TitleKeys>) -> bool {
+        match self {
+            RequiredKey::HeaderKey => keyset.header_key().is_some(),
+            RequiredKey::KeyAreaKey {
+                key_type,
+                generation,
+            } => keyset
+                .get_key_area_key(*key_type as u8, *generation)
+                .is_some(),
+            RequiredKey::TitleKek { generation } => {
+                keyset.get_title_kek(*generation as usize).is_some()
+            }
+            RequiredKey::TitleKey { rights_id } => title_keys
+                .and_then(|keys| keys.get_title_key(&hex::encode(rights_id)))
+                .is_some(),
+        }
+    }
+}
+
+impl fmt::Display for RequiredKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequiredKey::HeaderKey => write!(f, "header_key"),
+            RequiredKey::KeyAreaKey {
+                key_type,
+                generation,
+            } => {
+                let key_type = match key_type {
+                    KeyAreaEncryptionKeyIndex::Application => "application",
+                    KeyAreaEncryptionKeyIndex::Ocean => "ocean",
+                    KeyAreaEncryptionKeyIndex::System => "system",
+                };
+                write!(f, "key_area_key_{}_{:02x}", key_type, generation)
+            }
+            RequiredKey::TitleKek { generation } => write!(f, "titlekek_{:02x}", generation),
+            RequiredKey::TitleKey { rights_id } => {
+                write!(f, "title key for rights ID {}", hex::encode(rights_id))
+            }
+        }
+    }
+}
+
+/// Keys required by a single NCA
+#[derive(Debug, Clone)]
+pub struct NcaKeyRequirements {
+    /// Name of the NCA, usually its path within the containing archive
+    pub name: String,
+    /// Every key needed to fully decrypt the NCA
+    ///
+    /// If the header key is missing, the header can't be read and only
+    /// [`RequiredKey::HeaderKey`] is listed.
+    pub required: Vec<RequiredKey>,
+    /// Keys from `required` that are not available
+    pub missing: Vec<RequiredKey>,
+}
+
+impl NcaKeyRequirements {
+    /// Build the requirements for an already-decrypted NCA header
+    pub fn from_header(
+        name: impl Into<String>,
+        header: &NcaHeader,
+        keyset: &Keyset,
+        title_keys: Option<&TitleKeys>,
+    ) -> Self {
+        let generation = header.get_key_generation();
+
+        let mut required = vec![RequiredKey::HeaderKey];
+        if header.rights_id.iter().all(|&b| b == 0) {
+            required.push(RequiredKey::KeyAreaKey {
+                key_type: header.key_area_appkey_index,
+                generation,
+            });
+        } else {
+            required.push(RequiredKey::TitleKek { generation });
+            required.push(RequiredKey::TitleKey {
+                rights_id: header.rights_id,
+            });
+        }
+
+        Self::new(name, required, keyset, title_keys)
+    }
+
+    /// Build the requirements for an NCA from its raw (encrypted) data
+    ///
+    /// Only the first 0x400 bytes are read. If the header can't be decrypted, because the
+    /// header key is missing or wrong, the header key is reported as missing.
+    pub fn from_reader<R: Read + Seek>(
+        name: impl Into<String>,
+        mut reader: R,
+        keyset: &Keyset,
+        title_keys: Option<&TitleKeys>,
+    ) -> Result<Self, Error> {
+        let mut encrypted = vec![0u8; NCA_HEADER_SIZE];
+        reader.read_exact(&mut encrypted)?;
+
+        Ok(Self::from_header_bytes(
+            name, &encrypted, keyset, title_keys,
+        ))
+    }
+
+    /// Build the requirements from the first 0x400 bytes of an encrypted NCA
+    pub(crate) fn from_header_bytes(
+        name: impl Into<String>,
+        encrypted: &[u8],
+        keyset: &Keyset,
+        title_keys: Option<&TitleKeys>,
+    ) -> Self {
+        let header = decrypt_with_header_key(encrypted, keyset, 0x200, 0)
+            .ok()
+            .and_then(|decrypted| {
+                let bytes: &[u8; 0x340] = decrypted[..0x340].try_into().ok()?;
+                NcaHeader::from_bytes(bytes).ok()
+            });
+
+        match header {
+            Some(header) => Self::from_header(name, &header, keyset, title_keys),
+            None => Self {
+                name: name.into(),
+                required: vec![RequiredKey::HeaderKey],
+                missing: vec![RequiredKey::HeaderKey],
+            },
+        }
+    }
+
+    fn new(
+        name: impl Into<String>,
+        required: Vec<RequiredKey>,
+        keyset: &Keyset,
+        title_keys: Option<&TitleKeys>,
+    ) -> Self {
+        let missing = required
+            .iter()
+            .filter(|key| !key.is_available(keyset, title_keys))
+            .copied()
+            .collect();
+
+        Self {
+            name: name.into(),
+            required,
+            missing,
+        }
+    }
+
+    /// Whether every required key is available
+    pub fn is_satisfied(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+/// Keys required by an NCA, or by every NCA inside an NSP or XCI
+#[derive(Debug, Clone, Default)]
+pub struct KeyRequirements {
+    /// Requirements of each NCA
+    pub ncas: Vec<NcaKeyRequirements>,
+}
+
+impl KeyRequirements {
+    /// Every distinct key required by any NCA
+    pub fn required(&self) -> BTreeSet<RequiredKey> {
+        self.ncas
+            .iter()
+            .flat_map(|nca| nca.required.iter().copied())
+            .collect()
+    }
+
+    /// Every distinct key that is missing for any NCA
+    pub fn missing(&self) -> BTreeSet<RequiredKey> {
+        self.ncas
+            .iter()
+            .flat_map(|nca| nca.missing.iter().copied())
+            .collect()
+    }
+
+    /// Whether every NCA can be decrypted with the available keys
+    pub fn is_satisfied(&self) -> bool {
+        self.ncas.iter().all(|nca| nca.is_satisfied())
+    }
+}
+
+impl fmt::Display for KeyRequirements {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_satisfied() {
+            return write!(f, "All required keys are available");
+        }
+
+        write!(f, "Missing keys:")?;
+        for key in self.missing() {
+            let needed_by: Vec<_> = self
+                .ncas
+                .iter()
+                .filter(|nca| nca.missing.contains(&key))
+                .map(|nca| nca.name.as_str())
+                .collect();
+            write!(f, "\n  {} (needed by {})", key, needed_by.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::nca::test_header;
+
+    #[test]
+    fn test_key_area_requirements() {
+        let header = test_header();
+        let mut keyset = Keyset {
+            header_key_cache: Some([0; 0x20].into()),
+            ..Default::default()
+        };
+
+        let reqs = NcaKeyRequirements::from_header("test.nca", &header, &keyset, None);
+
+        // test_header uses key_generation_old Gen3_0_0, which is master key 01
+        let key_area_key = RequiredKey::KeyAreaKey {
+            key_type: KeyAreaEncryptionKeyIndex::Application,
+            generation: 1,
+        };
+        assert_eq!(reqs.required, vec![RequiredKey::HeaderKey, key_area_key]);
+        assert_eq!(reqs.missing, vec![key_area_key]);
+        assert_eq!(key_area_key.to_string(), "key_area_key_application_01");
+
+        keyset.raw_keys.insert(
+            "key_area_key_application_01".to_string(),
+            vec![0; 0x10].into(),
+        );
+        let reqs = NcaKeyRequirements::from_header("test.nca", &header, &keyset, None);
+        assert!(reqs.is_satisfied());
+    }
+
+    #[test]
+    fn test_title_key_requirements() {
+        let mut header = test_header();
+        header.rights_id = [0xAB; 0x10];
+        let keyset = Keyset::default();
+
+        let reqs = KeyRequirements {
+            ncas: vec![NcaKeyRequirements::from_header(
+                "test.nca", &header, &keyset, None,
+            )],
+        };
+
+        assert!(!reqs.is_satisfied());
+        assert!(
+            reqs.missing()
+                .contains(&RequiredKey::TitleKek { generation: 1 })
+        );
+        assert!(reqs.missing().contains(&RequiredKey::TitleKey {
+            rights_id: [0xAB; 0x10]
+        }));
+
+        let message = reqs.to_string();
+        assert!(message.contains("titlekek_01 (needed by test.nca)"));
+        assert!(message.contains("title key for rights ID abababab"));
+    }
+
+    #[test]
+    fn test_missing_header_key() {
+        let keyset = Keyset::default();
+        let reqs = NcaKeyRequirements::from_reader(
+            "test.nca",
+            std::io::Cursor::new(vec![0u8; 0x400]),
+            &keyset,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(reqs.missing, vec![RequiredKey::HeaderKey]);
+    }
+}
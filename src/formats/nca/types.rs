@@ -8,6 +8,13 @@ pub struct RSASignature {
     pub signature: [[u8; 0x20]; 8],
 }
 
+impl RSASignature {
+    /// The signature as a single 0x100 byte slice
+    pub fn as_bytes(&self) -> &[u8] {
+        self.signature.as_flattened()
+    }
+}
+
 #[binrw]
 #[brw(little, repr = u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -93,7 +100,7 @@ pub type KeyGenerationOld = KeyGeneration;
 
 #[binrw]
 #[brw(little, repr = u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 /// The encryption key index used for the key area in the NCA header.
 pub enum KeyAreaEncryptionKeyIndex {
     /// Application key area encryption key.
@@ -0,0 +1,1040 @@
+//! Program permissions (NPDM)
+//!
+//! The `main.npdm` in an ExeFS sets up the program's process and lists what it may do.
+//! It has three parts:
+//!
+//! - META, with the process settings such as the main thread priority and stack size
+//! - ACID, the permissions Nintendo signed off on, bounding what the program can ask for
+//! - ACI0, the permissions the program asks for
+//!
+//! ACID and ACI0 each hold FS access control, service access control and kernel
+//! capabilities, decoded here into [`FsAccessControl`], [`ServiceAccess`] and
+//! [`KernelCapability`].
+//!
+//! The ACID is signed with a fixed key, and carries the public key that signs the second NCA
+//! header signature of the Program NCA, checked together by [`Nca::verify_program`].
+
+use binrw::prelude::*;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+use crate::error::Error;
+use crate::formats::Keyset;
+use crate::formats::keyset::{FixedKeys, KeyEnvironment, SignatureStatus, verify_rsa_pss_sha256};
+use crate::formats::nca::{ContentType, Nca, NcaHeader};
+use crate::util::fixed_string;
+
+/// Address space of the process, from the META flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessAddressSpace {
+    AddressSpace32Bit = 0,
+    AddressSpace64BitOld = 1,
+    AddressSpace32BitNoReserved = 2,
+    AddressSpace64Bit = 3,
+}
+
+/// META header, at the start of the NPDM
+#[binrw]
+#[brw(little, magic = b"META")]
+#[derive(Debug, Clone)]
+pub struct MetaHeader {
+    /// Key generation of the ACID signature key
+    pub signature_key_generation: u32,
+    pub _reserved: u32,
+    pub flags: u8,
+    pub _reserved2: u8,
+    pub main_thread_priority: u8,
+    pub main_thread_core_number: u8,
+    pub _reserved3: u32,
+    pub system_resource_size: u32,
+    pub version: u32,
+    pub main_thread_stack_size: u32,
+    pub name: [u8; 0x10],
+    pub product_code: [u8; 0x10],
+    pub _reserved4: [u8; 0x30],
+    pub aci0_offset: u32,
+    pub aci0_size: u32,
+    pub acid_offset: u32,
+    pub acid_size: u32,
+}
+
+impl MetaHeader {
+    /// Title name, such as `Application`
+    pub fn name_string(&self) -> String {
+        fixed_string(&self.name)
+    }
+
+    pub fn product_code_string(&self) -> String {
+        fixed_string(&self.product_code)
+    }
+
+    pub fn is_64bit_instruction(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+
+    pub fn address_space(&self) -> ProcessAddressSpace {
+        match (self.flags >> 1) & 0x07 {
+            0 => ProcessAddressSpace::AddressSpace32Bit,
+            1 => ProcessAddressSpace::AddressSpace64BitOld,
+            2 => ProcessAddressSpace::AddressSpace32BitNoReserved,
+            _ => ProcessAddressSpace::AddressSpace64Bit,
+        }
+    }
+
+    pub fn optimize_memory_allocation(&self) -> bool {
+        self.flags & 0x10 != 0
+    }
+
+    pub fn disable_device_address_space_merge(&self) -> bool {
+        self.flags & 0x20 != 0
+    }
+}
+
+/// ACID header, after its signature and public key
+#[binrw]
+#[brw(little, magic = b"ACID")]
+#[derive(Debug, Clone)]
+pub struct AcidHeader {
+    /// Size of the signed data, which starts at the public key
+    pub size: u32,
+    pub version: u8,
+    pub _unknown: u8,
+    pub _reserved: [u8; 2],
+    pub flags: u32,
+    pub program_id_min: u64,
+    pub program_id_max: u64,
+    /// Offsets are relative to the start of the ACID
+    pub fs_access_control_offset: u32,
+    pub fs_access_control_size: u32,
+    pub service_access_control_offset: u32,
+    pub service_access_control_size: u32,
+    pub kernel_capability_offset: u32,
+    pub kernel_capability_size: u32,
+    pub _reserved2: [u8; 8],
+}
+
+/// ACI0 header
+#[binrw]
+#[brw(little, magic = b"ACI0")]
+#[derive(Debug, Clone)]
+pub struct Aci0Header {
+    pub _reserved: [u8; 0xC],
+    pub program_id: u64,
+    pub _reserved2: [u8; 8],
+    /// Offsets are relative to the start of the ACI0
+    pub fs_access_header_offset: u32,
+    pub fs_access_header_size: u32,
+    pub service_access_control_offset: u32,
+    pub service_access_control_size: u32,
+    pub kernel_capability_offset: u32,
+    pub kernel_capability_size: u32,
+    pub _reserved3: [u8; 8],
+}
+
+/// FS access control in the ACID
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone)]
+struct FsAccessControlData {
+    version: u8,
+    content_owner_id_count: u8,
+    save_data_owner_id_count: u8,
+    _padding: u8,
+    permissions: u64,
+    content_owner_id_min: u64,
+    content_owner_id_max: u64,
+    save_data_owner_id_min: u64,
+    save_data_owner_id_max: u64,
+    #[br(count = content_owner_id_count)]
+    content_owner_ids: Vec<u64>,
+    #[br(count = save_data_owner_id_count)]
+    save_data_owner_ids: Vec<u64>,
+}
+
+/// FS access header in the ACI0
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone)]
+struct FsAccessHeaderData {
+    version: u8,
+    _padding: [u8; 3],
+    permissions: u64,
+    content_owner_info_offset: u32,
+    content_owner_info_size: u32,
+    save_data_owner_info_offset: u32,
+    save_data_owner_info_size: u32,
+}
+
+/// How a program may access a save data owner's saves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveDataAccessibility {
+    Read,
+    Write,
+    ReadWrite,
+    Unknown(u8),
+}
+
+impl From<u8> for SaveDataAccessibility {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => SaveDataAccessibility::Read,
+            2 => SaveDataAccessibility::Write,
+            3 => SaveDataAccessibility::ReadWrite,
+            _ => SaveDataAccessibility::Unknown(value),
+        }
+    }
+}
+
+/// Filesystem permissions, from either the ACID or the ACI0
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsAccessControl {
+    pub version: u8,
+    /// Bit field of the FS permissions
+    pub permissions: u64,
+    /// Range of content owner IDs, only in the ACID
+    pub content_owner_id_range: Option<(u64, u64)>,
+    /// Range of save data owner IDs, only in the ACID
+    pub save_data_owner_id_range: Option<(u64, u64)>,
+    pub content_owner_ids: Vec<u64>,
+    /// Save data owner IDs, with their accessibility in the ACI0
+    pub save_data_owners: Vec<(u64, Option<SaveDataAccessibility>)>,
+}
+
+impl FsAccessControl {
+    /// Parse the FS access control of an ACID
+    fn from_acid(data: &[u8]) -> Result<Self, Error> {
+        let control: FsAccessControlData = Cursor::new(data).read_le()?;
+        Ok(Self {
+            version: control.version,
+            permissions: control.permissions,
+            content_owner_id_range: Some((
+                control.content_owner_id_min,
+                control.content_owner_id_max,
+            )),
+            save_data_owner_id_range: Some((
+                control.save_data_owner_id_min,
+                control.save_data_owner_id_max,
+            )),
+            content_owner_ids: control.content_owner_ids,
+            save_data_owners: control
+                .save_data_owner_ids
+                .into_iter()
+                .map(|id| (id, None))
+                .collect(),
+        })
+    }
+
+    /// Parse the FS access header of an ACI0
+    fn from_aci0(data: &[u8]) -> Result<Self, Error> {
+        let mut reader = Cursor::new(data);
+        let header: FsAccessHeaderData = reader.read_le()?;
+
+        let mut content_owner_ids = Vec::new();
+        if header.content_owner_info_size != 0 {
+            reader.seek(SeekFrom::Start(header.content_owner_info_offset as u64))?;
+            let count: u32 = reader.read_le()?;
+            for _ in 0..count {
+                content_owner_ids.push(reader.read_le()?);
+            }
+        }
+
+        let mut save_data_owners = Vec::new();
+        if header.save_data_owner_info_size != 0 {
+            reader.seek(SeekFrom::Start(header.save_data_owner_info_offset as u64))?;
+            let count: u32 = reader.read_le()?;
+            // Each owner takes an accessibility byte and an 8 byte ID
+            let min_size = 4 + count as u64 * 9;
+            if min_size > header.save_data_owner_info_size as u64 || min_size > data.len() as u64 {
+                return Err(Error::InvalidData(format!(
+                    "{} save data owners don't fit in the 0x{:X} byte owner info",
+                    count, header.save_data_owner_info_size
+                )));
+            }
+            let mut accessibility = vec![0u8; count as usize];
+            reader.read_exact(&mut accessibility)?;
+            // The IDs are aligned to 4 bytes after the accessibility list
+            let ids_offset =
+                (header.save_data_owner_info_offset as u64 + 4 + count as u64).next_multiple_of(4);
+            reader.seek(SeekFrom::Start(ids_offset))?;
+            for accessibility in accessibility {
+                let id: u64 = reader.read_le()?;
+                save_data_owners.push((id, Some(accessibility.into())));
+            }
+        }
+
+        Ok(Self {
+            version: header.version,
+            permissions: header.permissions,
+            content_owner_id_range: None,
+            save_data_owner_id_range: None,
+            content_owner_ids,
+            save_data_owners,
+        })
+    }
+}
+
+/// A service the program may connect to or host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceAccess {
+    /// Service name, where a trailing `*` matches any suffix
+    pub name: String,
+    /// Whether the program may register the service rather than connect to it
+    pub is_server: bool,
+}
+
+impl ServiceAccess {
+    /// Parse a service access control list
+    pub fn parse_list(data: &[u8]) -> Result<Vec<Self>, Error> {
+        let mut services = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let control = data[offset];
+            if control == 0 {
+                break;
+            }
+            let length = (control & 0x07) as usize + 1;
+            let name = data.get(offset + 1..offset + 1 + length).ok_or_else(|| {
+                Error::InvalidData("Service access control entry is truncated".to_string())
+            })?;
+            services.push(Self {
+                name: String::from_utf8_lossy(name).into_owned(),
+                is_server: control & 0x80 != 0,
+            });
+            offset += 1 + length;
+        }
+        Ok(services)
+    }
+}
+
+/// A kernel capability descriptor
+///
+/// The type of a descriptor is given by the number of trailing one bits in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KernelCapability {
+    /// Allowed thread priorities and CPU cores
+    ThreadInfo {
+        lowest_priority: u8,
+        highest_priority: u8,
+        min_core: u8,
+        max_core: u8,
+    },
+    /// Allowed system calls, by ID
+    EnableSystemCalls(Vec<u32>),
+    /// A range of physical memory to map, made of two descriptors
+    MemoryMap {
+        address: u64,
+        size: u64,
+        read_only: bool,
+        /// Mapped as static memory rather than IO memory
+        is_static: bool,
+    },
+    /// A page of IO memory to map
+    IoMemoryMap {
+        address: u64,
+    },
+    /// Predefined memory regions to map, with whether each is read-only
+    MemoryRegionMap(Vec<(u8, bool)>),
+    /// Allowed interrupts
+    EnableInterrupts(Vec<u16>),
+    ProgramType(u8),
+    /// Minimum kernel version
+    KernelVersion {
+        major: u16,
+        minor: u8,
+    },
+    HandleTableSize(u16),
+    DebugFlags {
+        allow_debug: bool,
+        force_debug_prod: bool,
+        force_debug: bool,
+    },
+    /// A descriptor of an unknown type
+    Unknown(u32),
+}
+
+impl KernelCapability {
+    /// Parse a list of kernel capability descriptors
+    pub fn parse_list(data: &[u8]) -> Result<Vec<Self>, Error> {
+        let mut descriptors = data
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()));
+        let bits = |value: u32, shift: u32, width: u32| (value >> shift) & ((1 << width) - 1);
+
+        let mut capabilities = Vec::new();
+        while let Some(descriptor) = descriptors.next() {
+            let capability = match descriptor.trailing_ones() {
+                3 => KernelCapability::ThreadInfo {
+                    lowest_priority: bits(descriptor, 4, 6) as u8,
+                    highest_priority: bits(descriptor, 10, 6) as u8,
+                    min_core: bits(descriptor, 16, 8) as u8,
+                    max_core: bits(descriptor, 24, 8) as u8,
+                },
+                4 => {
+                    let mask = bits(descriptor, 5, 24);
+                    let base = bits(descriptor, 29, 3) * 24;
+                    KernelCapability::EnableSystemCalls(
+                        (0..24)
+                            .filter(|bit| mask & (1 << bit) != 0)
+                            .map(|bit| base + bit)
+                            .collect(),
+                    )
+                }
+                6 => {
+                    let size_descriptor = descriptors.next().ok_or_else(|| {
+                        Error::InvalidData("Memory map is missing its size".to_string())
+                    })?;
+                    KernelCapability::MemoryMap {
+                        address: (bits(descriptor, 7, 24) as u64) << 12,
+                        size: (bits(size_descriptor, 7, 20) as u64) << 12,
+                        read_only: descriptor >> 31 != 0,
+                        is_static: size_descriptor >> 31 != 0,
+                    }
+                }
+                7 => KernelCapability::IoMemoryMap {
+                    address: (bits(descriptor, 8, 24) as u64) << 12,
+                },
+                10 => KernelCapability::MemoryRegionMap(
+                    [11, 18, 25]
+                        .into_iter()
+                        .map(|shift| {
+                            (
+                                bits(descriptor, shift, 6) as u8,
+                                bits(descriptor, shift + 6, 1) != 0,
+                            )
+                        })
+                        .filter(|(region, _)| *region != 0)
+                        .collect(),
+                ),
+                11 => KernelCapability::EnableInterrupts(
+                    [bits(descriptor, 12, 10), bits(descriptor, 22, 10)]
+                        .into_iter()
+                        .filter(|&interrupt| interrupt != 0x3FF)
+                        .map(|interrupt| interrupt as u16)
+                        .collect(),
+                ),
+                13 => KernelCapability::ProgramType(bits(descriptor, 14, 3) as u8),
+                14 => KernelCapability::KernelVersion {
+                    major: bits(descriptor, 19, 13) as u16,
+                    minor: bits(descriptor, 15, 4) as u8,
+                },
+                15 => KernelCapability::HandleTableSize(bits(descriptor, 16, 10) as u16),
+                16 => KernelCapability::DebugFlags {
+                    allow_debug: bits(descriptor, 17, 1) != 0,
+                    force_debug_prod: bits(descriptor, 18, 1) != 0,
+                    force_debug: bits(descriptor, 19, 1) != 0,
+                },
+                // Unused descriptors are all ones
+                32 => continue,
+                _ => KernelCapability::Unknown(descriptor),
+            };
+            capabilities.push(capability);
+        }
+        Ok(capabilities)
+    }
+}
+
+/// The permissions Nintendo signed off on
+#[derive(Debug, Clone)]
+pub struct Acid {
+    /// RSA-2048-PSS signature over the signed data
+    pub signature: [u8; 0x100],
+    /// Public key for the second NCA header signature
+    pub public_key: [u8; 0x100],
+    pub header: AcidHeader,
+    pub fs_access_control: FsAccessControl,
+    pub service_access_control: Vec<ServiceAccess>,
+    pub kernel_capabilities: Vec<KernelCapability>,
+    /// The whole ACID, kept for signature verification
+    data: Vec<u8>,
+}
+
+impl Acid {
+    fn from_bytes(data: Vec<u8>) -> Result<Self, Error> {
+        let mut reader = Cursor::new(&data);
+        let mut signature = [0; 0x100];
+        let mut public_key = [0; 0x100];
+        reader.read_exact(&mut signature)?;
+        reader.read_exact(&mut public_key)?;
+        let header: AcidHeader = reader.read_le()?;
+
+        let fs_access_control = FsAccessControl::from_acid(region(
+            &data,
+            header.fs_access_control_offset,
+            header.fs_access_control_size,
+        )?)?;
+        let service_access_control = ServiceAccess::parse_list(region(
+            &data,
+            header.service_access_control_offset,
+            header.service_access_control_size,
+        )?)?;
+        let kernel_capabilities = KernelCapability::parse_list(region(
+            &data,
+            header.kernel_capability_offset,
+            header.kernel_capability_size,
+        )?)?;
+
+        Ok(Self {
+            signature,
+            public_key,
+            header,
+            fs_access_control,
+            service_access_control,
+            kernel_capabilities,
+            data,
+        })
+    }
+
+    /// The data covered by the signature, from the public key on
+    pub fn signed_data(&self) -> &[u8] {
+        let end = (0x100 + self.header.size as usize).min(self.data.len());
+        &self.data[0x100..end]
+    }
+
+    /// Verify the ACID signature against the fixed ACID key of a signature key generation
+    ///
+    /// Needs `acid_fixed_key_modulus_XX` for the generation in the key file, otherwise the
+    /// signature is reported as unchecked.
+    pub fn verify_signature(&self, fixed_keys: &FixedKeys, generation: u8) -> SignatureStatus {
+        SignatureStatus::check(fixed_keys.acid_modulus(generation), |modulus| {
+            verify_rsa_pss_sha256(modulus, self.signed_data(), &self.signature)
+        })
+    }
+
+    /// Whether the ACID is signed for retail consoles
+    pub fn is_production(&self) -> bool {
+        self.header.flags & 0x01 != 0
+    }
+
+    pub fn is_unqualified_approval(&self) -> bool {
+        self.header.flags & 0x02 != 0
+    }
+
+    /// Memory region the process is created in
+    pub fn memory_region(&self) -> u8 {
+        ((self.header.flags >> 2) & 0x0F) as u8
+    }
+
+    /// Whether a program ID is in the range this ACID allows
+    pub fn allows_program_id(&self, program_id: u64) -> bool {
+        (self.header.program_id_min..=self.header.program_id_max).contains(&program_id)
+    }
+}
+
+/// The permissions the program asks for
+#[derive(Debug, Clone)]
+pub struct Aci0 {
+    pub header: Aci0Header,
+    pub fs_access_control: FsAccessControl,
+    pub service_access_control: Vec<ServiceAccess>,
+    pub kernel_capabilities: Vec<KernelCapability>,
+}
+
+impl Aci0 {
+    fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        let header: Aci0Header = Cursor::new(data).read_le()?;
+        Ok(Self {
+            fs_access_control: FsAccessControl::from_aci0(region(
+                data,
+                header.fs_access_header_offset,
+                header.fs_access_header_size,
+            )?)?,
+            service_access_control: ServiceAccess::parse_list(region(
+                data,
+                header.service_access_control_offset,
+                header.service_access_control_size,
+            )?)?,
+            kernel_capabilities: KernelCapability::parse_list(region(
+                data,
+                header.kernel_capability_offset,
+                header.kernel_capability_size,
+            )?)?,
+            header,
+        })
+    }
+}
+
+/// A parsed `main.npdm`
+#[derive(Debug, Clone)]
+pub struct Npdm {
+    pub meta: MetaHeader,
+    pub acid: Acid,
+    pub aci0: Aci0,
+}
+
+impl Npdm {
+    /// Parse an NPDM
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<Self, Error> {
+        let mut data = Vec::new();
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_to_end(&mut data)?;
+        Self::from_bytes(&data)
+    }
+
+    /// Parse an NPDM from its bytes
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        let meta: MetaHeader = Cursor::new(data).read_le()?;
+        let acid = Acid::from_bytes(region(data, meta.acid_offset, meta.acid_size)?.to_vec())?;
+        let aci0 = Aci0::from_bytes(region(data, meta.aci0_offset, meta.aci0_size)?)?;
+        Ok(Self { meta, acid, aci0 })
+    }
+
+    /// Verify the ACID signature, using the signature key generation from META
+    pub fn verify_acid_signature(&self, fixed_keys: &FixedKeys) -> SignatureStatus {
+        let generation = u8::try_from(self.meta.signature_key_generation).unwrap_or(u8::MAX);
+        self.acid.verify_signature(fixed_keys, generation)
+    }
+}
+
+/// Result of [`Nca::verify_program`]
+#[derive(Debug, Clone)]
+pub struct ProgramNcaVerification {
+    /// The environment whose keys were used
+    pub environment: KeyEnvironment,
+    /// `header_sig`, made with the fixed NCA header key
+    pub header_signature: SignatureStatus,
+    /// ACID signature, made with the fixed ACID key
+    pub acid_signature: SignatureStatus,
+    /// `header_key_sig`, made with the key in the ACID
+    pub header_key_signature: SignatureStatus,
+    /// Whether the NCA's program ID is in the range the ACID allows
+    pub program_id_allowed: bool,
+}
+
+impl ProgramNcaVerification {
+    /// Check a Program NCA header against its NPDM
+    pub fn new(
+        header: &NcaHeader,
+        header_bytes: &[u8],
+        npdm: &Npdm,
+        fixed_keys: &FixedKeys,
+    ) -> Self {
+        let status = |result: Result<bool, Error>| match result {
+            Ok(true) => SignatureStatus::Valid,
+            Ok(false) => SignatureStatus::Invalid,
+            Err(e) => SignatureStatus::Unchecked(e.to_string()),
+        };
+
+        Self {
+            environment: fixed_keys.environment,
+            header_signature: status(header.verify_signature(header_bytes, fixed_keys)),
+            acid_signature: npdm.verify_acid_signature(fixed_keys),
+            header_key_signature: status(
+                header.verify_key_signature(header_bytes, &npdm.acid.public_key),
+            ),
+            program_id_allowed: npdm.acid.allows_program_id(header.program_id),
+        }
+    }
+
+    /// Whether all three signatures are valid and the ACID allows the program ID
+    ///
+    /// The header key signature alone proves nothing, as anyone can put their own key in an
+    /// unsigned ACID.
+    pub fn is_ok(&self) -> bool {
+        self.header_signature.is_valid()
+            && self.acid_signature.is_valid()
+            && self.header_key_signature.is_valid()
+            && self.program_id_allowed
+    }
+}
+
+impl std::fmt::Display for ProgramNcaVerification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Keys: {}", self.environment)?;
+        writeln!(f, "Header signature: {}", self.header_signature)?;
+        writeln!(f, "ACID signature: {}", self.acid_signature)?;
+        writeln!(f, "Header key signature: {}", self.header_key_signature)?;
+        write!(
+            f,
+            "Program ID: {}",
+            if self.program_id_allowed {
+                "allowed by ACID"
+            } else {
+                "not allowed by ACID"
+            }
+        )
+    }
+}
+
+impl<R: Read + Seek> Nca<R> {
+    /// Read the `main.npdm` from the ExeFS of a Program NCA
+    pub fn read_npdm(&mut self) -> Result<Npdm, Error> {
+        if self.header.content_type != ContentType::Program {
+            return Err(Error::InvalidOperation(format!(
+                "Expected a Program NCA, got {:?}",
+                self.header.content_type
+            )));
+        }
+        let mut exefs = self.open_pfs0_filesystem(0)?;
+        let file = exefs
+            .get_file("main.npdm")
+            .ok_or_else(|| Error::NotFound("main.npdm".to_string()))?;
+        Npdm::from_bytes(&exefs.read_to_vec(&file)?)
+    }
+
+    /// Check that a Program NCA is signed by Nintendo, end to end
+    ///
+    /// Verifies `header_sig` with the fixed NCA header key, the ACID in `main.npdm` with the
+    /// fixed ACID key, and `header_key_sig` with the public key in the ACID. The fixed moduli
+    /// are not bundled with the crate, so the key file must supply the
+    /// `nca_hdr_fixed_key_modulus_XX` and `acid_fixed_key_modulus_XX` entries for the
+    /// signature key generations in use; a missing one leaves that check unchecked.
+    pub fn verify_program(&mut self, keyset: &Keyset) -> Result<ProgramNcaVerification, Error> {
+        let npdm = self.read_npdm()?;
+        Ok(ProgramNcaVerification::new(
+            &self.header,
+            self.header_bytes(),
+            &npdm,
+            &keyset.fixed_keys(),
+        ))
+    }
+}
+
+fn region(data: &[u8], offset: u32, size: u32) -> Result<&[u8], Error> {
+    let start = offset as usize;
+    data.get(start..start + size as usize).ok_or_else(|| {
+        Error::InvalidData(format!(
+            "NPDM region 0x{:X}+0x{:X} is out of bounds",
+            offset, size
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::keyset::TEST_RSA_MODULUS;
+    use crate::formats::nca::{HEADER_SIG, test_header};
+
+    const PROGRAM_ID: u64 = 0x0100000000001000;
+
+    /// Signature of the ACID from [`build_npdm`] with [`TEST_RSA_MODULUS`] as its public key,
+    /// made with the test key
+    const ACID_SIG: [u8; 0x100] = hex_literal::hex!(
+        "55ecff8028d80742e78d0bfd0a9070c8af63b99f72f7d50e98f9ede8ddd3010471a3d34de3ad2b1c07101650abfa47bd704cea17a0591094134d4925d617c42d4c44f2ed1f08822292926b7393c46e7a77bcad2e6897a55709b013b9ed32989c795f996566002327160bd8977d7ef3050d4010efbbfd238c52ec39d143ebeee5640f7d8da7bbc09eb2b4f4c34e183fd12b6dc57b44d19c86b614d52de6652791416ec4cd93f4ae5f93e06ee2b950ff564eec57c01c182b7c14edce72a92ebc99b51b98372f52d678ca7f5cb1c808a9cc2a68fb1f76f3c314e7ea534349262cbb0b8876fc1e9d64d6a81e1b216e13cd68b0afe6d3f9201e18c7c093dadb9bbf3c"
+    );
+
+    /// Signature of the test NCA header for [`PROGRAM_ID`], made with the test key
+    const PROGRAM_HEADER_SIG: [u8; 0x100] = hex_literal::hex!(
+        "59b7bac30d7d5969bbad2ce36a89cd3b507475b1f8c80226f4f7b5e3a03cae7da4cc4a5c77b0d02d585b5859e31747dfaa1bcf98c51a4cf1a11b252d5444e70a8d25d4e5267c8fcd476193d036b5131b6411b1021c7d1f1746602c7ac202c5f48d4d6243ac4201a4555699f9c391c64e5abaf3304b2c20bfc021d362b8c79a83004a8a0734ade63ae3aa3f73a60ce0c37dcb2b2aa7ee819b9577869324b8925d142e529645c6766aa9b75fcca174437cbc3b1830be72bb19b5177f5eff4195af544922212c36161fdf8a6d191c2f9f770b0080cee9d2fc624153b01b8f62f230d5f2557360e7f09dd32db6df1e99463d1dccf5a7bc5dd2771f98fc67ba7ae483"
+    );
+
+    fn kernel_capabilities() -> Vec<u8> {
+        [
+            // Priorities 28-59 on cores 0-2
+            (2 << 24) | (59 << 10) | (28 << 4) | 0b0111,
+            // svcSetHeapSize (1) and svcExitProcess (7)
+            (0b1000_0010 << 5) | 0b1111,
+            // Memory map at 0x70000000, 0x2000 bytes, read-only IO
+            (1 << 31) | (0x70000 << 7) | 0b0011_1111,
+            (2 << 7) | 0b0011_1111,
+            // Interrupt 0x20 only
+            (0x3FF << 22) | (0x20 << 12) | 0b0111_1111_1111,
+            // Kernel 3.0
+            (3 << 19) | 0b0011_1111_1111_1111,
+            // Handle table of 512
+            (512 << 16) | 0b0111_1111_1111_1111,
+            // Debug allowed
+            (1 << 17) | 0xFFFF,
+            0xFFFF_FFFF,
+        ]
+        .iter()
+        .flat_map(|descriptor: &u32| descriptor.to_le_bytes())
+        .collect()
+    }
+
+    fn service_access_control() -> Vec<u8> {
+        let mut data = Vec::new();
+        for (name, server) in [("fsp-srv", false), ("lm", false), ("my:srv", true)] {
+            data.push((name.len() as u8 - 1) | if server { 0x80 } else { 0 });
+            data.extend_from_slice(name.as_bytes());
+        }
+        data
+    }
+
+    /// Build an NPDM with an unsigned ACID, returning it and the ACID offset
+    fn build_npdm() -> (Vec<u8>, usize) {
+        let kc = kernel_capabilities();
+        let sac = service_access_control();
+
+        // ACI0
+        let mut aci0 = Vec::from(*b"ACI0");
+        aci0.resize(0x10, 0);
+        aci0.extend_from_slice(&PROGRAM_ID.to_le_bytes());
+        aci0.resize(0x20, 0);
+        let mut fah = vec![1, 0, 0, 0];
+        fah.extend_from_slice(&0x8000_0000_0000_0001u64.to_le_bytes());
+        fah.extend_from_slice(&0x1Cu32.to_le_bytes());
+        fah.extend_from_slice(&0x0Cu32.to_le_bytes());
+        fah.extend_from_slice(&0x28u32.to_le_bytes());
+        fah.extend_from_slice(&0x18u32.to_le_bytes());
+        fah.extend_from_slice(&1u32.to_le_bytes());
+        fah.extend_from_slice(&PROGRAM_ID.to_le_bytes());
+        fah.extend_from_slice(&2u32.to_le_bytes());
+        fah.extend_from_slice(&[3, 1, 0, 0]);
+        fah.extend_from_slice(&PROGRAM_ID.to_le_bytes());
+        fah.extend_from_slice(&0x0100000000002000u64.to_le_bytes());
+        let fah_offset = 0x40u32;
+        let sac_offset = fah_offset + fah.len() as u32;
+        let kc_offset = (sac_offset + sac.len() as u32).next_multiple_of(0x10);
+        for (offset, size) in [
+            (fah_offset, fah.len()),
+            (sac_offset, sac.len()),
+            (kc_offset, kc.len()),
+        ] {
+            aci0.extend_from_slice(&offset.to_le_bytes());
+            aci0.extend_from_slice(&(size as u32).to_le_bytes());
+        }
+        aci0.resize(fah_offset as usize, 0);
+        aci0.extend_from_slice(&fah);
+        aci0.extend_from_slice(&sac);
+        aci0.resize(kc_offset as usize, 0);
+        aci0.extend_from_slice(&kc);
+
+        // ACID
+        let mut fac = vec![1, 1, 0, 0];
+        fac.extend_from_slice(&u64::MAX.to_le_bytes());
+        for value in [PROGRAM_ID, PROGRAM_ID, 0, u64::MAX, PROGRAM_ID] {
+            fac.extend_from_slice(&value.to_le_bytes());
+        }
+        let fac_offset = 0x240u32;
+        let sac_offset = fac_offset + fac.len() as u32;
+        let kc_offset = (sac_offset + sac.len() as u32).next_multiple_of(0x10);
+        let acid_size = kc_offset as usize + kc.len();
+
+        let mut acid = vec![0xAA; 0x100];
+        acid.extend_from_slice(&[0xBB; 0x100]);
+        acid.extend_from_slice(b"ACID");
+        acid.extend_from_slice(&((acid_size - 0x100) as u32).to_le_bytes());
+        acid.extend_from_slice(&[0; 4]);
+        acid.extend_from_slice(&(1u32 | (2 << 2)).to_le_bytes());
+        acid.extend_from_slice(&PROGRAM_ID.to_le_bytes());
+        acid.extend_from_slice(&(PROGRAM_ID + 0xFF).to_le_bytes());
+        for (offset, size) in [
+            (fac_offset, fac.len()),
+            (sac_offset, sac.len()),
+            (kc_offset, kc.len()),
+        ] {
+            acid.extend_from_slice(&offset.to_le_bytes());
+            acid.extend_from_slice(&(size as u32).to_le_bytes());
+        }
+        acid.resize(fac_offset as usize, 0);
+        acid.extend_from_slice(&fac);
+        acid.extend_from_slice(&sac);
+        acid.resize(kc_offset as usize, 0);
+        acid.extend_from_slice(&kc);
+
+        // META
+        let mut npdm = Vec::from(*b"META");
+        npdm.resize(0x0C, 0);
+        npdm.extend_from_slice(&[0b0000_0111, 0, 44, 0]);
+        npdm.extend_from_slice(&0u32.to_le_bytes());
+        npdm.extend_from_slice(&0x0080_0000u32.to_le_bytes());
+        npdm.extend_from_slice(&0u32.to_le_bytes());
+        npdm.extend_from_slice(&0x0010_0000u32.to_le_bytes());
+        npdm.extend_from_slice(b"Application\0\0\0\0\0");
+        npdm.resize(0x70, 0);
+        let aci0_offset = 0x80u32;
+        let acid_offset = (aci0_offset + aci0.len() as u32).next_multiple_of(0x10);
+        for (offset, size) in [(aci0_offset, aci0.len()), (acid_offset, acid.len())] {
+            npdm.extend_from_slice(&offset.to_le_bytes());
+            npdm.extend_from_slice(&(size as u32).to_le_bytes());
+        }
+        npdm.extend_from_slice(&aci0);
+        npdm.resize(acid_offset as usize, 0);
+        npdm.extend_from_slice(&acid);
+        (npdm, acid_offset as usize)
+    }
+
+    #[test]
+    fn test_npdm() {
+        let (data, _) = build_npdm();
+        let npdm = Npdm::from_bytes(&data).unwrap();
+
+        assert_eq!(npdm.meta.name_string(), "Application");
+        assert_eq!(npdm.meta.main_thread_priority, 44);
+        assert_eq!(npdm.meta.system_resource_size, 0x0080_0000);
+        assert_eq!(npdm.meta.main_thread_stack_size, 0x0010_0000);
+        assert!(npdm.meta.is_64bit_instruction());
+        assert_eq!(
+            npdm.meta.address_space(),
+            ProcessAddressSpace::AddressSpace64Bit
+        );
+
+        let acid = &npdm.acid;
+        assert!(acid.is_production());
+        assert_eq!(acid.memory_region(), 2);
+        assert!(acid.allows_program_id(PROGRAM_ID));
+        assert!(!acid.allows_program_id(PROGRAM_ID + 0x100));
+        assert_eq!(acid.public_key, [0xBB; 0x100]);
+        assert_eq!(acid.signed_data().len(), acid.header.size as usize);
+        assert_eq!(acid.fs_access_control.permissions, u64::MAX);
+        assert_eq!(
+            acid.fs_access_control.content_owner_id_range,
+            Some((PROGRAM_ID, PROGRAM_ID))
+        );
+        assert_eq!(acid.fs_access_control.content_owner_ids, [PROGRAM_ID]);
+
+        let aci0 = &npdm.aci0;
+        assert_eq!(aci0.header.program_id, PROGRAM_ID);
+        assert_eq!(aci0.fs_access_control.content_owner_ids, [PROGRAM_ID]);
+        assert_eq!(
+            aci0.fs_access_control.save_data_owners,
+            [
+                (PROGRAM_ID, Some(SaveDataAccessibility::ReadWrite)),
+                (0x0100000000002000, Some(SaveDataAccessibility::Read)),
+            ]
+        );
+        assert_eq!(
+            aci0.service_access_control,
+            [
+                ServiceAccess {
+                    name: "fsp-srv".to_string(),
+                    is_server: false
+                },
+                ServiceAccess {
+                    name: "lm".to_string(),
+                    is_server: false
+                },
+                ServiceAccess {
+                    name: "my:srv".to_string(),
+                    is_server: true
+                },
+            ]
+        );
+        assert_eq!(
+            aci0.kernel_capabilities,
+            [
+                KernelCapability::ThreadInfo {
+                    lowest_priority: 28,
+                    highest_priority: 59,
+                    min_core: 0,
+                    max_core: 2
+                },
+                KernelCapability::EnableSystemCalls(vec![1, 7]),
+                KernelCapability::MemoryMap {
+                    address: 0x7000_0000,
+                    size: 0x2000,
+                    read_only: true,
+                    is_static: false
+                },
+                KernelCapability::EnableInterrupts(vec![0x20]),
+                KernelCapability::KernelVersion { major: 3, minor: 0 },
+                KernelCapability::HandleTableSize(512),
+                KernelCapability::DebugFlags {
+                    allow_debug: true,
+                    force_debug_prod: false,
+                    force_debug: false
+                },
+            ]
+        );
+        assert_eq!(acid.kernel_capabilities, aci0.kernel_capabilities);
+    }
+
+    #[test]
+    fn test_npdm_oversized_save_data_owner_count() {
+        let (mut data, _) = build_npdm();
+        // Save data owner count in the ACI0 FS access header
+        let count_offset = 0x80 + 0x40 + 0x28;
+        assert_eq!(&data[count_offset..count_offset + 4], 2u32.to_le_bytes());
+        data[count_offset..count_offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            Npdm::from_bytes(&data),
+            Err(Error::InvalidData(_))
+        ));
+    }
+
+    /// Build an NPDM whose ACID holds the test key and is signed with it
+    fn build_signed_npdm() -> Vec<u8> {
+        let (mut data, acid_offset) = build_npdm();
+        data[acid_offset..acid_offset + 0x100].copy_from_slice(&ACID_SIG);
+        data[acid_offset + 0x100..acid_offset + 0x200].copy_from_slice(&TEST_RSA_MODULUS);
+        data
+    }
+
+    fn test_keyset() -> Keyset {
+        let mut keyset = Keyset {
+            environment: KeyEnvironment::Development,
+            ..Default::default()
+        };
+        for name in ["nca_hdr_fixed_key_modulus_01", "acid_fixed_key_modulus_00"] {
+            keyset
+                .raw_keys
+                .insert(name.to_string(), TEST_RSA_MODULUS.to_vec().into());
+        }
+        keyset
+    }
+
+    /// A signed Program NCA header and its bytes
+    fn signed_header(program_id: u64, signature: &[u8; 0x100]) -> (NcaHeader, Vec<u8>) {
+        let mut header = test_header();
+        header.signature_key_generation = 1;
+        header.program_id = program_id;
+        let mut header_bytes = header.to_bytes();
+        header_bytes.resize(0x400, 0);
+        header
+            .header_sig
+            .signature
+            .as_flattened_mut()
+            .copy_from_slice(signature);
+        header
+            .header_key_sig
+            .signature
+            .as_flattened_mut()
+            .copy_from_slice(signature);
+        (header, header_bytes)
+    }
+
+    #[test]
+    fn test_acid_signature() {
+        let mut data = build_signed_npdm();
+        let npdm = Npdm::from_bytes(&data).unwrap();
+
+        assert!(matches!(
+            npdm.verify_acid_signature(&FixedKeys::default()),
+            SignatureStatus::Unchecked(_)
+        ));
+
+        let fixed_keys = test_keyset().fixed_keys();
+        assert_eq!(
+            npdm.verify_acid_signature(&fixed_keys),
+            SignatureStatus::Valid
+        );
+
+        // Widen the allowed program IDs
+        let acid_offset = npdm.meta.acid_offset as usize;
+        data[acid_offset + 0x219] = 0xFF;
+        let npdm = Npdm::from_bytes(&data).unwrap();
+        assert_eq!(
+            npdm.verify_acid_signature(&fixed_keys),
+            SignatureStatus::Invalid
+        );
+    }
+
+    #[test]
+    fn test_program_verification() {
+        let fixed_keys = test_keyset().fixed_keys();
+        let npdm = Npdm::from_bytes(&build_signed_npdm()).unwrap();
+
+        let (header, header_bytes) = signed_header(PROGRAM_ID, &PROGRAM_HEADER_SIG);
+        let verification = ProgramNcaVerification::new(&header, &header_bytes, &npdm, &fixed_keys);
+        assert!(verification.is_ok(), "{}", verification);
+        assert_eq!(verification.header_key_signature, SignatureStatus::Valid);
+
+        // An ACID with its own key can vouch for any header, but isn't signed by the fixed key
+        let (unsigned, _) = build_npdm();
+        let mut unsigned = Npdm::from_bytes(&unsigned).unwrap();
+        unsigned.acid.public_key = TEST_RSA_MODULUS;
+        let verification =
+            ProgramNcaVerification::new(&header, &header_bytes, &unsigned, &fixed_keys);
+        assert_eq!(verification.acid_signature, SignatureStatus::Invalid);
+        assert_eq!(verification.header_key_signature, SignatureStatus::Valid);
+        assert!(!verification.is_ok());
+
+        // Signed, but for a program ID outside the ACID's range
+        let (header, header_bytes) = signed_header(0, &HEADER_SIG);
+        let verification = ProgramNcaVerification::new(&header, &header_bytes, &npdm, &fixed_keys);
+        assert_eq!(verification.header_signature, SignatureStatus::Valid);
+        assert_eq!(verification.header_key_signature, SignatureStatus::Valid);
+        assert!(!verification.program_id_allowed);
+        assert!(!verification.is_ok());
+    }
+}
@@ -0,0 +1,279 @@
+//! Homebrew executables (NRO)
+//!
+//! An NRO is a module stored uncompressed, with its segments at their memory offsets in the
+//! file. Homebrew NROs usually end with an asset section (ASET) holding an icon, a NACP and a
+//! RomFS, which the homebrew menu and the application itself read.
+
+use binrw::prelude::*;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::error::Error;
+use crate::formats::elf::{ModuleImage, ModuleSegment};
+use crate::formats::nacp::Nacp;
+use crate::formats::nso::RodataExtent;
+use crate::formats::romfs::RomFs;
+use crate::io::SubFile;
+
+/// Where a segment is in the file, which is also where it is in memory
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NroSegmentHeader {
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// NRO header, including the start of `.text` before it
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone)]
+pub struct NroHeader {
+    /// Branch instruction at the start of `.text`
+    pub _branch: u32,
+    pub mod0_offset: u32,
+    pub _padding: [u8; 8],
+    #[brw(magic = b"NRO0")]
+    pub version: u32,
+    /// Size of the NRO, without the asset section
+    pub size: u32,
+    pub flags: u32,
+    pub text: NroSegmentHeader,
+    pub rodata: NroSegmentHeader,
+    pub data: NroSegmentHeader,
+    pub bss_size: u32,
+    pub _reserved: u32,
+    pub build_id: [u8; 0x20],
+    pub dso_handle_offset: u32,
+    pub _reserved2: u32,
+    pub api_info: RodataExtent,
+    pub dynstr: RodataExtent,
+    pub dynsym: RodataExtent,
+}
+
+/// Where an asset is, relative to the start of the asset section
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AssetExtent {
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Header of the asset section
+#[binrw]
+#[brw(little, magic = b"ASET")]
+#[derive(Debug, Clone)]
+pub struct AssetHeader {
+    pub version: u32,
+    /// JPEG icon
+    pub icon: AssetExtent,
+    pub nacp: AssetExtent,
+    pub romfs: AssetExtent,
+}
+
+/// An NRO module
+pub struct Nro<R: Read + Seek> {
+    reader: R,
+    pub header: NroHeader,
+    /// Asset section header, if the NRO has one
+    pub assets: Option<AssetHeader>,
+    file_size: u64,
+}
+
+impl<R: Read + Seek> Nro<R> {
+    /// Parse the header and asset section header of an NRO
+    pub fn from_reader(mut reader: R) -> Result<Self, Error> {
+        reader.seek(SeekFrom::Start(0))?;
+        let header: NroHeader = reader.read_le()?;
+
+        let file_size = reader.seek(SeekFrom::End(0))?;
+        let assets = if file_size > header.size as u64 {
+            reader.seek(SeekFrom::Start(header.size as u64))?;
+            match reader.read_le::<AssetHeader>() {
+                Ok(assets) => Some(assets),
+                Err(e) => {
+                    tracing::warn!("NRO has trailing data that isn't an asset section: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        Ok(Self {
+            reader,
+            header,
+            assets,
+            file_size,
+        })
+    }
+
+    /// Build ID as uppercase hex
+    pub fn build_id_string(&self) -> String {
+        hex::encode_upper(self.header.build_id)
+    }
+
+    /// SHA-256 of the NRO without its asset section, as listed in NRRs
+    pub fn hash(&mut self) -> Result<[u8; 0x20], Error> {
+        let data = self.read_at(0, self.header.size as u64)?;
+        Ok(Sha256::digest(&data).into())
+    }
+
+    /// Read `size` bytes at `offset`, which must be within the file
+    fn read_at(&mut self, offset: u64, size: u64) -> Result<Vec<u8>, Error> {
+        match offset.checked_add(size) {
+            Some(end) if end <= self.file_size => {}
+            _ => {
+                return Err(Error::InvalidData(format!(
+                    "NRO range 0x{:X}+0x{:X} is past the end of the 0x{:X} byte file",
+                    offset, size, self.file_size
+                )));
+            }
+        }
+
+        let mut data = vec![0; size as usize];
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Read all segments
+    pub fn load_image(&mut self) -> Result<ModuleImage, Error> {
+        let mut segments = Vec::with_capacity(3);
+        for segment in [self.header.text, self.header.rodata, self.header.data] {
+            segments.push(ModuleSegment {
+                memory_offset: segment.offset,
+                data: self.read_at(segment.offset as u64, segment.size as u64)?,
+            });
+        }
+        let [text, rodata, data] = segments.try_into().unwrap();
+        Ok(ModuleImage {
+            text,
+            rodata,
+            data,
+            bss_size: self.header.bss_size,
+        })
+    }
+
+    /// Convert the module to an AArch64 ELF, see [`ModuleImage::to_elf`]
+    pub fn to_elf(&mut self) -> Result<Vec<u8>, Error> {
+        self.load_image()?.to_elf()
+    }
+
+    /// Absolute range of an asset, `None` if there's no asset section or the asset is empty
+    fn asset_range(
+        &self,
+        asset: impl Fn(&AssetHeader) -> AssetExtent,
+    ) -> Result<Option<(u64, u64)>, Error> {
+        let Some(assets) = self.assets.as_ref() else {
+            return Ok(None);
+        };
+        let extent = asset(assets);
+        if extent.size == 0 {
+            return Ok(None);
+        }
+
+        let range = (self.header.size as u64)
+            .checked_add(extent.offset)
+            .and_then(|start| Some((start, start.checked_add(extent.size)?)));
+        match range {
+            Some((start, end)) if end <= self.file_size => Ok(Some((start, end))),
+            _ => Err(Error::InvalidData(format!(
+                "NRO asset at 0x{:X}+0x{:X} is past the end of the 0x{:X} byte file",
+                extent.offset, extent.size, self.file_size
+            ))),
+        }
+    }
+
+    /// Read the icon, a JPEG
+    pub fn read_icon(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        match self.asset_range(|assets| assets.icon)? {
+            Some((start, end)) => Ok(Some(self.read_at(start, end - start)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read the embedded NACP
+    pub fn read_nacp(&mut self) -> Result<Option<Nacp>, Error> {
+        match self.asset_range(|assets| assets.nacp)? {
+            Some((start, end)) => Ok(Some(Nacp::from_bytes(&self.read_at(start, end - start)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Open the embedded RomFS
+    pub fn open_romfs(&mut self) -> Result<Option<RomFs<SubFile<&mut R>>>, Error> {
+        match self.asset_range(|assets| assets.romfs)? {
+            Some((start, end)) => Ok(Some(RomFs::from_reader(SubFile::new(
+                &mut self.reader,
+                start,
+                end,
+            ))?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::nacp::NACP_SIZE;
+    use crate::formats::test_support::{build_module, build_nro, build_romfs, check_elf};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_nro() {
+        let mut nacp = vec![0; NACP_SIZE];
+        nacp[..8].copy_from_slice(b"Homebrew");
+        nacp[0x3060..0x3065].copy_from_slice(b"1.2.3");
+        let romfs = build_romfs(&[("config.ini", b"[app]")]);
+        let bytes = build_nro(b"\xFF\xD8icon", &nacp, &romfs);
+
+        let mut nro = Nro::from_reader(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(nro.build_id_string(), "B1".repeat(0x20));
+        assert_eq!(nro.header.rodata.offset, 0x1000);
+        assert_eq!(nro.read_icon().unwrap().unwrap(), b"\xFF\xD8icon");
+
+        let nacp = nro.read_nacp().unwrap().unwrap();
+        assert_eq!(nacp.default_title().unwrap().name_string(), "Homebrew");
+        assert_eq!(nacp.display_version_string(), "1.2.3");
+
+        let mut romfs = nro.open_romfs().unwrap().unwrap();
+        assert_eq!(romfs.read_to_vec("/config.ini").unwrap().unwrap(), b"[app]");
+
+        let mut module = build_module();
+        module.text.data = bytes[..0x1000].to_vec();
+        check_elf(&nro.to_elf().unwrap(), &module);
+    }
+
+    #[test]
+    fn test_nro_without_assets() {
+        let mut bytes = build_nro(b"", b"", b"");
+        let size = u32::from_le_bytes(bytes[0x18..0x1C].try_into().unwrap()) as usize;
+        bytes.truncate(size);
+
+        let mut nro = Nro::from_reader(Cursor::new(bytes)).unwrap();
+        assert!(nro.assets.is_none());
+        assert!(nro.read_icon().unwrap().is_none());
+        assert!(nro.open_romfs().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_nro_rejects_out_of_bounds_assets() {
+        let bytes = build_nro(b"\xFF\xD8icon", b"", b"");
+        let size = u32::from_le_bytes(bytes[0x18..0x1C].try_into().unwrap()) as usize;
+
+        // Icon size past the end of the file
+        let mut corrupted = bytes.clone();
+        corrupted[size + 0x10..size + 0x18].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mut nro = Nro::from_reader(Cursor::new(corrupted)).unwrap();
+        assert!(nro.read_icon().is_err());
+
+        // Icon offset that overflows
+        let mut corrupted = bytes;
+        corrupted[size + 0x8..size + 0x10].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mut nro = Nro::from_reader(Cursor::new(corrupted)).unwrap();
+        assert!(nro.read_icon().is_err());
+    }
+}
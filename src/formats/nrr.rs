@@ -0,0 +1,370 @@
+//! Executable registries (NRR)
+//!
+//! A program can only load NROs whose SHA-256 hash is listed in one of its NRRs, which titles
+//! with plugins ship as `.nrr` files under `/.nrr` in their RomFS.
+//!
+//! An NRR is signed in two steps. Its certification, holding a public key and the program IDs
+//! it may be used for, is signed with a fixed NRR key. The rest of the NRR is then signed with
+//! the key in the certification.
+
+use binrw::prelude::*;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+use crate::error::Error;
+use crate::formats::keyset::{FixedKeys, KeyEnvironment, SignatureStatus, verify_rsa_pss_sha256};
+use crate::formats::nro::Nro;
+
+/// Size of the NRR header
+pub const NRR_HEADER_SIZE: usize = 0x350;
+/// Offset of the data signed by the certification's key, from the program ID on
+const NRR_SIGNED_OFFSET: usize = 0x330;
+/// Size of the signed part of the certification, everything before its signature
+const CERTIFICATION_SIGNED_SIZE: usize = 0x120;
+
+/// What the NRR registers NROs for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NrrKind {
+    /// NROs loaded by the program itself
+    User = 0,
+    /// JIT plugins
+    JitPlugin = 1,
+}
+
+impl TryFrom<u8> for NrrKind {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(NrrKind::User),
+            1 => Ok(NrrKind::JitPlugin),
+            _ => Err(Error::InvalidData(format!("Unknown NRR kind: {}", value))),
+        }
+    }
+}
+
+/// Public key for the NRR signature, and the program IDs it may sign NRRs for
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone)]
+pub struct NrrCertification {
+    pub program_id_mask: u64,
+    pub program_id_pattern: u64,
+    pub _reserved: [u8; 0x10],
+    pub public_key: [u8; 0x100],
+    /// RSA-2048-PSS signature over the rest of the certification, made with the fixed NRR key
+    pub signature: [u8; 0x100],
+}
+
+impl NrrCertification {
+    /// Whether the certification covers a program ID
+    pub fn allows_program_id(&self, program_id: u64) -> bool {
+        program_id & self.program_id_mask == self.program_id_pattern
+    }
+
+    /// Verify the certification against the fixed NRR key of a key generation
+    pub fn verify_signature(&self, fixed_keys: &FixedKeys, generation: u8) -> SignatureStatus {
+        let mut cursor = Cursor::new(Vec::new());
+        self.write_le(&mut cursor)
+            .expect("Failed to serialize NRR certification");
+        let data = cursor.into_inner();
+
+        SignatureStatus::check(fixed_keys.nrr_modulus(generation), |modulus| {
+            verify_rsa_pss_sha256(modulus, &data[..CERTIFICATION_SIGNED_SIZE], &self.signature)
+        })
+    }
+}
+
+/// NRR header
+#[binrw]
+#[brw(little, magic = b"NRR0")]
+#[derive(Debug, Clone)]
+pub struct NrrHeader {
+    /// Selects the fixed key the certification is signed with
+    pub key_generation: u8,
+    pub _reserved: [u8; 0xB],
+    pub certification: NrrCertification,
+    /// RSA-2048-PSS signature from the program ID to the end of the NRR, made with the
+    /// certification's key
+    pub signature: [u8; 0x100],
+    pub program_id: u64,
+    /// Size of the whole NRR
+    pub size: u32,
+    pub kind: u8,
+    pub _reserved2: [u8; 3],
+    pub hashes_offset: u32,
+    pub hash_count: u32,
+    pub _reserved3: [u8; 8],
+}
+
+impl NrrHeader {
+    /// See [`NrrKind`]
+    pub fn kind(&self) -> Option<NrrKind> {
+        NrrKind::try_from(self.kind).ok()
+    }
+}
+
+/// Result of [`Nrr::verify`]
+#[derive(Debug, Clone)]
+pub struct NrrVerification {
+    /// The environment whose keys were used
+    pub environment: KeyEnvironment,
+    /// Signature over the certification, made with the fixed NRR key
+    pub certification_signature: SignatureStatus,
+    /// Signature over the NRR, made with the certification's key
+    pub signature: SignatureStatus,
+    /// Whether the certification covers the NRR's program ID
+    pub program_id_allowed: bool,
+}
+
+impl NrrVerification {
+    /// Whether both signatures are valid and the certification covers the program ID
+    pub fn is_ok(&self) -> bool {
+        self.certification_signature.is_valid()
+            && self.signature.is_valid()
+            && self.program_id_allowed
+    }
+}
+
+impl std::fmt::Display for NrrVerification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Keys: {}", self.environment)?;
+        writeln!(
+            f,
+            "Certification signature: {}",
+            self.certification_signature
+        )?;
+        writeln!(f, "NRR signature: {}", self.signature)?;
+        write!(
+            f,
+            "Program ID: {}",
+            if self.program_id_allowed {
+                "allowed by certification"
+            } else {
+                "not allowed by certification"
+            }
+        )
+    }
+}
+
+/// A parsed NRR
+#[derive(Debug, Clone)]
+pub struct Nrr {
+    pub header: NrrHeader,
+    /// SHA-256 hashes of the registered NROs, sorted
+    pub hashes: Vec<[u8; 0x20]>,
+    /// The whole NRR, kept for signature verification
+    data: Vec<u8>,
+}
+
+impl Nrr {
+    /// Parse an NRR
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<Self, Error> {
+        let mut data = Vec::new();
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_to_end(&mut data)?;
+        Self::from_bytes(&data)
+    }
+
+    /// Parse an NRR from its bytes
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        let header: NrrHeader = Cursor::new(data).read_le()?;
+
+        let size = header.size as usize;
+        if size < NRR_HEADER_SIZE || size > data.len() {
+            return Err(Error::InvalidData(format!(
+                "NRR size 0x{:X} is out of bounds, the file is 0x{:X} bytes",
+                size,
+                data.len()
+            )));
+        }
+
+        let start = header.hashes_offset as usize;
+        let end = start + header.hash_count as usize * 0x20;
+        let hashes = data
+            .get(start..end)
+            .filter(|_| end <= size)
+            .ok_or_else(|| {
+                Error::InvalidData(format!(
+                    "NRR hash list 0x{:X}..0x{:X} is out of bounds",
+                    start, end
+                ))
+            })?
+            .chunks_exact(0x20)
+            .map(|hash| hash.try_into().unwrap())
+            .collect();
+
+        Ok(Self {
+            header,
+            hashes,
+            data: data[..size].to_vec(),
+        })
+    }
+
+    /// The data covered by the NRR signature, from the program ID to the end of the NRR
+    pub fn signed_data(&self) -> &[u8] {
+        &self.data[NRR_SIGNED_OFFSET..]
+    }
+
+    /// Verify the NRR signature with the certification's key
+    ///
+    /// Only meaningful once the certification itself has been verified.
+    pub fn verify_signature(&self) -> bool {
+        verify_rsa_pss_sha256(
+            &self.header.certification.public_key,
+            self.signed_data(),
+            &self.header.signature,
+        )
+    }
+
+    /// Verify the certification chain: the certification with the fixed NRR key, then the
+    /// NRR with the certification's key
+    pub fn verify(&self, fixed_keys: &FixedKeys) -> NrrVerification {
+        let certification = &self.header.certification;
+        let signature = if self.verify_signature() {
+            SignatureStatus::Valid
+        } else {
+            SignatureStatus::Invalid
+        };
+
+        NrrVerification {
+            environment: fixed_keys.environment,
+            certification_signature: certification
+                .verify_signature(fixed_keys, self.header.key_generation),
+            signature,
+            program_id_allowed: certification.allows_program_id(self.header.program_id),
+        }
+    }
+
+    /// Whether an NRO hash is registered
+    pub fn contains_hash(&self, hash: &[u8; 0x20]) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    /// Whether an NRO is registered
+    pub fn contains_nro<R: Read + Seek>(&self, nro: &mut Nro<R>) -> Result<bool, Error> {
+        Ok(self.contains_hash(&nro.hash()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::Keyset;
+    use crate::formats::keyset::TEST_RSA_MODULUS;
+    use crate::formats::test_support::build_nro;
+    use sha2::{Digest, Sha256};
+    use std::io::Cursor;
+
+    const PROGRAM_ID: u64 = 0x0100000000001000;
+
+    /// Signature of the certification from [`build_nrr`], made with the test key
+    const CERTIFICATION_SIG: [u8; 0x100] = hex_literal::hex!(
+        "0d7a7edccb0e9ba504cf56c89726f862d6838844e4da7a9abf799d1359d99facde178173e7dbd0a45ec8920b5f6077f4058291d3145d4088249dca4ce52a7534ff37b6fec43127d3bc7664ab5222f28495abdecc0bc7c925e96f5d1a53b276912e4e3438beba2d6af39c0da02d6407fc80d7dc284299fbb9204718f0466b84482a77ae9e49717aa90f2a148bd053a7744754ae5ceb6ff036a114351f0098e2ddde5e206b55c86c4ffdd1e5dd504d472f454e2ba42be344f0f4aef567d00fc2d63a1477c34fb46827606a00695cd877d79f1439075364592e55500d6d0700e331149700175444f0553241eb62861c206e2e4b9384cec6382a59c8b1899b8e11bc"
+    );
+
+    /// Signature of the NRR from [`build_nrr`] for the test NRO and `[0x11; 0x20]`, made with
+    /// the test key
+    const NRR_SIG: [u8; 0x100] = hex_literal::hex!(
+        "33e7cd95b8778df3891263b35bb03b1ae6e457aebfbea93187a5f780016f90dd27990e9fe2b69090d407497a2c30cde3ef978b8845946b53ca5b9cb9b7eb642e128d303ce46e06fab4bce6b87d41ba7a125936d84f77ac86f44529aa8ab57928320369b16af64c62f238d05eb3594fd829e7c0bccd764aa9195135bb7846c79d1609dbc8890fcee14d23e937f9d2c759bf0c2891b03e4e9f713f5b91dd691b06db57803b0301946459ae85a92e42a21a3574b5b7ac4778c1f6f7adcfb432d15e49c9c55e6175c806be4f3fe166f6903ddeb027bfc1e19879c79ff5698951cffd58aed3aa40c24bebc4c76a8302be32bd6625d19a16bbdbc8361f01553d65a717"
+    );
+
+    /// Build a signed NRR listing the given hashes, certified for the program IDs 0x0100000000001xxx
+    fn build_nrr(hashes: &[[u8; 0x20]]) -> Vec<u8> {
+        let mut hashes = hashes.to_vec();
+        hashes.sort();
+
+        let mut nrr = Vec::from(*b"NRR0");
+        nrr.push(1);
+        nrr.resize(0x10, 0);
+        nrr.extend_from_slice(&0xFFFF_FFFF_FFFF_F000u64.to_le_bytes());
+        nrr.extend_from_slice(&PROGRAM_ID.to_le_bytes());
+        nrr.resize(0x30, 0);
+        nrr.extend_from_slice(&TEST_RSA_MODULUS);
+        nrr.extend_from_slice(&CERTIFICATION_SIG);
+        nrr.extend_from_slice(&NRR_SIG);
+        nrr.extend_from_slice(&PROGRAM_ID.to_le_bytes());
+        let size = NRR_HEADER_SIZE + hashes.len() * 0x20;
+        nrr.extend_from_slice(&(size as u32).to_le_bytes());
+        nrr.push(NrrKind::User as u8);
+        nrr.resize(0x340, 0);
+        nrr.extend_from_slice(&(NRR_HEADER_SIZE as u32).to_le_bytes());
+        nrr.extend_from_slice(&(hashes.len() as u32).to_le_bytes());
+        nrr.resize(NRR_HEADER_SIZE, 0);
+        for hash in &hashes {
+            nrr.extend_from_slice(hash);
+        }
+        nrr
+    }
+
+    /// The test NRO, and its hash
+    fn nro() -> (Vec<u8>, [u8; 0x20]) {
+        let bytes = build_nro(b"\xFF\xD8icon", b"", b"");
+        let size = u32::from_le_bytes(bytes[0x18..0x1C].try_into().unwrap()) as usize;
+        let hash = Sha256::digest(&bytes[..size]).into();
+        (bytes, hash)
+    }
+
+    #[test]
+    fn test_nrr() {
+        let (nro_bytes, hash) = nro();
+        let bytes = build_nrr(&[hash, [0x11; 0x20]]);
+        let nrr = Nrr::from_reader(&mut Cursor::new(bytes)).unwrap();
+
+        assert_eq!(nrr.header.program_id, PROGRAM_ID);
+        assert_eq!(nrr.header.kind(), Some(NrrKind::User));
+        assert_eq!(nrr.hashes.len(), 2);
+        assert!(nrr.contains_hash(&[0x11; 0x20]));
+        assert!(!nrr.contains_hash(&[0x22; 0x20]));
+
+        // The asset section isn't part of the hash
+        let mut nro = Nro::from_reader(Cursor::new(nro_bytes.clone())).unwrap();
+        assert!(nro.assets.is_some());
+        assert!(nrr.contains_nro(&mut nro).unwrap());
+
+        let mut patched = nro_bytes;
+        patched[0x1000] ^= 0xFF;
+        let mut nro = Nro::from_reader(Cursor::new(patched)).unwrap();
+        assert!(!nrr.contains_nro(&mut nro).unwrap());
+    }
+
+    #[test]
+    fn test_nrr_verification() {
+        let (_, hash) = nro();
+        let mut bytes = build_nrr(&[hash, [0x11; 0x20]]);
+        let nrr = Nrr::from_bytes(&bytes).unwrap();
+
+        let mut keyset = Keyset::default();
+        let verification = nrr.verify(&keyset.fixed_keys());
+        assert!(matches!(
+            verification.certification_signature,
+            SignatureStatus::Unchecked(_)
+        ));
+        assert_eq!(verification.signature, SignatureStatus::Valid);
+        assert!(!verification.is_ok());
+
+        keyset.raw_keys.insert(
+            "nrr_fixed_key_modulus_01".to_string(),
+            TEST_RSA_MODULUS.to_vec().into(),
+        );
+        let fixed_keys = keyset.fixed_keys();
+        let verification = nrr.verify(&fixed_keys);
+        assert!(verification.is_ok(), "{}", verification);
+
+        // Register another NRO
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        let verification = Nrr::from_bytes(&bytes).unwrap().verify(&fixed_keys);
+        assert_eq!(verification.certification_signature, SignatureStatus::Valid);
+        assert_eq!(verification.signature, SignatureStatus::Invalid);
+
+        // Certify other programs
+        bytes[last] ^= 0xFF;
+        bytes[0x19] ^= 0x10;
+        let verification = Nrr::from_bytes(&bytes).unwrap().verify(&fixed_keys);
+        assert_eq!(
+            verification.certification_signature,
+            SignatureStatus::Invalid
+        );
+        assert!(!verification.program_id_allowed);
+    }
+}
@@ -0,0 +1,394 @@
+//! Nintendo Switch executables (NSO)
+//!
+//! The `main`, `rtld` and `subsdk*` files in an ExeFS are NSO0 modules. An NSO holds three
+//! segments, `.text`, `.rodata` and `.data`, each of which may be LZ4 compressed and comes
+//! with a SHA-256 hash of its decompressed contents.
+//!
+//! The module ID (also called the build ID) identifies the exact build of a module. Cheat
+//! and patch tools key their files on it.
+
+use binrw::prelude::*;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::error::Error;
+use crate::formats::elf::{MAX_MODULE_SIZE, ModuleImage, ModuleSegment};
+
+/// Size of the NSO header
+pub const NSO_HEADER_SIZE: usize = 0x100;
+
+/// Where a segment is in the file and in memory
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SegmentHeader {
+    pub file_offset: u32,
+    pub memory_offset: u32,
+    /// Size of the decompressed segment
+    pub size: u32,
+}
+
+/// A range of the `.rodata` segment, relative to its start
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RodataExtent {
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// The segments of an NSO
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NsoSegment {
+    Text = 0,
+    Rodata = 1,
+    Data = 2,
+}
+
+impl NsoSegment {
+    pub const ALL: [NsoSegment; 3] = [NsoSegment::Text, NsoSegment::Rodata, NsoSegment::Data];
+}
+
+/// NSO header
+#[binrw]
+#[brw(little, magic = b"NSO0")]
+#[derive(Debug, Clone)]
+pub struct NsoHeader {
+    pub version: u32,
+    pub _reserved: u32,
+    /// Bits 0-2 mark compressed segments, bits 3-5 mark segments whose hash is checked
+    pub flags: u32,
+    pub text: SegmentHeader,
+    pub module_name_offset: u32,
+    pub rodata: SegmentHeader,
+    pub module_name_size: u32,
+    pub data: SegmentHeader,
+    pub bss_size: u32,
+    pub module_id: [u8; 0x20],
+    pub text_file_size: u32,
+    pub rodata_file_size: u32,
+    pub data_file_size: u32,
+    pub _reserved2: [u8; 0x1C],
+    pub api_info: RodataExtent,
+    pub dynstr: RodataExtent,
+    pub dynsym: RodataExtent,
+    pub text_hash: [u8; 0x20],
+    pub rodata_hash: [u8; 0x20],
+    pub data_hash: [u8; 0x20],
+}
+
+impl NsoHeader {
+    pub fn segment(&self, segment: NsoSegment) -> &SegmentHeader {
+        match segment {
+            NsoSegment::Text => &self.text,
+            NsoSegment::Rodata => &self.rodata,
+            NsoSegment::Data => &self.data,
+        }
+    }
+
+    /// Size of the segment as stored in the file
+    pub fn file_size(&self, segment: NsoSegment) -> u32 {
+        match segment {
+            NsoSegment::Text => self.text_file_size,
+            NsoSegment::Rodata => self.rodata_file_size,
+            NsoSegment::Data => self.data_file_size,
+        }
+    }
+
+    /// SHA-256 of the decompressed segment
+    pub fn hash(&self, segment: NsoSegment) -> &[u8; 0x20] {
+        match segment {
+            NsoSegment::Text => &self.text_hash,
+            NsoSegment::Rodata => &self.rodata_hash,
+            NsoSegment::Data => &self.data_hash,
+        }
+    }
+
+    pub fn is_compressed(&self, segment: NsoSegment) -> bool {
+        self.flags & (1 << segment as u32) != 0
+    }
+
+    /// Whether the loader checks the segment's hash
+    pub fn checks_hash(&self, segment: NsoSegment) -> bool {
+        self.flags & (1 << (segment as u32 + 3)) != 0
+    }
+}
+
+/// An NSO module
+pub struct Nso<R: Read + Seek> {
+    reader: R,
+    pub header: NsoHeader,
+    file_size: u64,
+}
+
+impl<R: Read + Seek> Nso<R> {
+    /// Parse the header of an NSO
+    pub fn from_reader(mut reader: R) -> Result<Self, Error> {
+        reader.seek(SeekFrom::Start(0))?;
+        let header: NsoHeader = reader.read_le()?;
+        let file_size = reader.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            reader,
+            header,
+            file_size,
+        })
+    }
+
+    /// Module ID as uppercase hex
+    ///
+    /// Patch tools use all of it, cheat tools only the first 16 characters.
+    pub fn module_id_string(&self) -> String {
+        hex::encode_upper(self.header.module_id)
+    }
+
+    /// Read a segment and decompress it, without checking its hash
+    fn read_segment_raw(&mut self, segment: NsoSegment) -> Result<Vec<u8>, Error> {
+        let header = *self.header.segment(segment);
+        let file_size = self.header.file_size(segment);
+
+        let end = header.file_offset as u64 + file_size as u64;
+        if end > self.file_size {
+            return Err(Error::InvalidData(format!(
+                "{:?} segment 0x{:X}+0x{:X} is past the end of the 0x{:X} byte file",
+                segment, header.file_offset, file_size, self.file_size
+            )));
+        }
+        if header.size as u64 > MAX_MODULE_SIZE {
+            return Err(Error::InvalidData(format!(
+                "{:?} segment is 0x{:X} bytes, over the 0x{:X} byte limit",
+                segment, header.size, MAX_MODULE_SIZE
+            )));
+        }
+
+        let mut data = vec![0; file_size as usize];
+        self.reader
+            .seek(SeekFrom::Start(header.file_offset as u64))?;
+        self.reader.read_exact(&mut data)?;
+
+        if !self.header.is_compressed(segment) {
+            if file_size != header.size {
+                return Err(Error::InvalidData(format!(
+                    "Uncompressed {:?} segment is 0x{:X} bytes, expected 0x{:X}",
+                    segment, file_size, header.size
+                )));
+            }
+            return Ok(data);
+        }
+
+        let decompressed =
+            lz4_flex::block::decompress(&data, header.size as usize).map_err(|e| {
+                Error::InvalidData(format!("Failed to decompress {:?} segment: {}", segment, e))
+            })?;
+        if decompressed.len() != header.size as usize {
+            return Err(Error::InvalidData(format!(
+                "{:?} segment decompressed to 0x{:X} bytes, expected 0x{:X}",
+                segment,
+                decompressed.len(),
+                header.size
+            )));
+        }
+        Ok(decompressed)
+    }
+
+    /// Read a segment, decompressing it if needed
+    ///
+    /// # Errors
+    /// [`Error::InvalidData`] if the segment's hash is checked and doesn't match.
+    pub fn read_segment(&mut self, segment: NsoSegment) -> Result<Vec<u8>, Error> {
+        let data = self.read_segment_raw(segment)?;
+        if self.header.checks_hash(segment)
+            && Sha256::digest(&data).as_slice() != self.header.hash(segment)
+        {
+            return Err(Error::InvalidData(format!(
+                "{:?} segment hash mismatch",
+                segment
+            )));
+        }
+        Ok(data)
+    }
+
+    /// Check the hash of a segment, whether or not the loader would check it
+    pub fn verify_segment(&mut self, segment: NsoSegment) -> Result<bool, Error> {
+        let data = self.read_segment_raw(segment)?;
+        Ok(Sha256::digest(&data).as_slice() == self.header.hash(segment))
+    }
+
+    /// Check the hashes of all segments
+    pub fn verify(&mut self) -> Result<bool, Error> {
+        for segment in NsoSegment::ALL {
+            if !self.verify_segment(segment)? {
+                tracing::warn!(?segment, "NSO segment hash mismatch");
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Read and decompress all segments
+    pub fn load_image(&mut self) -> Result<ModuleImage, Error> {
+        let mut segments = Vec::with_capacity(3);
+        for segment in NsoSegment::ALL {
+            segments.push(ModuleSegment {
+                memory_offset: self.header.segment(segment).memory_offset,
+                data: self.read_segment(segment)?,
+            });
+        }
+        let [text, rodata, data] = segments.try_into().unwrap();
+        Ok(ModuleImage {
+            text,
+            rodata,
+            data,
+            bss_size: self.header.bss_size,
+        })
+    }
+
+    /// Convert the module to an AArch64 ELF, see [`ModuleImage::to_elf`]
+    pub fn to_elf(&mut self) -> Result<Vec<u8>, Error> {
+        self.load_image()?.to_elf()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::test_support::{build_module, check_elf};
+    use std::io::Cursor;
+
+    /// Build an NSO with the given segments, compressing and checking `.text` and `.data`
+    fn build_nso(text: &[u8], rodata: &[u8], data: &[u8], bss_size: u32) -> Vec<u8> {
+        let segments = [
+            (text, lz4_flex::block::compress(text)),
+            (rodata, rodata.to_vec()),
+            (data, lz4_flex::block::compress(data)),
+        ];
+
+        let mut header = NsoHeader {
+            version: 0,
+            _reserved: 0,
+            flags: 0b101_101,
+            text: SegmentHeader::default(),
+            module_name_offset: 0,
+            rodata: SegmentHeader::default(),
+            module_name_size: 0,
+            data: SegmentHeader::default(),
+            bss_size,
+            module_id: [0; 0x20],
+            text_file_size: 0,
+            rodata_file_size: 0,
+            data_file_size: 0,
+            _reserved2: [0; 0x1C],
+            api_info: RodataExtent::default(),
+            dynstr: RodataExtent::default(),
+            dynsym: RodataExtent::default(),
+            text_hash: [0; 0x20],
+            rodata_hash: [0; 0x20],
+            data_hash: [0; 0x20],
+        };
+        header.module_id[..8].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF, 0, 1, 2, 3]);
+
+        let mut body = Vec::new();
+        let mut memory_offset = 0;
+        for (i, (plain, stored)) in segments.iter().enumerate() {
+            let segment_header = SegmentHeader {
+                file_offset: (NSO_HEADER_SIZE + body.len()) as u32,
+                memory_offset,
+                size: plain.len() as u32,
+            };
+            let hash: [u8; 0x20] = Sha256::digest(plain).into();
+            match i {
+                0 => {
+                    header.text = segment_header;
+                    header.text_file_size = stored.len() as u32;
+                    header.text_hash = hash;
+                }
+                1 => {
+                    header.rodata = segment_header;
+                    header.rodata_file_size = stored.len() as u32;
+                    header.rodata_hash = hash;
+                }
+                _ => {
+                    header.data = segment_header;
+                    header.data_file_size = stored.len() as u32;
+                    header.data_hash = hash;
+                }
+            }
+            body.extend_from_slice(stored);
+            memory_offset += (plain.len() as u32).next_multiple_of(0x1000);
+        }
+
+        let mut out = Cursor::new(Vec::new());
+        header.write_le(&mut out).unwrap();
+        let mut out = out.into_inner();
+        out.extend_from_slice(&body);
+        out
+    }
+
+    #[test]
+    fn test_nso() {
+        let text = [0x1F, 0x20, 0x03, 0xD5].repeat(0x100);
+        let rodata = b"rodata".repeat(0x10);
+        let data = vec![0xAB; 0x80];
+        let bytes = build_nso(&text, &rodata, &data, 0x1000);
+
+        let mut nso = Nso::from_reader(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(
+            nso.module_id_string(),
+            format!("DEADBEEF00010203{}", "0".repeat(48))
+        );
+        assert!(nso.header.is_compressed(NsoSegment::Text));
+        assert!(!nso.header.is_compressed(NsoSegment::Rodata));
+        assert!(!nso.header.checks_hash(NsoSegment::Rodata));
+        assert_eq!(nso.read_segment(NsoSegment::Text).unwrap(), text);
+        assert_eq!(nso.read_segment(NsoSegment::Rodata).unwrap(), rodata);
+        assert_eq!(nso.read_segment(NsoSegment::Data).unwrap(), data);
+        assert!(nso.verify().unwrap());
+
+        // Corrupt the checked .text hash
+        let mut corrupted = bytes;
+        corrupted[0xA0] ^= 0xFF;
+        let mut nso = Nso::from_reader(Cursor::new(corrupted)).unwrap();
+        assert!(nso.read_segment(NsoSegment::Text).is_err());
+        assert!(!nso.verify_segment(NsoSegment::Text).unwrap());
+        assert!(nso.verify_segment(NsoSegment::Data).unwrap());
+    }
+
+    #[test]
+    fn test_nso_oversized_segment() {
+        let bytes = build_nso(&[0; 0x100], &[0; 0x100], &[0; 0x100], 0);
+
+        // .text stored size past the end of the file
+        let mut truncated = bytes.clone();
+        truncated[0x60..0x64].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut nso = Nso::from_reader(Cursor::new(truncated)).unwrap();
+        assert_eq!(nso.header.text_file_size, u32::MAX);
+        assert!(matches!(
+            nso.read_segment(NsoSegment::Text),
+            Err(Error::InvalidData(_))
+        ));
+
+        // Decompressed .text size over the module limit
+        let mut oversized = bytes;
+        oversized[0x18..0x1C].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut nso = Nso::from_reader(Cursor::new(oversized)).unwrap();
+        assert_eq!(nso.header.text.size, u32::MAX);
+        assert!(matches!(
+            nso.read_segment(NsoSegment::Text),
+            Err(Error::InvalidData(_))
+        ));
+    }
+
+    #[test]
+    fn test_nso_to_elf() {
+        let module = build_module();
+        let bytes = build_nso(
+            &module.text.data,
+            &module.rodata.data,
+            &module.data.data,
+            module.bss_size,
+        );
+
+        let mut nso = Nso::from_reader(Cursor::new(bytes)).unwrap();
+        let elf = nso.to_elf().unwrap();
+        check_elf(&elf, &module);
+    }
+}
@@ -16,7 +16,12 @@ use std::io::{Read, Seek, SeekFrom};
 use binrw::prelude::*;
 
 use crate::{
-    FileEntryExt, TitleDataExt, VirtualFSExt,
+    FileEntryExt, KeyRequirementsExt, TitleDataExt, VirtualFSExt,
+    formats::{
+        Keyset, TitleKeys,
+        nca::{KeyRequirements, NcaKeyRequirements},
+        ticket::add_bundled_title_key,
+    },
     io::{SharedReader, SubFile},
 };
 
@@ -26,7 +31,7 @@ pub type Nsp<R> = Pfs0<R>;
 pub type NspHeader = Pfs0Header;
 pub type NspEntry = Pfs0Entry;
 
-#[derive(BinRead, Debug)]
+#[derive(BinRead, BinWrite, Debug)]
 #[brw(little, magic = b"PFS0")]
 /// Nintendo Switch PFS0 (PartitionFS0) header structure
 ///
@@ -54,7 +59,7 @@ impl Pfs0Header {
     pub const MAGIC: [u8; 4] = *b"PFS0";
 }
 
-#[derive(BinRead, Debug)]
+#[derive(BinRead, BinWrite, Debug, Clone)]
 #[brw(little)]
 /// The PFS0 file entry structure describes a single file within the archive
 ///
@@ -116,6 +121,78 @@ pub struct Pfs0File {
     pub size: u64,
 }
 
+/// Alignment of the PFS0 header size, so file data starts on an aligned offset
+pub const PFS0_HEADER_ALIGNMENT: u64 = 0x20;
+
+/// Builds the header of a new PFS0 archive
+///
+/// Files are laid out back to back in the order they are added. The caller writes
+/// [`Pfs0Builder::header_bytes`] followed by the data of each file.
+#[derive(Debug, Default)]
+pub struct Pfs0Builder {
+    entries: Vec<Pfs0Entry>,
+    string_table: Vec<u8>,
+    data_size: u64,
+}
+
+impl Pfs0Builder {
+    /// Create an empty PFS0 builder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a file, returning its offset from the start of the data section
+    pub fn add_file(&mut self, name: &str, size: u64) -> u64 {
+        let offset = self.data_size;
+
+        self.entries.push(Pfs0Entry {
+            data_offset: offset,
+            data_size: size,
+            string_table_offset: self.string_table.len() as u32,
+            reserved: [0; 4],
+        });
+        self.string_table.extend_from_slice(name.as_bytes());
+        self.string_table.push(0);
+        self.data_size += size;
+
+        offset
+    }
+
+    /// Serialize the header, file entries and string table
+    ///
+    /// The string table is zero-padded so the header size is a multiple of 0x20.
+    pub fn header_bytes(&self) -> Vec<u8> {
+        let unpadded = 0x10 + 0x18 * self.entries.len() + self.string_table.len();
+        let padded = (unpadded as u64).next_multiple_of(PFS0_HEADER_ALIGNMENT) as usize;
+
+        let header = Pfs0Header {
+            num_files: self.entries.len() as u32,
+            str_table_offset: (self.string_table.len() + padded - unpadded) as u32,
+            reserved: [0; 4],
+        };
+
+        let mut cursor = binrw::io::Cursor::new(Vec::with_capacity(padded));
+        header
+            .write(&mut cursor)
+            .expect("Failed to serialize PFS0 header");
+        for entry in &self.entries {
+            entry
+                .write(&mut cursor)
+                .expect("Failed to serialize PFS0 entry");
+        }
+
+        let mut bytes = cursor.into_inner();
+        bytes.extend_from_slice(&self.string_table);
+        bytes.resize(padded, 0);
+        bytes
+    }
+
+    /// Total size of the archive, header included
+    pub fn total_size(&self) -> u64 {
+        self.header_bytes().len() as u64 + self.data_size
+    }
+}
+
 /// Main structure for working with Nintendo Switch PFS0 archives
 ///
 /// PFS0 is a simple archive format used by Nintendo Switch for packaging files.
@@ -303,6 +380,52 @@ impl<R: Read + Seek + Clone> TitleDataExt for Pfs0<R> {
     }
 }
 
+/// Title keys from the common tickets bundled in the package count as available
+impl<R: Read + Seek> KeyRequirementsExt for Pfs0<R> {
+    fn key_requirements(
+        &mut self,
+        keyset: &Keyset,
+        title_keys: Option<&TitleKeys>,
+    ) -> Result<KeyRequirements, crate::error::Error> {
+        let mut available = TitleKeys::new();
+        if let Some(title_keys) = title_keys {
+            available.merge(title_keys);
+        }
+        let tickets: Vec<Pfs0File> = self
+            .files
+            .iter()
+            .filter(|file| file.name.ends_with(".tik"))
+            .cloned()
+            .collect();
+        for file in tickets {
+            add_bundled_title_key(&mut available, &file.name, self.read_to_vec(&file)?)?;
+        }
+
+        let ncas: Vec<Pfs0File> = self
+            .files
+            .iter()
+            .filter(|file| file.name.ends_with(".nca"))
+            .cloned()
+            .collect();
+
+        let mut requirements = KeyRequirements::default();
+        for file in ncas {
+            let mut header = vec![0u8; 0x400];
+            self.read_buf(&file, &mut header)?;
+            requirements
+                .ncas
+                .push(NcaKeyRequirements::from_header_bytes(
+                    file.name,
+                    &header,
+                    keyset,
+                    Some(&available),
+                ));
+        }
+
+        Ok(requirements)
+    }
+}
+
 impl<R: Read + Seek + Clone> VirtualFSExt<R> for Pfs0<R> {
     type Entry = Pfs0File;
 
@@ -375,4 +498,70 @@ mod tests {
         assert_eq!(data.len(), fixture_data.len());
         assert_eq!(data, fixture_data);
     }
+
+    #[test]
+    fn test_pfs0_builder_roundtrip() {
+        let mut builder = Pfs0Builder::new();
+        builder.add_file("a.nca", 3);
+        builder.add_file("b.tik", 2);
+
+        let mut image = builder.header_bytes();
+        assert_eq!(image.len() as u64 % PFS0_HEADER_ALIGNMENT, 0);
+        image.extend_from_slice(b"aaabb");
+        assert_eq!(image.len() as u64, builder.total_size());
+
+        let mut pfs0 = Pfs0::from_reader(std::io::Cursor::new(image)).unwrap();
+        let b = pfs0.get_file("b.tik").unwrap();
+        assert_eq!(pfs0.read_to_vec(&b).unwrap(), b"bb");
+        let a = pfs0.get_file("a.nca").unwrap();
+        assert_eq!(pfs0.read_to_vec(&a).unwrap(), b"aaa");
+    }
+
+    #[test]
+    fn test_key_requirements_use_bundled_tickets() {
+        use crate::formats::nca::RequiredKey;
+        use crate::formats::nca::test_header;
+        use crate::formats::test_support::build_common_ticket;
+
+        let rights_id = hex_literal::hex!("0100000000001000000000000000000a");
+        let key_gen = test_header().get_key_generation();
+        let keyset = Keyset::from_reader(std::io::Cursor::new(format!(
+            "header_key = {}\ntitlekek_{key_gen:02x} = {}\n",
+            "11".repeat(0x20),
+            "22".repeat(0x10),
+        )))
+        .unwrap();
+
+        let mut header = test_header();
+        header.rights_id = rights_id;
+        let nca = header.to_bytes_encrypt(&keyset);
+
+        let tik = build_common_ticket(rights_id, [0x42; 0x10]);
+
+        let build = |files: &[(&str, &[u8])]| {
+            let mut builder = Pfs0Builder::new();
+            for (name, data) in files {
+                builder.add_file(name, data.len() as u64);
+            }
+            let mut image = builder.header_bytes();
+            for (_, data) in files {
+                image.extend_from_slice(data);
+            }
+            Pfs0::from_reader(std::io::Cursor::new(image)).unwrap()
+        };
+
+        let title_key = RequiredKey::TitleKey { rights_id };
+        let reqs = build(&[("a.nca", &nca)])
+            .key_requirements(&keyset, None)
+            .unwrap();
+        assert_eq!(
+            reqs.missing().into_iter().collect::<Vec<_>>(),
+            vec![title_key]
+        );
+
+        let reqs = build(&[("a.nca", &nca), ("a.tik", &tik)])
+            .key_requirements(&keyset, None)
+            .unwrap();
+        assert!(reqs.is_satisfied(), "{}", reqs);
+    }
 }
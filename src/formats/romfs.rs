@@ -14,15 +14,15 @@ use std::sync::{Arc, Mutex};
 #[derive(Debug, Clone)]
 #[br(little)]
 pub struct RomFsHeader {
-    pub header_size: u32,
+    pub header_size: u64,
     pub dir_hash_table_offset: u64,
-    pub dir_hash_table_size: u32,
+    pub dir_hash_table_size: u64,
     pub dir_table_offset: u64,
-    pub dir_table_size: u32,
+    pub dir_table_size: u64,
     pub file_hash_table_offset: u64,
-    pub file_hash_table_size: u32,
+    pub file_hash_table_size: u64,
     pub file_table_offset: u64,
-    pub file_table_size: u32,
+    pub file_table_size: u64,
     pub file_data_offset: u64,
 }
 
@@ -126,9 +126,9 @@ impl<R: Read + Seek> RomFs<R> {
     /// Offset of the root directory
     pub const ROOT_DIR_OFFSET: u32 = 0;
     /// Maximum reasonable header size (to prevent excessive allocations)
-    const MAX_REASONABLE_HEADER_SIZE: u32 = 0x10000000; // 256MB
+    const MAX_REASONABLE_HEADER_SIZE: u64 = 0x10000000; // 256MB
     /// Maximum reasonable table size (to prevent excessive allocations)
-    const MAX_REASONABLE_TABLE_SIZE: u32 = 0x10000000; // 256MB
+    const MAX_REASONABLE_TABLE_SIZE: u64 = 0x10000000; // 256MB
 
     /// Create a new RomFS parser from a reader
     pub fn from_reader(mut reader: R) -> Result<Self, Error> {
@@ -584,3 +584,20 @@ impl<R: Read + Seek + Clone> FileEntryExt<R> for FileEntry {
         self.name.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::test_support::build_romfs;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_romfs() {
+        let data = build_romfs(&[("hello.txt", b"Hello!"), ("empty", b"")]);
+        let mut romfs = RomFs::from_reader(Cursor::new(data)).unwrap();
+        assert_eq!(romfs.header.header_size, 0x50);
+        assert_eq!(romfs.read_to_vec("/hello.txt").unwrap().unwrap(), b"Hello!");
+        assert_eq!(romfs.read_to_vec("/empty").unwrap().unwrap(), b"");
+        assert!(romfs.read_to_vec("/missing").unwrap().is_none());
+    }
+}
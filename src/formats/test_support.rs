@@ -0,0 +1,216 @@
+//! Builders for test fixtures shared between format modules
+//!
+//! Everything here builds small synthetic images in memory, so the tests don't depend on
+//! real (and encrypted) content.
+
+use crate::formats::elf::{ModuleImage, ModuleSegment};
+
+/// Build a RomFS with the given files in its root, all in a single hash bucket
+pub fn build_romfs(files: &[(&str, &[u8])]) -> Vec<u8> {
+    const INVALID: u32 = u32::MAX;
+
+    let mut file_table = Vec::new();
+    let mut file_data = Vec::new();
+    for (index, (name, data)) in files.iter().enumerate() {
+        let entry_size = (0x20 + name.len()).next_multiple_of(4);
+        let next = if index + 1 < files.len() {
+            (file_table.len() + entry_size) as u32
+        } else {
+            INVALID
+        };
+        file_table.extend_from_slice(&0u32.to_le_bytes()); // parent
+        file_table.extend_from_slice(&next.to_le_bytes()); // sibling
+        file_table.extend_from_slice(&(file_data.len() as u64).to_le_bytes());
+        file_table.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        file_table.extend_from_slice(&next.to_le_bytes()); // hash sibling
+        file_table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        file_table.extend_from_slice(name.as_bytes());
+        file_table.resize(file_table.len().next_multiple_of(4), 0);
+        file_data.extend_from_slice(data);
+        file_data.resize(file_data.len().next_multiple_of(0x10), 0);
+    }
+
+    let first_file = if files.is_empty() { INVALID } else { 0 };
+    let mut dir_table = Vec::new();
+    for value in [0, INVALID, INVALID, first_file, INVALID, 0] {
+        dir_table.extend_from_slice(&value.to_le_bytes());
+    }
+
+    let dir_hash_table_offset = 0x50u64;
+    let dir_table_offset = dir_hash_table_offset + 4;
+    let file_hash_table_offset = dir_table_offset + dir_table.len() as u64;
+    let file_table_offset = file_hash_table_offset + 4;
+    let file_data_offset = (file_table_offset + file_table.len() as u64).next_multiple_of(0x10);
+
+    let mut out = Vec::new();
+    for value in [
+        0x50,
+        dir_hash_table_offset,
+        4,
+        dir_table_offset,
+        dir_table.len() as u64,
+        file_hash_table_offset,
+        4,
+        file_table_offset,
+        file_table.len() as u64,
+        file_data_offset,
+    ] {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&dir_table);
+    out.extend_from_slice(&first_file.to_le_bytes());
+    out.extend_from_slice(&file_table);
+    out.resize(file_data_offset as usize, 0);
+    out.extend_from_slice(&file_data);
+    out
+}
+
+/// Build a module with a MOD0 header, a dynamic section and two symbols
+pub fn build_module() -> ModuleImage {
+    // .text: a branch over the MOD0 offset, then a few `ret`s
+    let mut text = vec![0x02, 0x00, 0x00, 0x14];
+    text.extend_from_slice(&0x1000u32.to_le_bytes());
+    text.extend_from_slice(&[0xC0, 0x03, 0x5F, 0xD6].repeat(4));
+
+    // .rodata: MOD0 at 0x1000, hash table at 0x1020, symbols at 0x1040, strings at 0x1070
+    let mut rodata = vec![0; 0x80];
+    rodata[..4].copy_from_slice(b"MOD0");
+    rodata[4..8].copy_from_slice(&(0x2000i32 - 0x1000).to_le_bytes());
+    rodata[8..12].copy_from_slice(&(0x2060i32 - 0x1000).to_le_bytes());
+    rodata[12..16].copy_from_slice(&(0x2060i32 - 0x1000).to_le_bytes());
+    rodata[0x20..0x24].copy_from_slice(&1u32.to_le_bytes());
+    rodata[0x24..0x28].copy_from_slice(&2u32.to_le_bytes());
+    // Symbol 1 is global, named "main", in .text
+    rodata[0x58..0x5C].copy_from_slice(&1u32.to_le_bytes());
+    rodata[0x5C] = 0x12;
+    rodata[0x5E..0x60].copy_from_slice(&1u16.to_le_bytes());
+    rodata[0x70..0x76].copy_from_slice(b"\0main\0");
+
+    let mut data = Vec::new();
+    for (tag, value) in [
+        (4u64, 0x1020u64), // DT_HASH
+        (5, 0x1070),       // DT_STRTAB
+        (6, 0x1040),       // DT_SYMTAB
+        (10, 6),           // DT_STRSZ
+        (11, 0x18),        // DT_SYMENT
+        (0, 0),            // DT_NULL
+    ] {
+        data.extend_from_slice(&tag.to_le_bytes());
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    ModuleImage {
+        text: ModuleSegment {
+            memory_offset: 0,
+            data: text,
+        },
+        rodata: ModuleSegment {
+            memory_offset: 0x1000,
+            data: rodata,
+        },
+        data: ModuleSegment {
+            memory_offset: 0x2000,
+            data,
+        },
+        bss_size: 0x100,
+    }
+}
+
+/// Check an ELF written from [`build_module`], reading the ELF64 structures by offset
+pub fn check_elf(elf: &[u8], module: &ModuleImage) {
+    let u16_at = |offset: u64| u16::from_le_bytes(elf[offset as usize..][..2].try_into().unwrap());
+    let u32_at = |offset: u64| u32::from_le_bytes(elf[offset as usize..][..4].try_into().unwrap());
+    let u64_at = |offset: u64| u64::from_le_bytes(elf[offset as usize..][..8].try_into().unwrap());
+
+    assert_eq!(&elf[..4], b"\x7FELF");
+    assert_eq!(u16_at(0x12), 183); // EM_AARCH64
+    assert_eq!(u16_at(0x38), 4);
+
+    // Program headers are 0x38 bytes each
+    let program_header = |index: u64| u64_at(0x20) + index * 0x38;
+    let dynamic = program_header(3);
+    assert_eq!(u32_at(dynamic), 2); // PT_DYNAMIC
+    assert_eq!(u64_at(dynamic + 0x10), 0x2000);
+    assert_eq!(u64_at(dynamic + 0x20), 6 * 0x10);
+    assert_eq!(u64_at(program_header(2) + 0x28), 0x60 + 0x100);
+
+    // Section headers are 0x40 bytes each
+    let section_header = |index: u64| u64_at(0x28) + index * 0x40;
+    let names = section_header(u16_at(0x3E) as u64);
+    let names = &elf[u64_at(names + 0x18) as usize..][..u64_at(names + 0x20) as usize];
+    let section = |name: &str| {
+        (0..u16_at(0x3C) as u64)
+            .map(section_header)
+            .find(|&header| crate::util::fixed_string(&names[u32_at(header) as usize..]) == name)
+            .unwrap()
+    };
+    let addr = |header: u64| u64_at(header + 0x10);
+    let size = |header: u64| u64_at(header + 0x20);
+
+    let text = section(".text");
+    assert_eq!(
+        &elf[u64_at(text + 0x18) as usize..][..size(text) as usize],
+        module.text.data
+    );
+    let dynsym = section(".dynsym");
+    assert_eq!(size(dynsym), 2 * 0x18);
+    assert_eq!(u32_at(dynsym + 0x2C), 1);
+    assert_eq!(addr(section_header(u32_at(dynsym + 0x28) as u64)), 0x1070);
+    assert_eq!(addr(section(".dynamic")), 0x2000);
+    assert_eq!(size(section(".hash")), (2 + 1 + 2) * 4);
+    assert_eq!(size(section(".bss")), 0x100);
+}
+
+/// Build an NRO from [`build_module`], with an asset section
+pub fn build_nro(icon: &[u8], nacp: &[u8], romfs: &[u8]) -> Vec<u8> {
+    let module = build_module();
+    let mut nro = module.to_flat().unwrap();
+    let size = nro.len() as u32;
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&nro[..0x10]);
+    header.extend_from_slice(b"NRO0");
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&size.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    // .text includes the header, and runs up to .rodata
+    for (offset, size) in [
+        (0, module.rodata.memory_offset),
+        (module.rodata.memory_offset, module.rodata.data.len() as u32),
+        (module.data.memory_offset, module.data.data.len() as u32),
+    ] {
+        header.extend_from_slice(&offset.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+    }
+    header.extend_from_slice(&module.bss_size.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&[0xB1; 0x20]);
+    header.resize(0x80, 0);
+
+    nro[..0x80].copy_from_slice(&header);
+
+    let mut offset = 0x38u64;
+    let mut assets = Vec::from(*b"ASET");
+    assets.extend_from_slice(&0u32.to_le_bytes());
+    for asset in [icon, nacp, romfs] {
+        assets.extend_from_slice(&offset.to_le_bytes());
+        assets.extend_from_slice(&(asset.len() as u64).to_le_bytes());
+        offset += asset.len() as u64;
+    }
+    for asset in [icon, nacp, romfs] {
+        assets.extend_from_slice(asset);
+    }
+    nro.extend_from_slice(&assets);
+    nro
+}
+
+/// Build a common ticket for a rights ID, with the title key in the clear
+pub fn build_common_ticket(rights_id: [u8; 0x10], title_key: [u8; 0x10]) -> Vec<u8> {
+    // Title key at 0x180, key type at 0x281, rights ID at 0x2A0
+    let mut tik = vec![0u8; 0x2C0];
+    tik[..4].copy_from_slice(&0x10004u32.to_le_bytes());
+    tik[0x180..0x190].copy_from_slice(&title_key);
+    tik[0x2A0..0x2B0].copy_from_slice(&rights_id);
+    tik
+}
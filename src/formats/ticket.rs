@@ -0,0 +1,192 @@
+//! Tickets
+//!
+//! A ticket (`.tik`) grants the rights to content with a given rights ID, and carries the
+//! encrypted title key needed to decrypt it. NSPs bundle the ticket of every rights ID
+//! their NCAs use.
+//!
+//! Only common tickets can be used offline: personalized tickets encrypt the title key
+//! with the console's RSA key.
+
+use binrw::prelude::*;
+use std::io::{Cursor, Read, Seek};
+
+use crate::error::Error;
+use crate::formats::TitleKeys;
+use crate::formats::keyset::RedactedKey;
+use crate::util::fixed_string;
+
+/// How the signature block at the start of a ticket is made
+#[binrw]
+#[brw(little, repr = u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureType {
+    Rsa4096Sha1 = 0x10000,
+    Rsa2048Sha1 = 0x10001,
+    EcdsaSha1 = 0x10002,
+    Rsa4096Sha256 = 0x10003,
+    Rsa2048Sha256 = 0x10004,
+    EcdsaSha256 = 0x10005,
+}
+
+impl SignatureType {
+    /// Size of the signature itself
+    pub fn signature_size(self) -> usize {
+        match self {
+            SignatureType::Rsa4096Sha1 | SignatureType::Rsa4096Sha256 => 0x200,
+            SignatureType::Rsa2048Sha1 | SignatureType::Rsa2048Sha256 => 0x100,
+            SignatureType::EcdsaSha1 | SignatureType::EcdsaSha256 => 0x3C,
+        }
+    }
+
+    /// Size of the padding between the signature and the signed data
+    pub fn padding_size(self) -> usize {
+        match self {
+            SignatureType::EcdsaSha1 | SignatureType::EcdsaSha256 => 0x40,
+            _ => 0x3C,
+        }
+    }
+}
+
+/// How the title key in a ticket is encrypted
+#[binrw]
+#[brw(little, repr = u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TitleKeyType {
+    /// Encrypted with the title KEK, usable by any console
+    Common = 0x00,
+    /// Encrypted with a console's RSA key
+    Personalized = 0x01,
+}
+
+/// A ticket, as found in NSPs
+#[binrw]
+#[brw(little)]
+pub struct Ticket {
+    pub signature_type: SignatureType,
+    #[br(count = signature_type.signature_size())]
+    #[bw(pad_after = signature_type.padding_size())]
+    pub signature: Vec<u8>,
+    #[br(pad_before = signature_type.padding_size())]
+    pub issuer: [u8; 0x40],
+    /// For common tickets, the encrypted title key is the first 0x10 bytes
+    pub title_key_block: [u8; 0x100],
+    pub format_version: u8,
+    pub title_key_type: TitleKeyType,
+    pub ticket_version: u16,
+    pub license_type: u8,
+    /// Key generation the title key is encrypted for
+    pub master_key_revision: u8,
+    pub properties: u16,
+    pub _reserved: [u8; 8],
+    pub ticket_id: u64,
+    pub device_id: u64,
+    pub rights_id: [u8; 0x10],
+    pub account_id: u32,
+    pub section_total_size: u32,
+    pub section_header_offset: u32,
+    pub section_header_count: u16,
+    pub section_header_entry_size: u16,
+}
+
+impl std::fmt::Debug for Ticket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ticket")
+            .field("signature_type", &self.signature_type)
+            .field("issuer", &self.issuer_string())
+            .field("title_key_block", &RedactedKey(&self.title_key_block))
+            .field("title_key_type", &self.title_key_type)
+            .field("master_key_revision", &self.master_key_revision)
+            .field("rights_id", &self.rights_id_string())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Ticket {
+    /// Parse a ticket
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<Self, Error> {
+        Ok(reader.read_le()?)
+    }
+
+    /// Issuer of the ticket, such as `Root-CA00000003-XS00000020`
+    pub fn issuer_string(&self) -> String {
+        fixed_string(&self.issuer)
+    }
+
+    /// Rights ID as uppercase hex, as used in `title.keys`
+    pub fn rights_id_string(&self) -> String {
+        hex::encode_upper(self.rights_id)
+    }
+
+    /// The encrypted title key of a common ticket
+    ///
+    /// # Errors
+    /// [`Error::NotSupported`] for personalized tickets.
+    pub fn title_key(&self) -> Result<[u8; 0x10], Error> {
+        match self.title_key_type {
+            TitleKeyType::Common => Ok(self.title_key_block[..0x10]
+                .try_into()
+                .expect("Slice length doesn't match array length")),
+            TitleKeyType::Personalized => Err(Error::NotSupported(format!(
+                "Ticket for {} is personalized",
+                self.rights_id_string()
+            ))),
+        }
+    }
+}
+
+/// Add the title key of a ticket bundled in a container to `title_keys`
+///
+/// A title key already known for the rights ID is kept. Personalized tickets are skipped
+/// with a warning, as their title key can't be decrypted offline.
+pub(crate) fn add_bundled_title_key(
+    title_keys: &mut TitleKeys,
+    name: &str,
+    data: Vec<u8>,
+) -> Result<(), Error> {
+    let ticket = Ticket::from_reader(&mut Cursor::new(data))?;
+    let rights_id = ticket.rights_id_string();
+    match ticket.title_key() {
+        Ok(title_key) if title_keys.get_title_key(&rights_id).is_none() => {
+            title_keys.add_title_key(&rights_id, title_key.to_vec())
+        }
+        Ok(_) => {}
+        Err(e) => tracing::warn!("Skipping ticket {}: {}", name, e),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ticket() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&0x10004u32.to_le_bytes());
+        data.extend_from_slice(&[0xAA; 0x100]);
+        data.extend_from_slice(&[0; 0x3C]);
+        let mut issuer = [0u8; 0x40];
+        issuer[..26].copy_from_slice(b"Root-CA00000003-XS00000020");
+        data.extend_from_slice(&issuer);
+        data.extend_from_slice(&[0x11; 0x10]);
+        data.extend_from_slice(&[0; 0xF0]);
+        data.extend_from_slice(&[2, 0, 0, 0, 0, 0x0A]);
+        data.resize(0x140 + 0x160, 0);
+        data.extend_from_slice(&hex_literal::hex!("0100000000001000000000000000000a"));
+        data.resize(0x2C0, 0);
+
+        let ticket = Ticket::from_reader(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(ticket.signature_type, SignatureType::Rsa2048Sha256);
+        assert_eq!(ticket.issuer_string(), "Root-CA00000003-XS00000020");
+        assert_eq!(ticket.master_key_revision, 0x0A);
+        assert_eq!(
+            ticket.rights_id_string(),
+            "0100000000001000000000000000000A"
+        );
+        assert_eq!(ticket.title_key().unwrap(), [0x11; 0x10]);
+
+        let mut written = Cursor::new(Vec::new());
+        ticket.write_le(&mut written).unwrap();
+        assert_eq!(written.into_inner(), data);
+    }
+}
@@ -1,7 +1,8 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
-use std::io::{self, BufRead};
-use std::path::Path;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
 
 use aes::Aes128;
 use aes::cipher::generic_array::GenericArray;
@@ -9,6 +10,14 @@ use cipher::{BlockDecrypt, KeyInit};
 use hex::decode as hex_decode;
 use thiserror::Error;
 use tracing::{info, warn};
+use zeroize::Zeroizing;
+
+use crate::formats::keyset::{
+    KeyConflict, KeyMergeReport, RedactedKey, default_key_paths, load_first_existing,
+};
+
+/// Environment variable that overrides where [`TitleKeys::load_default`] looks for `title.keys`
+pub const TITLE_KEYS_PATH_ENV: &str = "NX_ARCHIVE_TITLE_KEYS";
 
 #[derive(Error, Debug)]
 pub enum KeyError {
@@ -23,15 +32,48 @@ pub enum KeyError {
 
     #[error("Key not found: {0}")]
     KeyNotFound(String),
+
+    #[error(
+        "No {file} found in default locations, tried: {}",
+        .tried.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    NotFoundInDefaultLocations { file: String, tried: Vec<PathBuf> },
+
+    #[error(
+        "Failed to load {file}: {}",
+        .failures.iter().map(|(p, e)| format!("{}: {}", p.display(), e)).collect::<Vec<_>>().join("; ")
+    )]
+    LoadFailed {
+        file: String,
+        failures: Vec<(PathBuf, KeyError)>,
+    },
 }
 
 /// Stores title keys for decryption
-#[derive(Default, Debug)]
+#[derive(Default)]
 pub struct TitleKeys {
-    keys: HashMap<String, Vec<u8>>,
+    keys: HashMap<String, Zeroizing<Vec<u8>>>,
     loaded_file: Option<String>,
 }
 
+impl fmt::Debug for TitleKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut entries: Vec<_> = self.keys.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+
+        f.debug_struct("TitleKeys")
+            .field(
+                "keys",
+                &entries
+                    .into_iter()
+                    .map(|(rights_id, key)| (rights_id, RedactedKey(key)))
+                    .collect::<Vec<_>>(),
+            )
+            .field("loaded_file", &self.loaded_file)
+            .finish()
+    }
+}
+
 impl TitleKeys {
     /// Create a new empty TitleKeys instance
     pub fn new() -> Self {
@@ -43,12 +85,13 @@ impl TitleKeys {
 
     /// Add a title key to the database
     pub fn add_title_key(&mut self, rights_id: &str, key: Vec<u8>) {
-        self.keys.insert(rights_id.to_uppercase(), key);
+        self.keys
+            .insert(rights_id.to_uppercase(), Zeroizing::new(key));
     }
 
     /// Get a title key by rights ID
     pub fn get_title_key(&self, rights_id: &str) -> Option<&Vec<u8>> {
-        self.keys.get(&rights_id.to_uppercase())
+        self.keys.get(&rights_id.to_uppercase()).map(|key| &**key)
     }
 
     /// Decrypt a title key using the title KEK
@@ -137,40 +180,71 @@ impl TitleKeys {
         Ok(keys)
     }
 
-    /// Load title keys from the default location: ~/.switch/title.keys
+    /// Load title keys from the default locations
+    ///
+    /// If the `NX_ARCHIVE_TITLE_KEYS` environment variable is set, only that path is tried.
+    /// Otherwise `title.keys` is looked up in `~/.switch`, `$XDG_CONFIG_HOME/switch`
+    /// (default `~/.config/switch`) and the current directory, in that order.
+    ///
+    /// # Errors
+    /// See [`Keyset::load_default`](crate::formats::Keyset::load_default).
     pub fn load_default() -> Result<Self, KeyError> {
-        let home_dir = dirs::home_dir();
-
-        let possible_paths = vec![
-            // ~/.switch/title.keys
-            home_dir
-                .as_ref()
-                .map(|h| h.join(".switch").join("title.keys")),
-            // Current directory title.keys
-            Some(Path::new("title.keys").to_path_buf()),
-        ];
-
-        let mut last_error = None;
-
-        for path in possible_paths.into_iter().flatten() {
-            if path.exists() {
-                match Self::load_from_file(&path) {
-                    Ok(keys) => {
-                        info!("Successfully loaded title keys from {}", path.display());
-                        return Ok(keys);
-                    }
-                    Err(e) => {
-                        warn!("Failed to load title keys from {}: {}", path.display(), e);
-                        last_error = Some(e);
-                    }
+        let possible_paths = default_key_paths(&["title.keys"], TITLE_KEYS_PATH_ENV);
+        load_first_existing(possible_paths, "title.keys", |path| {
+            Self::load_from_file(path)
+        })
+    }
+
+    /// Write the title keys out in the `title.keys` format, sorted by rights ID
+    pub fn write_to(&self, mut writer: impl Write) -> Result<(), KeyError> {
+        let mut rights_ids: Vec<&String> = self.keys.keys().collect();
+        rights_ids.sort();
+
+        for rights_id in rights_ids {
+            writeln!(
+                writer,
+                "{} = {}",
+                rights_id.to_lowercase(),
+                hex::encode(&self.keys[rights_id])
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Merge the title keys from another database into this one
+    ///
+    /// Rights IDs missing from this database are added. Rights IDs present in both
+    /// with different keys are left untouched and reported as conflicts.
+    pub fn merge(&mut self, other: &TitleKeys) -> KeyMergeReport {
+        let mut report = KeyMergeReport::default();
+
+        let mut rights_ids: Vec<&String> = other.keys.keys().collect();
+        rights_ids.sort();
+
+        for rights_id in rights_ids {
+            let incoming = &other.keys[rights_id];
+            match self.keys.get(rights_id) {
+                Some(existing) if existing != incoming => {
+                    warn!(
+                        "Conflicting title keys for rights ID {}, keeping existing",
+                        rights_id
+                    );
+                    report.conflicts.push(KeyConflict {
+                        name: rights_id.clone(),
+                        existing: existing.clone(),
+                        incoming: incoming.clone(),
+                    });
+                }
+                Some(_) => {}
+                None => {
+                    self.keys.insert(rights_id.clone(), incoming.clone());
+                    report.added.push(rights_id.clone());
                 }
             }
         }
 
-        // If we get here, we couldn't find/load any title keys
-        Err(last_error.unwrap_or_else(|| {
-            KeyError::InvalidFormat("No title.keys file found in default locations".to_string())
-        }))
+        report
     }
 
     /// Get the number of keys in the database
@@ -219,4 +293,39 @@ mod tests {
         assert_eq!(keys.get_title_key("ABC123").unwrap(), &vec![0; 16]);
         assert_eq!(keys.get_title_key("abc123").unwrap(), &vec![0; 16]);
     }
+
+    #[test]
+    fn test_write_to_is_sorted() {
+        let mut keys = TitleKeys::new();
+
+        keys.add_title_key("0100000000000000000000000000000b", vec![0xBB; 16]);
+        keys.add_title_key("0100000000000000000000000000000a", vec![0xAA; 16]);
+
+        let mut out = Vec::new();
+        keys.write_to(&mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "0100000000000000000000000000000a = aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\n\
+             0100000000000000000000000000000b = bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_reports_conflicts() {
+        let mut keys = TitleKeys::new();
+        keys.add_title_key("foo", vec![0; 16]);
+
+        let mut other = TitleKeys::new();
+        other.add_title_key("foo", vec![1; 16]);
+        other.add_title_key("bar", vec![2; 16]);
+
+        let report = keys.merge(&other);
+
+        assert_eq!(report.added, vec!["BAR".to_string()]);
+        assert_eq!(report.conflicts.len(), 1);
+        assert_eq!(report.conflicts[0].name, "FOO");
+        assert_eq!(keys.get_title_key("foo").unwrap(), &vec![0; 16]);
+        assert_eq!(keys.len(), 2);
+    }
 }
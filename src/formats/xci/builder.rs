@@ -0,0 +1,309 @@
+//! Game card image builder
+//!
+//! [`XciBuilder`] assembles an XCI from NCAs. The image is laid out like a dumped card:
+//!
+//! | Offset   | Contents                                             |
+//! |----------|------------------------------------------------------|
+//! | 0x0      | [`XciHeader`]                                        |
+//! | 0x200    | Padding, including the (empty) gamecard certificate  |
+//! | 0xF000   | Root HFS0, pointing to the `update`, `logo`, `normal` and `secure` partitions |
+//!
+//! The header signature, the encrypted card info and the encrypted title key in the
+//! initial data are left zeroed, since producing them needs Nintendo's private keys. Built
+//! images are therefore only useful for tools and loaders that don't check them.
+
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use super::{
+    GameCardFlags, KEY_AREA_SIZE, KeyArea, MEDIA_SIZE, PADDING_BYTE, RomSize, XciHeader,
+    copy_exact, write_padding,
+};
+use crate::error::Error;
+use crate::formats::hfs0::Hfs0Builder;
+
+/// Offset of the root HFS0 in a built image
+pub const ROOT_HFS0_OFFSET: u64 = 0xF000;
+
+/// Size of the region hashed for each NCA in a partition
+const NCA_HASHED_REGION_SIZE: u64 = 0x200;
+
+/// A partition of the root HFS0, in the order they are laid out in the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum XciPartitionKind {
+    /// System update content
+    Update,
+    /// Logo content, only present on newer cards
+    Logo,
+    /// Content readable without the card being authenticated
+    Normal,
+    /// The game itself
+    Secure,
+}
+
+impl XciPartitionKind {
+    /// Name of the partition in the root HFS0
+    pub fn name(self) -> &'static str {
+        match self {
+            XciPartitionKind::Update => "update",
+            XciPartitionKind::Logo => "logo",
+            XciPartitionKind::Normal => "normal",
+            XciPartitionKind::Secure => "secure",
+        }
+    }
+}
+
+/// Builds an XCI from NCAs
+///
+/// # Example
+/// ```no_run
+/// # use std::fs::File;
+/// # use nx_archive::formats::xci::{XciBuilder, XciPartitionKind};
+/// let mut builder = XciBuilder::new(0x0123456789ABCDEF);
+/// builder.add_nca(
+///     XciPartitionKind::Secure,
+///     "0123456789abcdef0123456789abcdef.nca",
+///     File::open("program.nca").unwrap(),
+/// );
+/// builder.write(File::create("game.xci").unwrap()).unwrap();
+/// ```
+pub struct XciBuilder<R: Read + Seek> {
+    package_id: u64,
+    key_area: bool,
+    partitions: BTreeMap<XciPartitionKind, Vec<(String, R)>>,
+}
+
+impl<R: Read + Seek> XciBuilder<R> {
+    /// Create a builder for a card with the given package ID
+    pub fn new(package_id: u64) -> Self {
+        let mut partitions = BTreeMap::new();
+        // These are present on every card, even when empty
+        partitions.insert(XciPartitionKind::Update, Vec::new());
+        partitions.insert(XciPartitionKind::Normal, Vec::new());
+        partitions.insert(XciPartitionKind::Secure, Vec::new());
+
+        Self {
+            package_id,
+            key_area: false,
+            partitions,
+        }
+    }
+
+    /// Prepend a 0x1000 byte key area, like a "full" XCI
+    ///
+    /// Without it, the image starts directly with the header.
+    pub fn with_key_area(mut self, key_area: bool) -> Self {
+        self.key_area = key_area;
+        self
+    }
+
+    /// Add an NCA to a partition
+    pub fn add_nca(
+        &mut self,
+        partition: XciPartitionKind,
+        name: impl Into<String>,
+        reader: R,
+    ) -> &mut Self {
+        self.partitions
+            .entry(partition)
+            .or_default()
+            .push((name.into(), reader));
+        self
+    }
+
+    /// Write the trimmed image, returning the number of bytes written
+    pub fn write<W: Write>(mut self, mut writer: W) -> Result<u64, Error> {
+        // Lay out each partition
+        let mut partition_layouts = Vec::new();
+        for (kind, files) in &mut self.partitions {
+            let mut hfs0 = Hfs0Builder::new();
+            let mut file_offsets = Vec::with_capacity(files.len());
+
+            for (name, reader) in files.iter_mut() {
+                let size = reader.seek(SeekFrom::End(0))?;
+                let hashed_size = size.min(NCA_HASHED_REGION_SIZE);
+
+                let mut hashed = vec![0u8; hashed_size as usize];
+                reader.seek(SeekFrom::Start(0))?;
+                reader.read_exact(&mut hashed)?;
+
+                let offset = hfs0.add_file(
+                    name,
+                    size,
+                    hashed_size as u32,
+                    Sha256::digest(&hashed).into(),
+                );
+                file_offsets.push((offset, size));
+            }
+
+            partition_layouts.push((*kind, hfs0, file_offsets));
+        }
+
+        // Lay out the root HFS0 pointing to each partition
+        let mut root = Hfs0Builder::new();
+        let mut partition_offsets = Vec::with_capacity(partition_layouts.len());
+        for (kind, hfs0, _) in &partition_layouts {
+            let header = hfs0.header().to_bytes();
+            let offset = root.add_file(
+                kind.name(),
+                hfs0.total_size(),
+                header.len() as u32,
+                Sha256::digest(&header).into(),
+            );
+            partition_offsets.push(offset);
+        }
+
+        let root_header = root.header().to_bytes();
+        let data_start = ROOT_HFS0_OFFSET + root_header.len() as u64;
+        let image_size = (data_start + root.data_size()).next_multiple_of(MEDIA_SIZE);
+
+        let rom_size = RomSize::smallest_fitting(image_size).ok_or_else(|| {
+            Error::InvalidData(format!(
+                "Image is 0x{:X} bytes, too large for any game card",
+                image_size
+            ))
+        })?;
+
+        let secure_offset = partition_layouts
+            .iter()
+            .zip(&partition_offsets)
+            .find(|((kind, _, _), _)| *kind == XciPartitionKind::Secure)
+            .map(|(_, offset)| data_start + offset)
+            .unwrap_or(image_size);
+
+        let key_area = KeyArea::new(self.package_id);
+        let header = XciHeader {
+            signature: [0; 0x100],
+            rom_area_offset: (ROOT_HFS0_OFFSET / MEDIA_SIZE) as u32,
+            backup_area_offset: 0xFFFFFFFF,
+            title_kek_index: 0,
+            rom_size,
+            gamecard_header_version: 0,
+            // No flags set
+            gamecard_flags: GameCardFlags::AutoBoot,
+            package_id: self.package_id,
+            valid_data_end_address: (image_size / MEDIA_SIZE - 1) as u32,
+            _reserved: 0,
+            gamecard_flags2: 0,
+            application_id_list_entry_count: 0,
+            reversed_iv: [0; 0x10],
+            hfs0_offset: ROOT_HFS0_OFFSET,
+            hfs0_header_size: root_header.len() as u64,
+            hfs0_header_hash: Sha256::digest(&root_header).into(),
+            initial_data_hash: key_area.initial_data_hash(),
+            sel_sec: 1,
+            sel_t1_key: 2,
+            sel_key: 0,
+            lim_area: (secure_offset / MEDIA_SIZE) as u32,
+            card_header_encrypted: [0; 0x70],
+        };
+
+        // Write everything out
+        let mut written = 0;
+        if self.key_area {
+            writer.write_all(&key_area.to_bytes())?;
+        }
+
+        let header_bytes = header.to_bytes();
+        writer.write_all(&header_bytes)?;
+        write_padding(
+            &mut writer,
+            PADDING_BYTE,
+            ROOT_HFS0_OFFSET - header_bytes.len() as u64,
+        )?;
+        writer.write_all(&root_header)?;
+
+        for ((kind, hfs0, file_offsets), partition_offset) in
+            partition_layouts.iter().zip(&partition_offsets)
+        {
+            write_padding(&mut writer, 0, *partition_offset - written)?;
+            written = *partition_offset;
+
+            let partition_header = hfs0.header().to_bytes();
+            writer.write_all(&partition_header)?;
+            written += partition_header.len() as u64;
+
+            let files = self
+                .partitions
+                .get_mut(kind)
+                .expect("partition was laid out");
+            let file_data_start = written;
+            for ((_, reader), (offset, size)) in files.iter_mut().zip(file_offsets) {
+                write_padding(&mut writer, 0, file_data_start + offset - written)?;
+                reader.seek(SeekFrom::Start(0))?;
+                copy_exact(reader, &mut writer, *size)?;
+                written = file_data_start + offset + size;
+            }
+        }
+
+        write_padding(&mut writer, 0, image_size - data_start - written)?;
+
+        let total = image_size + if self.key_area { KEY_AREA_SIZE } else { 0 };
+        tracing::trace!("Built XCI of 0x{:X} bytes ({:?})", total, rom_size);
+        Ok(total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::xci::Xci;
+    use std::io::Cursor;
+
+    fn test_nca(size: usize, fill: u8) -> Cursor<Vec<u8>> {
+        Cursor::new(vec![fill; size])
+    }
+
+    #[test]
+    fn test_build_xci() {
+        for with_key_area in [false, true] {
+            let mut builder = XciBuilder::new(0x0123456789ABCDEF).with_key_area(with_key_area);
+            builder
+                .add_nca(XciPartitionKind::Secure, "a.nca", test_nca(0x400, 0xAA))
+                .add_nca(
+                    XciPartitionKind::Secure,
+                    "b.cnmt.nca",
+                    test_nca(0x310, 0xBB),
+                )
+                .add_nca(XciPartitionKind::Normal, "c.nca", test_nca(0x200, 0xCC));
+
+            let mut image = Vec::new();
+            let size = builder.write(&mut image).unwrap();
+            assert_eq!(size, image.len() as u64);
+
+            let mut xci = Xci::new(Cursor::new(image)).unwrap();
+            assert_eq!(xci.key_area.is_some(), with_key_area);
+            assert_eq!(xci.verify_initial_data(), with_key_area.then_some(true));
+            assert_eq!(xci.header.package_id, 0x0123456789ABCDEF);
+            assert_eq!(xci.header.rom_size, RomSize::Size1Gb);
+            assert_eq!(xci.image_offset() + xci.trimmed_size(), size);
+
+            let root = xci.list_hfs0_partitions().unwrap();
+            let names: Vec<_> = root
+                .list_files()
+                .unwrap()
+                .into_iter()
+                .map(|f| f.name)
+                .collect();
+            assert_eq!(names, ["update", "normal", "secure"]);
+
+            let root_header = root.header.to_bytes();
+            assert_eq!(root_header.len() as u64, xci.header.hfs0_header_size);
+            assert_eq!(
+                <[u8; 0x20]>::from(Sha256::digest(&root_header)),
+                xci.header.hfs0_header_hash
+            );
+
+            let mut secure = xci.open_secure_partition().unwrap().unwrap();
+            let files = secure.list_files().unwrap();
+            assert_eq!(files.len(), 2);
+            assert_eq!(secure.read_to_vec(&files[0]).unwrap(), vec![0xAA; 0x400]);
+            assert_eq!(secure.read_to_vec(&files[1]).unwrap(), vec![0xBB; 0x310]);
+            assert_eq!(
+                files[1].hash,
+                <[u8; 0x20]>::from(Sha256::digest([0xBB; 0x200]))
+            );
+        }
+    }
+}
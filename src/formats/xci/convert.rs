@@ -0,0 +1,489 @@
+//! Conversion between XCIs and NSPs
+//!
+//! A game card keeps its content in the `secure` partition. [`Xci::to_nsp`] streams that
+//! partition into a single NSP, while [`Xci::to_split_nsps`] writes one NSP per
+//! application, patch or add-on content meta, for cards that bundle several titles.
+//!
+//! NCAs dumped from a card are marked with [`DistributionType::GameCard`], which some
+//! installers refuse. Both converters can rewrite that to [`DistributionType::Download`],
+//! re-encrypting the header with the header key. This changes the NCA, so its header
+//! signature and the hash recorded for it in the CNMT no longer match.
+//!
+//! Going the other way, [`XciBuilder::from_nsps`] lays out the NCAs of one or more NSPs in
+//! the secure partition of a new card. Cards have no tickets, so NCAs using title key
+//! crypto are converted to key area crypto with the title keys from the bundled tickets,
+//! and every NCA is marked as game card content. This invalidates the same signatures
+//! and hashes.
+
+use aes::Aes128;
+use aes::cipher::generic_array::GenericArray;
+use cipher::{BlockEncrypt, KeyInit};
+use std::collections::HashSet;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use zeroize::Zeroize;
+
+use super::{Xci, XciBuilder, XciPartitionKind, copy_exact, read_cnmt_nca};
+use crate::error::Error;
+use crate::formats::cnmt::{Cnmt, ContentMetaType};
+use crate::formats::hfs0::{Hfs0, Hfs0File};
+use crate::formats::nca::{
+    DistributionType, KeyArea, KeyAreaEncryptionKeyIndex, NcaHeader, decrypt_with_header_key,
+};
+use crate::formats::pfs0::{Pfs0, Pfs0Builder};
+use crate::formats::ticket::Ticket;
+use crate::formats::{Keyset, TitleKeys};
+use crate::io::SubFile;
+
+/// Size of the encrypted NCA header, including the section headers
+const NCA_FULL_HEADER_SIZE: usize = 0xC00;
+
+impl<R: Read + Seek> Xci<R> {
+    /// Write every file in the secure partition into a single NSP
+    ///
+    /// If `rewrite_distribution` is set, NCA headers marked as game card content are
+    /// re-encrypted as download content, which needs the header key. Returns the number
+    /// of bytes written.
+    pub fn to_nsp<W: Write>(
+        &mut self,
+        writer: W,
+        keyset: &Keyset,
+        rewrite_distribution: bool,
+    ) -> Result<u64, Error> {
+        let mut secure = self
+            .open_secure_partition()?
+            .ok_or_else(|| Error::NotFound("secure partition".to_string()))?;
+
+        let files = secure.list_files()?;
+        let files: Vec<&Hfs0File> = files.iter().collect();
+        write_nsp(&mut secure, &files, writer, keyset, rewrite_distribution)
+    }
+
+    /// Write one NSP per application, patch or add-on content meta in the secure partition
+    ///
+    /// Each NSP holds the CNMT NCA and every content it lists. `create_writer` is called
+    /// with the parsed CNMT to open the output for it. Returns the number of NSPs written.
+    pub fn to_split_nsps<W, F>(
+        &mut self,
+        keyset: &Keyset,
+        title_keys: Option<&TitleKeys>,
+        rewrite_distribution: bool,
+        mut create_writer: F,
+    ) -> Result<usize, Error>
+    where
+        W: Write,
+        F: FnMut(&Cnmt) -> Result<W, Error>,
+    {
+        let mut secure = self
+            .open_secure_partition()?
+            .ok_or_else(|| Error::NotFound("secure partition".to_string()))?;
+        let files = secure.list_files()?;
+
+        let mut written = 0;
+        for cnmt_file in files.iter().filter(|f| f.name.ends_with(".cnmt.nca")) {
+            let cnmt = read_cnmt_nca(secure.read_to_vec(cnmt_file)?, keyset, title_keys)?;
+            if !matches!(
+                cnmt.header.meta_type,
+                ContentMetaType::Application
+                    | ContentMetaType::Patch
+                    | ContentMetaType::AddOnContent
+            ) {
+                tracing::debug!(
+                    "Skipping {:?} meta {}",
+                    cnmt.header.meta_type,
+                    cnmt.get_title_id_string()
+                );
+                continue;
+            }
+
+            let mut nsp_files = vec![cnmt_file];
+            for content in &cnmt.content_entries {
+                let name = format!("{}.nca", hex::encode(content.info.content_id));
+                match files.iter().find(|f| f.name == name) {
+                    Some(file) if !nsp_files.iter().any(|f| f.name == name) => nsp_files.push(file),
+                    Some(_) => {}
+                    None => tracing::warn!(
+                        "Content {} of {} is not in the secure partition",
+                        name,
+                        cnmt.get_title_id_string()
+                    ),
+                }
+            }
+
+            let writer = create_writer(&cnmt)?;
+            write_nsp(
+                &mut secure,
+                &nsp_files,
+                writer,
+                keyset,
+                rewrite_distribution,
+            )?;
+            written += 1;
+        }
+
+        Ok(written)
+    }
+}
+
+/// An NCA from an NSP, with its header rewritten for a game card
+///
+/// Reads like the original NCA, except for the rewritten header.
+pub struct CardNca<R: Read + Seek> {
+    header: Vec<u8>,
+    inner: SubFile<R>,
+}
+
+impl<R: Read + Seek> Read for CardNca<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let position = self.inner.position();
+        let read = self.inner.read(buf)?;
+
+        if let Some(header) = self.header.get(position as usize..) {
+            let overlap = read.min(header.len());
+            buf[..overlap].copy_from_slice(&header[..overlap]);
+        }
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for CardNca<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl<R: Read + Seek + Clone> XciBuilder<CardNca<R>> {
+    /// Create a builder for a card holding the content of one or more NSPs
+    ///
+    /// Every NCA is added to the secure partition, in the order of the NSPs. Title keys
+    /// are taken from the tickets in the NSPs, so the keyset needs the title KEKs and
+    /// application key area keys of the NCAs' key generations, as well as the header key.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use std::fs::File;
+    /// # use nx_archive::formats::{Keyset, pfs0::Pfs0, xci::XciBuilder};
+    /// # use nx_archive::io::SharedReader;
+    /// let keyset = Keyset::load_default().unwrap();
+    /// let mut nsps = vec![
+    ///     Pfs0::from_shared(SharedReader::new(File::open("base.nsp").unwrap())).unwrap(),
+    ///     Pfs0::from_shared(SharedReader::new(File::open("update.nsp").unwrap())).unwrap(),
+    /// ];
+    /// let builder = XciBuilder::from_nsps(0x0123456789ABCDEF, &mut nsps, &keyset).unwrap();
+    /// builder.write(File::create("game.xci").unwrap()).unwrap();
+    /// ```
+    pub fn from_nsps(
+        package_id: u64,
+        nsps: &mut [Pfs0<R>],
+        keyset: &Keyset,
+    ) -> Result<Self, Error> {
+        let mut title_keys = TitleKeys::new();
+        for nsp in nsps.iter_mut() {
+            for file in nsp.list_files()? {
+                if !file.name.ends_with(".tik") {
+                    continue;
+                }
+                let ticket = Ticket::from_reader(&mut Cursor::new(nsp.read_to_vec(&file)?))?;
+                match ticket.title_key() {
+                    Ok(title_key) => {
+                        title_keys.add_title_key(&ticket.rights_id_string(), title_key.to_vec())
+                    }
+                    Err(e) => tracing::warn!("Skipping ticket {}: {}", file.name, e),
+                }
+            }
+        }
+
+        let mut builder = XciBuilder::new(package_id);
+        let mut names = HashSet::new();
+        for nsp in nsps.iter_mut() {
+            for file in nsp.list_files()? {
+                if !file.name.ends_with(".nca") {
+                    continue;
+                }
+                if !names.insert(file.name.clone()) {
+                    tracing::warn!("Skipping duplicate NCA {}", file.name);
+                    continue;
+                }
+                if file.size < NCA_FULL_HEADER_SIZE as u64 {
+                    return Err(Error::InvalidData(format!(
+                        "{} is too small to be an NCA",
+                        file.name
+                    )));
+                }
+
+                let mut inner = nsp.subfile(&file);
+                let mut encrypted = vec![0u8; NCA_FULL_HEADER_SIZE];
+                inner.read_exact(&mut encrypted)?;
+                inner.seek(SeekFrom::Start(0))?;
+
+                let header = rewrite_nca_header(&encrypted, keyset, |header| {
+                    to_card_header(header, keyset, &title_keys)
+                })?;
+                builder.add_nca(
+                    XciPartitionKind::Secure,
+                    file.name,
+                    CardNca { header, inner },
+                );
+            }
+        }
+
+        Ok(builder)
+    }
+}
+
+/// Mark an NCA header as game card content, moving its title key into the key area
+fn to_card_header(
+    header: &mut NcaHeader,
+    keyset: &Keyset,
+    title_keys: &TitleKeys,
+) -> Result<bool, Error> {
+    let has_rights_id = header.rights_id.iter().any(|&b| b != 0);
+    if !has_rights_id && header.distribution == DistributionType::GameCard {
+        return Ok(false);
+    }
+
+    if has_rights_id {
+        let rights_id = hex::encode_upper(header.rights_id);
+        let key_gen = header.get_key_generation() as usize;
+
+        let title_kek = keyset
+            .get_title_kek(key_gen)
+            .ok_or_else(|| Error::KeyLookupError(format!("No titlekek_{:02x}", key_gen)))?;
+        let key_area_key = keyset
+            .get_key_area_key_application(key_gen)
+            .ok_or_else(|| {
+                Error::KeyLookupError(format!("No key_area_key_application_{:02x}", key_gen))
+            })?;
+        let mut title_key = title_keys.decrypt_title_key(&rights_id, &title_kek)?;
+
+        // The title key takes the place of the AES-CTR key, the other keys are left empty
+        let mut key_area = [0u8; 0x40];
+        key_area[0x20..0x30].copy_from_slice(&title_key);
+        let cipher = Aes128::new(GenericArray::from_slice(&key_area_key));
+        for block in key_area.chunks_exact_mut(0x10) {
+            cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        }
+
+        header.encrypted_keys = KeyArea {
+            aes_xts_key: key_area[..0x20].try_into().unwrap(),
+            aes_ctr_key: key_area[0x20..0x30].try_into().unwrap(),
+            _reserved: key_area[0x30..].try_into().unwrap(),
+        };
+        header.key_area_appkey_index = KeyAreaEncryptionKeyIndex::Application;
+        header.rights_id = [0; 0x10];
+
+        title_key.zeroize();
+        key_area.zeroize();
+    }
+
+    header.distribution = DistributionType::GameCard;
+    Ok(true)
+}
+
+/// Stream files from an HFS0 partition into a new PFS0
+fn write_nsp<S: Read + Seek, W: Write>(
+    partition: &mut Hfs0<S>,
+    files: &[&Hfs0File],
+    mut writer: W,
+    keyset: &Keyset,
+    rewrite_distribution: bool,
+) -> Result<u64, Error> {
+    let mut builder = Pfs0Builder::new();
+    for file in files {
+        builder.add_file(&file.name, file.size);
+    }
+    writer.write_all(&builder.header_bytes())?;
+
+    for file in files {
+        partition.reader.seek(SeekFrom::Start(file.offset))?;
+
+        let is_nca = file.name.ends_with(".nca");
+        if rewrite_distribution && is_nca && file.size >= NCA_FULL_HEADER_SIZE as u64 {
+            let mut header = vec![0u8; NCA_FULL_HEADER_SIZE];
+            partition.reader.read_exact(&mut header)?;
+            writer.write_all(&rewrite_nca_distribution(&header, keyset)?)?;
+            copy_exact(
+                &mut partition.reader,
+                &mut writer,
+                file.size - NCA_FULL_HEADER_SIZE as u64,
+            )?;
+        } else {
+            copy_exact(&mut partition.reader, &mut writer, file.size)?;
+        }
+    }
+
+    Ok(builder.total_size())
+}
+
+/// Re-encrypt an NCA header marked as game card content as download content
+///
+/// Takes and returns the first 0xC00 encrypted bytes of the NCA.
+fn rewrite_nca_distribution(encrypted: &[u8], keyset: &Keyset) -> Result<Vec<u8>, Error> {
+    rewrite_nca_header(encrypted, keyset, |header| {
+        if header.distribution != DistributionType::GameCard {
+            return Ok(false);
+        }
+        header.distribution = DistributionType::Download;
+        Ok(true)
+    })
+}
+
+/// Decrypt, modify and re-encrypt an NCA header
+///
+/// Takes and returns the first 0xC00 encrypted bytes of the NCA. `modify` returns whether
+/// it changed the header, if not the input is returned as is. Only the main header is
+/// re-encrypted, the section headers are passed through untouched.
+fn rewrite_nca_header(
+    encrypted: &[u8],
+    keyset: &Keyset,
+    modify: impl FnOnce(&mut NcaHeader) -> Result<bool, Error>,
+) -> Result<Vec<u8>, Error> {
+    let decrypted = decrypt_with_header_key(&encrypted[..0x400], keyset, 0x200, 0)?;
+    let header_bytes: &[u8; 0x340] = decrypted[..0x340]
+        .try_into()
+        .expect("Slice length doesn't match array length");
+    let mut header = NcaHeader::from_bytes(header_bytes)?;
+
+    if !modify(&mut header)? {
+        return Ok(encrypted.to_vec());
+    }
+
+    // to_bytes_encrypt only knows about the parsed fields, so make sure the rest of the
+    // main header is empty before replacing it
+    if decrypted[0x340..0x400].iter().any(|&b| b != 0) {
+        return Err(Error::InvalidData(
+            "NCA header has data in its reserved area, refusing to rewrite it".to_string(),
+        ));
+    }
+
+    let mut rewritten = header.to_bytes_encrypt(keyset);
+    rewritten.truncate(0x400);
+    rewritten.extend_from_slice(&encrypted[0x400..]);
+    Ok(rewritten)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::nca::Nca;
+    use crate::formats::nca::test_header;
+    use crate::formats::ticket::{SignatureType, TitleKeyType};
+
+    #[test]
+    fn test_xci_to_nsp_rewrites_distribution() {
+        let keyset = Keyset {
+            header_key_cache: Some([0x11; 0x20].into()),
+            ..Default::default()
+        };
+
+        let mut header = test_header();
+        header.distribution = DistributionType::GameCard;
+        let mut nca = header.to_bytes_encrypt(&keyset);
+        nca.extend_from_slice(&[0xAB; 0x400]);
+
+        let mut builder = XciBuilder::new(0);
+        builder
+            .add_nca(XciPartitionKind::Secure, "a.nca", Cursor::new(nca.clone()))
+            .add_nca(
+                XciPartitionKind::Secure,
+                "b.bin",
+                Cursor::new(vec![7; 0x10]),
+            );
+        let mut image = Vec::new();
+        builder.write(&mut image).unwrap();
+
+        let mut xci = Xci::new(Cursor::new(image)).unwrap();
+        let mut nsp = Vec::new();
+        let size = xci.to_nsp(&mut nsp, &keyset, true).unwrap();
+        assert_eq!(size, nsp.len() as u64);
+
+        let mut pfs0 = Pfs0::from_reader(Cursor::new(nsp)).unwrap();
+        let b = pfs0.get_file("b.bin").unwrap();
+        assert_eq!(pfs0.read_to_vec(&b).unwrap(), vec![7; 0x10]);
+
+        let a = pfs0.get_file("a.nca").unwrap();
+        let converted = pfs0.read_to_vec(&a).unwrap();
+        assert_eq!(converted.len(), nca.len());
+        assert_eq!(converted[0x400..], nca[0x400..]);
+
+        let decrypted = decrypt_with_header_key(&converted[..0x400], &keyset, 0x200, 0).unwrap();
+        let header = NcaHeader::from_bytes(decrypted[..0x340].try_into().unwrap()).unwrap();
+        assert_eq!(header.distribution, DistributionType::Download);
+    }
+
+    #[test]
+    fn test_nsp_to_xci_moves_title_key() {
+        let key_gen = test_header().get_key_generation();
+        let keyset = Keyset::from_reader(Cursor::new(format!(
+            "header_key = {}\ntitlekek_{key_gen:02x} = {}\nkey_area_key_application_{key_gen:02x} = {}\n",
+            "11".repeat(0x20),
+            "22".repeat(0x10),
+            "33".repeat(0x10),
+        )))
+        .unwrap();
+
+        let rights_id = hex_literal::hex!("0100000000001000000000000000000a");
+        let title_key = [0x42; 0x10];
+        let mut encrypted_title_key = GenericArray::from(title_key);
+        Aes128::new(GenericArray::from_slice(&[0x22; 0x10]))
+            .encrypt_block(&mut encrypted_title_key);
+
+        let mut header = test_header();
+        header.rights_id = rights_id;
+        let mut nca = header.to_bytes_encrypt(&keyset);
+        nca.extend_from_slice(&[0xAB; 0x400]);
+
+        let mut title_key_block = [0; 0x100];
+        title_key_block[..0x10].copy_from_slice(&encrypted_title_key);
+        let ticket = Ticket {
+            signature_type: SignatureType::Rsa2048Sha256,
+            signature: vec![0; 0x100],
+            issuer: [0; 0x40],
+            title_key_block,
+            format_version: 2,
+            title_key_type: TitleKeyType::Common,
+            ticket_version: 0,
+            license_type: 0,
+            master_key_revision: key_gen,
+            properties: 0,
+            _reserved: [0; 8],
+            ticket_id: 0,
+            device_id: 0,
+            rights_id,
+            account_id: 0,
+            section_total_size: 0,
+            section_header_offset: 0x2C0,
+            section_header_count: 0,
+            section_header_entry_size: 0,
+        };
+        let mut tik = Cursor::new(Vec::new());
+        binrw::BinWrite::write_le(&ticket, &mut tik).unwrap();
+        let tik = tik.into_inner();
+
+        let mut pfs0 = Pfs0Builder::new();
+        pfs0.add_file("a.nca", nca.len() as u64);
+        pfs0.add_file("a.tik", tik.len() as u64);
+        let mut nsp = pfs0.header_bytes();
+        nsp.extend_from_slice(&nca);
+        nsp.extend_from_slice(&tik);
+
+        let mut nsps = [Pfs0::from_reader(Cursor::new(nsp)).unwrap()];
+        let builder = XciBuilder::from_nsps(1, &mut nsps, &keyset).unwrap();
+        let mut image = Vec::new();
+        builder.write(&mut image).unwrap();
+
+        let mut xci = Xci::new(Cursor::new(image)).unwrap();
+        let mut secure = xci.open_secure_partition().unwrap().unwrap();
+        let files = secure.list_files().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "a.nca");
+
+        let converted = secure.read_to_vec(&files[0]).unwrap();
+        assert_eq!(converted.len(), nca.len());
+        assert_eq!(converted[0x400..], nca[0x400..]);
+
+        let converted = Nca::from_reader(Cursor::new(converted), &keyset, None).unwrap();
+        assert!(!converted.has_rights_id());
+        assert_eq!(converted.header.distribution, DistributionType::GameCard);
+        assert_eq!(converted.get_aes_ctr_decrypt_key().unwrap(), title_key);
+    }
+}
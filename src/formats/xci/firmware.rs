@@ -0,0 +1,260 @@
+//! Firmware bundled on game cards
+//!
+//! The `update` partition of a card holds the system update needed to run the game. Its
+//! SystemUpdate meta lists every system title in the update, and the SystemVersion title
+//! holds the version string shown in the console's settings.
+
+use binrw::prelude::*;
+use std::io::{Cursor, Read, Seek};
+
+use super::{Xci, read_cnmt_nca};
+use crate::error::Error;
+use crate::formats::Keyset;
+use crate::formats::cnmt::{Cnmt, ContentMetaType, ExtendedHeader, PackagedContentType};
+use crate::formats::nca::Nca;
+use crate::util::fixed_string;
+
+/// Title ID of the SystemVersion system data archive
+pub const SYSTEM_VERSION_TITLE_ID: u64 = 0x0100000000000809;
+
+/// Contents of the `file` in the SystemVersion title's RomFS
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone)]
+pub struct SystemVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub micro: u8,
+    pub _reserved: u8,
+    pub revision_major: u8,
+    pub revision_minor: u8,
+    pub _reserved2: [u8; 2],
+    /// Platform string, such as `NX`
+    pub platform: [u8; 0x20],
+    /// Hash of the firmware build, as a hex string
+    pub version_hash: [u8; 0x40],
+    /// Version shown to the user, such as `17.0.1`
+    pub display_version: [u8; 0x18],
+    /// Full version shown to the user, such as `NintendoSDK Firmware for NX 17.0.1-1.0`
+    pub display_title: [u8; 0x80],
+}
+
+impl SystemVersion {
+    /// Parse the SystemVersion `file`
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<Self, Error> {
+        Ok(reader.read_le()?)
+    }
+
+    pub fn platform_string(&self) -> String {
+        fixed_string(&self.platform)
+    }
+
+    pub fn version_hash_string(&self) -> String {
+        fixed_string(&self.version_hash)
+    }
+
+    pub fn display_version_string(&self) -> String {
+        fixed_string(&self.display_version)
+    }
+
+    pub fn display_title_string(&self) -> String {
+        fixed_string(&self.display_title)
+    }
+}
+
+/// A system title listed by the SystemUpdate meta
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundledTitle {
+    pub title_id: u64,
+    pub version: u32,
+    /// Raw content meta type, see [`BundledTitle::meta_type`]
+    pub meta_type: u8,
+}
+
+impl BundledTitle {
+    /// Content meta type of the title, if known
+    pub fn meta_type(&self) -> Option<ContentMetaType> {
+        ContentMetaType::read_le(&mut Cursor::new([self.meta_type])).ok()
+    }
+}
+
+/// Firmware bundled in the update partition of a game card
+#[derive(Debug, Clone)]
+pub struct BundledFirmware {
+    /// Version of the SystemUpdate meta, which encodes the firmware version
+    pub system_update_version: u32,
+    /// Contents of the SystemVersion title, if it is in the update partition
+    pub system_version: Option<SystemVersion>,
+    /// System titles listed by the SystemUpdate meta
+    pub titles: Vec<BundledTitle>,
+}
+
+impl BundledFirmware {
+    /// Read the firmware version and title list from a SystemUpdate meta
+    ///
+    /// The SystemVersion title isn't resolved, see [`Xci::bundled_firmware`].
+    pub fn from_cnmt(cnmt: &Cnmt) -> Result<Self, Error> {
+        if !matches!(cnmt.extended_header, ExtendedHeader::SystemUpdate(_)) {
+            return Err(Error::InvalidData(format!(
+                "Expected a SystemUpdate meta, got {:?}",
+                cnmt.header.meta_type
+            )));
+        }
+
+        Ok(Self {
+            system_update_version: cnmt.header.title_version,
+            system_version: None,
+            titles: cnmt
+                .meta_entries
+                .iter()
+                .map(|entry| BundledTitle {
+                    title_id: entry.title_id,
+                    version: entry.version,
+                    meta_type: entry.meta_type,
+                })
+                .collect(),
+        })
+    }
+
+    /// Firmware version, such as `17.0.1`
+    ///
+    /// Taken from the SystemVersion title if it was found, otherwise decoded from the
+    /// SystemUpdate meta version.
+    pub fn version_string(&self) -> String {
+        if let Some(system_version) = &self.system_version {
+            return system_version.display_version_string();
+        }
+
+        let version = self.system_update_version;
+        format!(
+            "{}.{}.{}",
+            version >> 26,
+            (version >> 20) & 0x3F,
+            (version >> 16) & 0xF
+        )
+    }
+
+    /// Look up a bundled system title
+    pub fn title(&self, title_id: u64) -> Option<&BundledTitle> {
+        self.titles.iter().find(|title| title.title_id == title_id)
+    }
+}
+
+impl<R: Read + Seek> Xci<R> {
+    /// Read the firmware bundled in the update partition
+    ///
+    /// Returns `None` if the card has no update partition or no SystemUpdate meta in it.
+    /// The keyset needs the keys for the system titles' key generations.
+    pub fn bundled_firmware(&mut self, keyset: &Keyset) -> Result<Option<BundledFirmware>, Error> {
+        let Some(mut update) = self.open_update_partition()? else {
+            return Ok(None);
+        };
+
+        let mut metas = Vec::new();
+        for file in update.list_files()? {
+            if file.name.ends_with(".cnmt.nca") {
+                metas.push(read_cnmt_nca(update.read_to_vec(&file)?, keyset, None)?);
+            }
+        }
+
+        let Some(system_update) = metas
+            .iter()
+            .find(|cnmt| cnmt.header.meta_type == ContentMetaType::SystemUpdate)
+        else {
+            return Ok(None);
+        };
+        let mut firmware = BundledFirmware::from_cnmt(system_update)?;
+
+        let system_version_content = metas
+            .iter()
+            .find(|cnmt| cnmt.header.title_id == SYSTEM_VERSION_TITLE_ID)
+            .and_then(|cnmt| cnmt.get_content_entry_by_type(PackagedContentType::Data));
+
+        if let Some(content) = system_version_content {
+            let name = format!("{}.nca", hex::encode(content.info.content_id));
+            match update.get_file(&name)? {
+                Some(file) => {
+                    let data = update.read_to_vec(&file)?;
+                    let mut nca = Nca::from_reader(Cursor::new(data), keyset, None)?;
+                    let file = nca
+                        .open_romfs_filesystem(0)?
+                        .read_to_vec("/file")?
+                        .ok_or_else(|| Error::NotFound("file in SystemVersion".to_string()))?;
+                    firmware.system_version =
+                        Some(SystemVersion::from_reader(&mut Cursor::new(file))?);
+                }
+                None => tracing::warn!(
+                    "SystemVersion content {} is not in the update partition",
+                    name
+                ),
+            }
+        }
+
+        Ok(Some(firmware))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_system_version() {
+        let mut data = vec![17, 0, 1, 0, 1, 0, 0, 0];
+        data.extend_from_slice(b"NX");
+        data.resize(0x28, 0);
+        data.extend_from_slice(&[b'a'; 0x28]);
+        data.resize(0x68, 0);
+        data.extend_from_slice(b"17.0.1");
+        data.resize(0x80, 0);
+        data.extend_from_slice(b"NintendoSDK Firmware for NX 17.0.1-1.0");
+        data.resize(0x100, 0);
+
+        let version = SystemVersion::from_reader(&mut Cursor::new(data)).unwrap();
+        assert_eq!(version.major, 17);
+        assert_eq!(version.micro, 1);
+        assert_eq!(version.platform_string(), "NX");
+        assert_eq!(version.version_hash_string(), "a".repeat(0x28));
+        assert_eq!(version.display_version_string(), "17.0.1");
+        assert_eq!(
+            version.display_title_string(),
+            "NintendoSDK Firmware for NX 17.0.1-1.0"
+        );
+    }
+
+    #[test]
+    fn test_bundled_firmware_from_cnmt() {
+        // 17.0.1, as the SystemUpdate meta version
+        let version: u32 = (17 << 26) | (1 << 16);
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&0x0100000000000816u64.to_le_bytes());
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(&[0x03, 0x00]); // SystemUpdate, NX
+        data.extend_from_slice(&4u16.to_le_bytes()); // extended header size
+        data.extend_from_slice(&0u16.to_le_bytes()); // content entries
+        data.extend_from_slice(&2u16.to_le_bytes()); // meta entries
+        data.resize(0x20, 0);
+        data.extend_from_slice(&0u32.to_le_bytes()); // extended data size
+        for (title_id, meta_type) in [
+            (SYSTEM_VERSION_TITLE_ID, 0x02u8),
+            (0x0100000000000000, 0x01),
+        ] {
+            data.extend_from_slice(&title_id.to_le_bytes());
+            data.extend_from_slice(&version.to_le_bytes());
+            data.extend_from_slice(&[meta_type, 0, 0, 0]);
+        }
+
+        let cnmt = Cnmt::from_reader(&mut Cursor::new(data)).unwrap();
+        let firmware = BundledFirmware::from_cnmt(&cnmt).unwrap();
+        assert_eq!(firmware.version_string(), "17.0.1");
+        assert_eq!(firmware.titles.len(), 2);
+
+        let system_version = firmware.title(SYSTEM_VERSION_TITLE_ID).unwrap();
+        assert_eq!(system_version.version, version);
+        assert_eq!(
+            system_version.meta_type(),
+            Some(ContentMetaType::SystemData)
+        );
+    }
+}
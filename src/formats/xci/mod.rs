@@ -0,0 +1,1662 @@
+//! The XCI (eXtendable Card Image) format is a Nintendo Switch game card image format.
+//!
+//! This format is the raw data from a Nintendo Switch game card, those little bitter
+//! SD card-like things that you insert physically on top of the console.
+//!
+//! The cards themselves physically have a similar footprint to an SD card, but are slightly thicker
+//! and less wide. The data inside is stored in an eMMC chip, which is soldered onto the card.
+//! They are also coated with Denatonium Benzoate, a bittering agent, to discourage consumption.
+//! Just in case you (or your pet, or your little sibling) get any ideas. No, the game cards taste horrible (by design).
+
+// note to self: XCI files are massive, so we need to be veery careful with memory usage.
+// maybe consider using a buffered reader to read the file in chunks?
+// or, mmap the file and read it in chunks that way?
+use aes::Aes128;
+use aes::cipher::generic_array::GenericArray;
+use binrw::prelude::*;
+use ccm::Ccm;
+use ccm::aead::AeadInPlace;
+use ccm::consts::{U12, U16};
+use cipher::{BlockDecrypt, BlockDecryptMut, KeyInit, KeyIvInit, block_padding::NoPadding};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Seek, SeekFrom, Write};
+use tracing::trace;
+use zeroize::Zeroize;
+
+use crate::{
+    FileEntryExt, KeyRequirementsExt, TitleDataExt, VirtualFSExt,
+    error::Error,
+    formats::nca::{KeyRequirements, NcaKeyRequirements},
+    io::SubFile,
+};
+
+use super::cnmt::Cnmt;
+use super::hfs0::{Hfs0, Hfs0FileVerification};
+use super::keyset::{
+    FixedKeys, KeyEnvironment, RedactedKey, SignatureStatus, verify_rsa_pkcs1_sha256,
+};
+use super::nca::Nca;
+use super::ticket::add_bundled_title_key;
+use super::{Keyset, TitleKeys};
+
+mod builder;
+mod convert;
+mod firmware;
+pub use builder::*;
+pub use firmware::*;
+
+#[binrw]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[brw(little, repr = u8)]
+/// Size of the eMMC chip on the game card,
+/// base 2 logarithm of the size in gigabytes.
+pub enum RomSize {
+    Size1Gb = 0xFA,
+    Size2Gb = 0xF8,
+    Size4Gb = 0xF0,
+    Size8Gb = 0xE0,
+    Size16Gb = 0xE1,
+    Size32Gb = 0xE2,
+}
+
+impl RomSize {
+    /// Size of the card in gigabytes
+    pub fn gigabytes(self) -> u64 {
+        match self {
+            RomSize::Size1Gb => 1,
+            RomSize::Size2Gb => 2,
+            RomSize::Size4Gb => 4,
+            RomSize::Size8Gb => 8,
+            RomSize::Size16Gb => 16,
+            RomSize::Size32Gb => 32,
+        }
+    }
+
+    /// Usable capacity of the card in bytes, which is the size of an untrimmed image
+    ///
+    /// Every 0x200 byte page of the raw eMMC loses 0x24 bytes to error correction,
+    /// so a 1 GB card holds 0x3B800000 bytes of image data.
+    pub fn capacity(self) -> u64 {
+        self.gigabytes() * (GIGABYTE / MEDIA_SIZE) * (MEDIA_SIZE - 0x24)
+    }
+
+    /// The smallest card that can hold an image of the given size
+    pub fn smallest_fitting(image_size: u64) -> Option<Self> {
+        [
+            RomSize::Size1Gb,
+            RomSize::Size2Gb,
+            RomSize::Size4Gb,
+            RomSize::Size8Gb,
+            RomSize::Size16Gb,
+            RomSize::Size32Gb,
+        ]
+        .into_iter()
+        .find(|size| size.capacity() >= image_size)
+    }
+}
+
+#[binrw]
+#[derive(Debug)]
+#[brw(little, repr = u8)]
+/// Flags for the Game card
+/// These are used to determine extra features of the game card.
+pub enum GameCardFlags {
+    /// Boots to this game card's title automatically
+    /// on startup
+    AutoBoot = 0,
+    /// Don't ever record this card ever being launched
+    HistoryErase = 1,
+    /// This card is a service card,
+    /// used for debugging and repairs by
+    /// Nintendo
+    RepairTool = 2,
+    /// This card is Chinese region-locked,
+    /// and can only be played on Chinese Switches
+    /// (Terra region)
+    DifferentRegionCupToTerraDevice = 3,
+    /// This card is region-free, and can be played
+    /// globally on any Switch. Used for worldwide
+    /// game releases that contain multiple Asian locales.
+    /// i.e Southeast Asian releases
+    ///
+    /// These releases are usually labeled as "Asia" or "Multi-Language"
+    DifferentRegionCupToGlobalDevice = 4,
+    /// This card has a next-generation header format, used
+    /// for later Switch games
+    HasNewCardHeader = 7,
+    HasNewCardHeaderV2 = 8,
+}
+
+#[binrw]
+#[derive(Debug)]
+#[brw(little, repr = u64)]
+/// Firmware version for game card
+pub enum FirmwareVersion {
+    Development = 0,
+    Retail1_0_0 = 1,
+    Retail4_0_0 = 2,
+    Devel11_0_0 = 3,
+    Retail11_0_0 = 4,
+    Retail12_0_0 = 5,
+}
+
+#[binrw]
+#[derive(Debug)]
+#[brw(little, repr = u32)]
+/// Access control flags for game card
+pub enum AccessControlFlags {
+    /// No access control
+    TwentyFiveMhz = 0x00A10011,
+    /// 50mhz
+    FiftyMhz = 0x00A10012,
+}
+
+#[binrw]
+#[derive(Debug)]
+#[brw(little, repr = u8)]
+/// Compatibility type for game card
+pub enum CompatibilityType {
+    /// Normal game card
+    Normal = 0,
+    /// Chinese game card, compatible
+    /// with Chinese Switches
+    Terra = 1,
+}
+
+/// Size of a media sector
+pub const MEDIA_SIZE: u64 = 0x200;
+
+/// Size of the key area prepended to "full" XCI files
+pub const KEY_AREA_SIZE: u64 = 0x1000;
+
+/// Byte value filling the unused space of a game card
+pub const PADDING_BYTE: u8 = 0xFF;
+
+const GIGABYTE: u64 = 0x40000000;
+
+/// XCI Header structure
+#[derive(Debug, BinRead, BinWrite)]
+#[br(little)]
+pub struct XciHeader {
+    /// RSA-2048 signature over the header
+    pub signature: [u8; 0x100],
+    #[brw(magic = b"HEAD")]
+    /// `RomAreaStartPageAddress`
+    /// (in Gamecard page units, which are 0x200 bytes)
+    pub rom_area_offset: u32,
+    /// Offset to backup partition
+    /// (Should always be 0xFFFFFFFF)
+    pub backup_area_offset: u32,
+    /// Index for title key encryption key
+    /// TitleKeyDecIndex (high nibble) and KekIndex (low nibble)
+    pub title_kek_index: u8,
+    /// Size of the gamecard (0-7)
+    pub rom_size: RomSize,
+    /// Version of the gamecard header
+    pub gamecard_header_version: u8,
+    /// Flags for the gamecard
+    pub gamecard_flags: GameCardFlags,
+    /// Unique identifier for the game package
+    pub package_id: u64,
+    /// End offset of the valid data
+    /// (in Gamecard page units, which are 0x200 bytes)
+    pub valid_data_end_address: u32,
+    /// Additional gamecard info
+    _reserved: u8,
+    /// Flags2
+    pub gamecard_flags2: u8,
+    /// ApplicationIdListEntryCount
+    pub application_id_list_entry_count: u16,
+    /// Reversed IV
+    pub reversed_iv: [u8; 0x10],
+    /// PartitionFS header address
+    pub hfs0_offset: u64,
+    /// Size of the HFS0 header
+    pub hfs0_header_size: u64,
+    // HFS0 header hash
+    pub hfs0_header_hash: [u8; 0x20],
+    /// Hash of the InitialData
+    pub initial_data_hash: [u8; 0x20],
+    /// SelSec
+    pub sel_sec: u32,
+    /// SelT1Key
+    pub sel_t1_key: u32,
+    /// SelKey
+    pub sel_key: u32,
+    /// LimArea
+    /// (in Gamecard page units, which are 0x200 bytes)
+    pub lim_area: u32,
+    /// CardHeaderEncrypted data,
+    /// encrypted with AES-128-CBC
+    ///
+    /// Use [`XciHeader::decrypt_card_header`] or [`Xci::card_info`] to read it.
+    pub card_header_encrypted: [u8; 0x70],
+}
+
+impl XciHeader {
+    /// Serializes the header to its 0x200 byte on-disk form
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut cursor = binrw::io::Cursor::new(Vec::new());
+        self.write_le(&mut cursor)
+            .expect("Failed to serialize XCI header");
+        cursor.into_inner()
+    }
+
+    /// Verify the RSA-2048 PKCS#1 signature over the header body (0x100..0x200)
+    ///
+    /// Needs `xci_header_fixed_key_modulus` in the key file, otherwise the signature is
+    /// reported as unchecked.
+    pub fn verify_signature(&self, fixed_keys: &FixedKeys) -> SignatureStatus {
+        let bytes = self.to_bytes();
+        SignatureStatus::check(fixed_keys.xci_header_modulus(), |modulus| {
+            verify_rsa_pkcs1_sha256(modulus, &bytes[0x100..], &self.signature)
+        })
+    }
+
+    /// The IV for `card_header_encrypted`, which is stored byte-reversed in the header
+    pub fn card_header_iv(&self) -> [u8; 0x10] {
+        let mut iv = self.reversed_iv;
+        iv.reverse();
+        iv
+    }
+
+    /// Decrypts and parses `card_header_encrypted`
+    ///
+    /// The data is encrypted with AES-128-CBC using `xci_header_key` from the keyset,
+    /// so a development card needs a `dev.keys` keyset.
+    pub fn decrypt_card_header(&self, keyset: &Keyset) -> Result<CardHeaderEncryptedData, Error> {
+        let key: [u8; 0x10] = keyset.get_key("xci_header_key").ok_or_else(|| {
+            Error::KeyLookupError(format!(
+                "xci_header_key not found in {} keyset",
+                keyset.environment
+            ))
+        })?;
+
+        let mut decrypted = self.card_header_encrypted;
+        cbc::Decryptor::<Aes128>::new(&key.into(), &self.card_header_iv().into())
+            .decrypt_padded_mut::<NoPadding>(&mut decrypted)
+            .map_err(|e| Error::CryptoError(format!("Failed to decrypt card header: {}", e)))?;
+
+        binrw::io::Cursor::new(&decrypted).read_le().map_err(|e| {
+            Error::InvalidData(format!(
+                "Failed to parse decrypted card header, is xci_header_key correct? {}",
+                e
+            ))
+        })
+    }
+}
+
+/// Gamecard Information structure
+#[derive(Debug, BinRead, BinWrite)]
+#[br(little)]
+pub struct CardHeaderEncryptedData {
+    /// Firmware version
+    pub firmware_version: FirmwareVersion,
+    /// Access control flags
+    pub access_control_flags: AccessControlFlags,
+    /// Read wait time
+    ///
+    /// Most of the time this is always
+    /// 0x1388
+    pub read_wait_time: u32,
+    /// Read wait time 2
+    ///
+    /// Most of the time this is always 0
+    pub read_wait_time2: u32,
+    /// Write wait time
+    ///
+    /// Most of the time this is always 0
+    pub write_wait_time: u32,
+    /// Write wait time 2
+    ///
+    /// Most of the time this is always 0
+    pub write_wait_time2: u32,
+    /// Firmware mode
+    ///
+    /// (The SDK Addon version for this game)
+    pub firmware_mode: u32,
+    /// Minimal version for this game?
+    pub update_partition_version: u32,
+    /// Compatibility type
+    pub compatibility_type: CompatibilityType,
+    /// Reserved
+    pub _reserved1: [u8; 0x3],
+    /// Hash of the update partition
+    pub update_partition_hash: [u8; 0x8],
+    /// Update partition ID
+    ///
+    /// This should always be 0x0100000000000816
+    pub update_partition_id: u64,
+    /// Empty field 2
+    pub empty2: [u8; 0x38],
+}
+
+/// Gamecard Certificate structure
+///
+/// Found at 0x7000 after the header. Scene dumps often blank this area out,
+/// in which case the magic doesn't match and no certificate is read.
+#[binrw]
+#[derive(Debug)]
+#[brw(little)]
+pub struct GamecardCertificate {
+    /// RSA-2048 PKCS#1 signature over the rest of the certificate
+    pub signature: [u8; 0x100],
+    #[brw(magic = b"CERT")]
+    /// Certificate version
+    pub version: u32,
+    /// Index of the KEK used for the encrypted data
+    pub kek_index: u8,
+    /// Flags
+    pub flags: [u8; 0x7],
+    /// Unique ID of the game card's T1 device
+    pub device_id: [u8; 0x10],
+    /// IV for the encrypted data
+    pub iv: [u8; 0x10],
+    /// Hardware key
+    pub hw_key: [u8; 0x10],
+    /// Encrypted certificate data
+    pub encrypted_data: [u8; 0xC0],
+}
+
+impl GamecardCertificate {
+    /// Serializes the certificate to its 0x200 byte on-disk form
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut cursor = binrw::io::Cursor::new(Vec::new());
+        self.write_le(&mut cursor)
+            .expect("Failed to serialize gamecard certificate");
+        cursor.into_inner()
+    }
+
+    /// Verify the certificate signature against the fixed gamecard certificate key
+    ///
+    /// Needs `xci_cert_fixed_key_modulus` in the key file, otherwise the signature is
+    /// reported as unchecked.
+    pub fn verify_signature(&self, fixed_keys: &FixedKeys) -> SignatureStatus {
+        let body = self.to_bytes();
+        SignatureStatus::check(fixed_keys.xci_cert_modulus(), |modulus| {
+            verify_rsa_pkcs1_sha256(modulus, &body[0x100..], &self.signature)
+        })
+    }
+}
+
+/// Result of [`Xci::verify`]
+#[derive(Debug, Clone)]
+pub struct XciVerification {
+    /// The environment whose keys were used
+    pub environment: KeyEnvironment,
+    /// Signature over the XCI header
+    pub header_signature: SignatureStatus,
+    /// Signature over the gamecard certificate, `None` if the image has no certificate
+    pub certificate_signature: Option<SignatureStatus>,
+    /// Whether the initial data matches the header's hash, `None` if the image has no key area
+    pub initial_data_hash: Option<bool>,
+}
+
+impl XciVerification {
+    /// Whether the header signature is valid and no certificate signature or initial data
+    /// hash is invalid
+    pub fn is_ok(&self) -> bool {
+        self.header_signature.is_valid()
+            && self.certificate_signature != Some(SignatureStatus::Invalid)
+            && self.initial_data_hash != Some(false)
+    }
+}
+
+impl std::fmt::Display for XciVerification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Keys: {}", self.environment)?;
+        writeln!(f, "Header signature: {}", self.header_signature)?;
+        match &self.certificate_signature {
+            Some(status) => writeln!(f, "Certificate signature: {}", status)?,
+            None => writeln!(f, "Certificate: not present")?,
+        }
+        match self.initial_data_hash {
+            Some(true) => write!(f, "Initial data hash: valid"),
+            Some(false) => write!(f, "Initial data hash: invalid"),
+            None => write!(f, "Key area: not present"),
+        }
+    }
+}
+
+/// Hash checks of one partition of the root HFS0, part of [`XciHashVerification`]
+#[derive(Debug, Clone)]
+pub struct XciPartitionVerification {
+    /// Name of the partition, such as `secure`
+    pub name: String,
+    /// Whether the partition's HFS0 header matches the hash in the root HFS0
+    pub header_valid: bool,
+    /// Hash checks of each file in the partition
+    pub files: Vec<Hfs0FileVerification>,
+}
+
+impl XciPartitionVerification {
+    /// Whether the partition header and all its files are valid
+    pub fn is_ok(&self) -> bool {
+        self.header_valid && self.files.iter().all(|file| file.valid)
+    }
+}
+
+/// Result of [`Xci::verify_hashes`]
+#[derive(Debug, Clone)]
+pub struct XciHashVerification {
+    /// Whether the root HFS0 header matches `hfs0_header_hash` in the XCI header
+    pub root_header_valid: bool,
+    /// Hash checks of each partition in the root HFS0
+    pub partitions: Vec<XciPartitionVerification>,
+}
+
+impl XciHashVerification {
+    /// Whether every hash matched
+    pub fn is_ok(&self) -> bool {
+        self.root_header_valid && self.partitions.iter().all(|p| p.is_ok())
+    }
+}
+
+impl std::fmt::Display for XciHashVerification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = |valid: bool| if valid { "valid" } else { "invalid" };
+
+        write!(f, "Root HFS0 header: {}", status(self.root_header_valid))?;
+        for partition in &self.partitions {
+            let failed: Vec<_> = partition.files.iter().filter(|f| !f.valid).collect();
+            write!(
+                f,
+                "\nPartition {}: header {}, {}/{} files valid",
+                partition.name,
+                status(partition.header_valid),
+                partition.files.len() - failed.len(),
+                partition.files.len()
+            )?;
+            for file in failed {
+                write!(f, "\n  {}: invalid", file.name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// XCI file representation
+pub struct Xci<R: Read + Seek> {
+    /// Reader for the XCI file
+    reader: R,
+    /// XCI header
+    pub header: XciHeader,
+    /// Optional key area for "full" XCI files
+    pub key_area: Option<KeyArea>,
+    /// Gamecard certificate
+    pub gamecard_cert: Option<GamecardCertificate>,
+}
+
+/// Size of the initial data at the start of the key area
+pub const INITIAL_DATA_SIZE: usize = 0x200;
+
+/// Key Area found in "full" XCI files
+///
+/// Only the initial data, the first 0x200 bytes, can be read from a game card. It holds
+/// the title key encrypted with AES-128-CCM. The plain title key after it is usually
+/// zeroed in dumps.
+#[binrw]
+#[brw(little)]
+pub struct KeyArea {
+    /// Package ID (same as in header)
+    pub package_id: u64,
+    /// Together with the package ID, the key source for the initial data key
+    pub _reserved: [u8; 0x8],
+    /// Challenge Response Auth Data, the encrypted title key
+    pub challenge_response_auth_data: [u8; 0x10],
+    /// Challenge Response Auth MAC
+    pub challenge_response_auth_mac: [u8; 0x10],
+    /// Challenge Response Auth Nonce
+    pub challenge_response_auth_nonce: [u8; 0xC],
+    pub _reserved_initial_data: [u8; 0x1C4],
+    /// Title key 1
+    pub title_key1: [u8; 0x8],
+    /// Title key 2
+    pub title_key2: [u8; 0x8],
+    pub _reserved_title_key_area: [u8; 0xCF0],
+    /// Encryption parameters of the title key area
+    pub title_key_area_encryption: [u8; 0x100],
+}
+
+impl std::fmt::Debug for KeyArea {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyArea")
+            .field("package_id", &format_args!("{:016X}", self.package_id))
+            .field(
+                "challenge_response_auth_data",
+                &RedactedKey(&self.challenge_response_auth_data),
+            )
+            .field(
+                "challenge_response_auth_mac",
+                &hex::encode(self.challenge_response_auth_mac),
+            )
+            .field(
+                "challenge_response_auth_nonce",
+                &hex::encode(self.challenge_response_auth_nonce),
+            )
+            .field("title_key1", &RedactedKey(&self.title_key1))
+            .field("title_key2", &RedactedKey(&self.title_key2))
+            .finish_non_exhaustive()
+    }
+}
+
+impl KeyArea {
+    /// An empty key area for a card with the given package ID
+    pub fn new(package_id: u64) -> Self {
+        Self {
+            package_id,
+            _reserved: [0; 0x8],
+            challenge_response_auth_data: [0; 0x10],
+            challenge_response_auth_mac: [0; 0x10],
+            challenge_response_auth_nonce: [0; 0xC],
+            _reserved_initial_data: [0; 0x1C4],
+            title_key1: [0; 0x8],
+            title_key2: [0; 0x8],
+            _reserved_title_key_area: [0; 0xCF0],
+            title_key_area_encryption: [0; 0x100],
+        }
+    }
+
+    /// Serializes the key area
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut cursor = binrw::io::Cursor::new(Vec::new());
+        self.write(&mut cursor)
+            .expect("Failed to serialize key area");
+        cursor.into_inner()
+    }
+
+    /// The initial data, as hashed in [`XciHeader::initial_data_hash`]
+    pub fn initial_data(&self) -> Vec<u8> {
+        let mut bytes = self.to_bytes();
+        bytes.truncate(INITIAL_DATA_SIZE);
+        bytes
+    }
+
+    /// SHA-256 of the initial data
+    pub fn initial_data_hash(&self) -> [u8; 0x20] {
+        Sha256::digest(self.initial_data()).into()
+    }
+
+    /// The plain title key stored after the initial data
+    pub fn title_key(&self) -> [u8; 0x10] {
+        let mut key = [0u8; 0x10];
+        key[..0x8].copy_from_slice(&self.title_key1);
+        key[0x8..].copy_from_slice(&self.title_key2);
+        key
+    }
+
+    /// Decrypt the title key from the initial data
+    ///
+    /// The AES-128-CCM key is the key source (package ID and the reserved bytes after it)
+    /// decrypted with `xci_t1_titlekey_kek_XX`, where `XX` is `kek_index`.
+    ///
+    /// # Errors
+    /// * [`Error::KeyLookupError`] - If the keyset has no KEK for `kek_index`
+    /// * [`Error::CryptoError`] - If the MAC doesn't match
+    pub fn decrypt_title_key(&self, keyset: &Keyset, kek_index: u8) -> Result<[u8; 0x10], Error> {
+        let kek = keyset
+            .get_xci_titlekey_kek(kek_index as usize)
+            .ok_or_else(|| {
+                Error::KeyLookupError(format!(
+                    "Failed to decrypt initial data, no xci_t1_titlekey_kek_{:02x}",
+                    kek_index
+                ))
+            })?;
+
+        let mut key = GenericArray::from([0u8; 0x10]);
+        key[..0x8].copy_from_slice(&self.package_id.to_le_bytes());
+        key[0x8..].copy_from_slice(&self._reserved);
+        Aes128::new(GenericArray::from_slice(&kek)).decrypt_block(&mut key);
+
+        let mut title_key = self.challenge_response_auth_data;
+        let result = Ccm::<Aes128, U16, U12>::new(&key).decrypt_in_place_detached(
+            GenericArray::from_slice(&self.challenge_response_auth_nonce),
+            &[],
+            &mut title_key,
+            GenericArray::from_slice(&self.challenge_response_auth_mac),
+        );
+        key.as_mut_slice().zeroize();
+
+        result.map_err(|_| {
+            title_key.zeroize();
+            Error::CryptoError("Initial data MAC mismatch".to_string())
+        })?;
+        Ok(title_key)
+    }
+}
+
+/// A partition entry in an XCI file
+#[derive(Debug, Clone)]
+pub struct XciPartition {
+    pub name: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl<R: Read + Seek + Clone> VirtualFSExt<R> for Xci<R> {
+    type Entry = XciPartition;
+
+    fn list_files(&self) -> Result<Vec<Self::Entry>, Error> {
+        // We need to list the HFS0 partitions, but we can't borrow self as mutable
+        // So we'll clone the reader and create a new instance
+        let mut reader = self.reader.clone();
+        let hfs0_offset = self.get_hfs0_offset();
+        reader.seek(SeekFrom::Start(hfs0_offset)).ok();
+
+        // Create a SubFile that covers the entire rest of the XCI file
+        let subfile = SubFile::new(
+            &mut reader,
+            hfs0_offset,
+            hfs0_offset + (self.header.valid_data_end_address as u64 * MEDIA_SIZE),
+        );
+
+        let hfs0 = Hfs0::from_reader(subfile)?;
+        Ok(hfs0
+            .list_files()?
+            .into_iter()
+            .map(|file| XciPartition {
+                name: file.name,
+                offset: hfs0_offset + file.offset,
+                size: file.size,
+            })
+            .collect())
+    }
+
+    fn get_file(&self, name: &str) -> Result<Option<Self::Entry>, Error> {
+        let files = self.list_files()?;
+        Ok(files.into_iter().find(|f| f.name == name))
+    }
+
+    fn create_reader(&mut self, file: &Self::Entry) -> Result<SubFile<R>, Error> {
+        Ok(SubFile::new(
+            self.reader.clone(),
+            file.offset,
+            file.offset + file.size,
+        ))
+    }
+}
+
+impl<R: Read + Seek + Clone> FileEntryExt<R> for XciPartition {
+    type FS = Xci<R>;
+
+    fn file_reader(&self, fs: &mut Xci<R>) -> Result<SubFile<R>, Error> {
+        fs.create_reader(self)
+    }
+
+    fn file_size(&self) -> u64 {
+        self.size
+    }
+
+    fn file_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn read_bytes(&self, fs: &mut Xci<R>, size: usize) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0; size];
+        let mut reader = self.file_reader(fs)?;
+        reader.read_exact(&mut buf).map_err(Error::from)?;
+        Ok(buf)
+    }
+}
+
+impl<R: Read + Seek> Xci<R> {
+    /// Creates a new XCI instance from a reader
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        // Check if this is a "full" XCI
+        // (full XCIs contain a 0x1000 key area, usually)
+        // unreadable directly in most r/w operations
+        let is_full_xci = {
+            trace!("Checking if this is a full XCI");
+            // Read at 0x100 first, we're checking for a trimmed XCI
+            // trimmed XCIs will have a header at 0x100
+            reader.seek(SeekFrom::Start(0x100)).map_err(Error::from)?;
+            let mut magic = [0u8; 4];
+            reader.read_exact(&mut magic).map_err(Error::from)?;
+            let at_0x100 = b"HEAD"[..] == magic[..];
+            trace!("Found magic at 0x100?: {}", at_0x100);
+
+            if at_0x100 {
+                trace!("This is a trimmed XCI, there's no key area");
+                !at_0x100
+            } else {
+                // Let's go to 0x200
+                // full XCIs will have a 0x1000 key area before the XciHeader
+                reader.seek(SeekFrom::Start(0x1100)).map_err(Error::from)?;
+                reader.read_exact(&mut magic).map_err(Error::from)?;
+                let at_0x1100 = b"HEAD"[..] == magic[..];
+                trace!("Found magic at 0x1100?: {}", at_0x1100);
+                at_0x1100
+            }
+        };
+
+        // Set up the key area if this is a full XCI
+        let key_area = if is_full_xci {
+            reader.seek(SeekFrom::Start(0)).map_err(Error::from)?;
+            Some(reader.read_le::<KeyArea>().map_err(Error::from)?)
+        } else {
+            None
+        };
+
+        trace!("full XCI? {}", is_full_xci);
+
+        // Determine header offset
+        let header_offset = if is_full_xci { KEY_AREA_SIZE } else { 0 };
+
+        let mut magic_area = [0u8; 4];
+        reader
+            .seek(SeekFrom::Start(header_offset))
+            .map_err(Error::from)?;
+        reader.read_exact(&mut magic_area).map_err(Error::from)?;
+        reader
+            .seek(SeekFrom::Start(header_offset))
+            .map_err(Error::from)?;
+
+        // Read the header
+        reader
+            .seek(SeekFrom::Start(header_offset))
+            .map_err(Error::from)?;
+        let header: XciHeader = reader.read_le().map_err(Error::from)?;
+
+        // Read gamecard certificate
+        // Full XCIs would have this offset at 0x8000,
+        // so trimmed XCIs would have this at 0x7000
+        reader
+            .seek(SeekFrom::Start(header_offset + 0x7000))
+            .map_err(Error::from)?;
+        // Attempt to read the gamecard certificate but don't fail if it doesn't exist
+        let gamecard_cert = match reader.read_le::<GamecardCertificate>() {
+            Ok(cert) => Some(cert),
+            Err(err) => {
+                tracing::warn!("Failed to read gamecard certificate: {}", err);
+                None
+            }
+        };
+
+        Ok(Xci {
+            reader,
+            header,
+            key_area,
+            gamecard_cert,
+        })
+    }
+
+    /// Gets the offset of the card image (starting with the header) within the file
+    ///
+    /// This is 0x1000 for "full" XCIs with a key area, and 0 otherwise.
+    pub fn image_offset(&self) -> u64 {
+        if self.key_area.is_some() {
+            KEY_AREA_SIZE
+        } else {
+            0
+        }
+    }
+
+    /// Gets the offset to the HFS0 partition
+    pub fn get_hfs0_offset(&self) -> u64 {
+        self.image_offset() + self.header.hfs0_offset
+    }
+
+    /// Size of the card image up to the end of the valid data
+    ///
+    /// This is the size of a trimmed image, not counting the key area.
+    pub fn trimmed_size(&self) -> u64 {
+        (self.header.valid_data_end_address as u64 + 1) * MEDIA_SIZE
+    }
+
+    /// Size of the card image padded to the card's full capacity
+    ///
+    /// This is the size of an untrimmed image, not counting the key area.
+    pub fn untrimmed_size(&self) -> u64 {
+        self.header.rom_size.capacity()
+    }
+
+    /// Writes a trimmed copy of the XCI, without the padding after the valid data
+    ///
+    /// The key area is kept if the source has one. Everything after the valid data is
+    /// checked to be padding first, so nothing is written if trimming would lose data.
+    /// Returns the number of bytes written.
+    pub fn trim<W: Write>(&mut self, mut writer: W) -> Result<u64, Error> {
+        let data_end = self.image_offset() + self.trimmed_size();
+        let file_size = self.reader.seek(SeekFrom::End(0))?;
+
+        if file_size < data_end {
+            return Err(Error::InvalidData(format!(
+                "XCI is 0x{:X} bytes, but valid data ends at 0x{:X}",
+                file_size, data_end
+            )));
+        }
+
+        self.reader.seek(SeekFrom::Start(data_end))?;
+        check_padding(&mut self.reader, data_end, file_size - data_end)?;
+
+        self.reader.seek(SeekFrom::Start(0))?;
+        copy_exact(&mut self.reader, &mut writer, data_end)?;
+
+        trace!(
+            "Trimmed XCI from 0x{:X} to 0x{:X} bytes",
+            file_size, data_end
+        );
+        Ok(data_end)
+    }
+
+    /// Writes an untrimmed copy of the XCI, padded to the full capacity of the card
+    ///
+    /// The key area is kept if the source has one. Returns the number of bytes written.
+    pub fn untrim<W: Write>(&mut self, mut writer: W) -> Result<u64, Error> {
+        let data_end = self.image_offset() + self.trimmed_size();
+        let full_size = self.image_offset() + self.untrimmed_size();
+        let file_size = self.reader.seek(SeekFrom::End(0))?;
+
+        if file_size < data_end {
+            return Err(Error::InvalidData(format!(
+                "XCI is 0x{:X} bytes, but valid data ends at 0x{:X}",
+                file_size, data_end
+            )));
+        }
+        if data_end > full_size {
+            return Err(Error::InvalidData(format!(
+                "Valid data ends at 0x{:X}, past the 0x{:X} byte capacity of a {} GB card",
+                data_end,
+                full_size,
+                self.header.rom_size.gigabytes()
+            )));
+        }
+
+        self.reader.seek(SeekFrom::Start(0))?;
+        copy_exact(&mut self.reader, &mut writer, data_end)?;
+        write_padding(&mut writer, PADDING_BYTE, full_size - data_end)?;
+
+        trace!(
+            "Untrimmed XCI from 0x{:X} to 0x{:X} bytes",
+            file_size, full_size
+        );
+        Ok(full_size)
+    }
+
+    /// Reads the initial HFS0 header on the XCI file, returning a list of partitions found
+    #[tracing::instrument(skip(self), level = "trace")]
+    pub fn list_hfs0_partitions(&mut self) -> Result<Hfs0<SubFile<&mut R>>, Error> {
+        let hfs0_offset = self.get_hfs0_offset();
+        self.reader
+            .seek(SeekFrom::Start(hfs0_offset))
+            .map_err(Error::from)?;
+
+        // Create a SubFile that covers the entire rest of the XCI file
+        // This allows reading the HFS0 header and all partition data
+        let subfile = SubFile::new(
+            &mut self.reader,
+            hfs0_offset,
+            // Don't limit to just header_size - we need access to the full content
+            hfs0_offset + (self.header.valid_data_end_address as u64 * MEDIA_SIZE),
+        );
+        let hfs0 = Hfs0::from_reader(subfile)
+            .map_err(|e| Error::Other(format!("Failed to parse HFS0: {}", e)))?;
+
+        trace!("HFS0 header read successfully");
+
+        Ok(hfs0)
+    }
+
+    /// Opens an HFS0 partition by name, returning the partition if it exists
+    #[tracing::instrument(skip(self), level = "trace")]
+    pub fn open_hfs0_partition(
+        &mut self,
+        part_name: &str,
+    ) -> Result<Option<Hfs0<SubFile<&mut R>>>, Error> {
+        let hfs0_header = self.list_hfs0_partitions()?;
+
+        // trace!("Attempting to open HFS0 partition: {}", part_name);
+        let part = hfs0_header.get_file(part_name);
+
+        if let Ok(Some(file)) = part {
+            trace!("Attempting to open HFS0 partition: {}", part_name);
+            // Calculate the absolute offset in the file
+            let hfs0_offset = self.get_hfs0_offset();
+            let start_offset = hfs0_offset + file.offset;
+            let end_offset = start_offset + file.size;
+
+            let part = Hfs0::from_reader(SubFile::new(&mut self.reader, start_offset, end_offset))
+                .map_err(|e| {
+                    Error::Other(format!(
+                        "Failed to parse HFS0 partition {}: {}",
+                        part_name, e
+                    ))
+                })?;
+
+            trace!("HFS0 partition opened successfully");
+
+            return Ok(Some(part));
+        }
+
+        Ok(None)
+    }
+
+    /// Verify the header signature and the gamecard certificate
+    ///
+    /// The fixed keys are selected by the keyset's environment, so development cards
+    /// need a `dev.keys` keyset. The moduli are not bundled with the crate: both checks
+    /// need `xci_header_fixed_key_modulus` and `xci_cert_fixed_key_modulus` in the key
+    /// file, and report the signature as unchecked without them.
+    pub fn verify(&self, keyset: &Keyset) -> XciVerification {
+        let fixed_keys = keyset.fixed_keys();
+
+        XciVerification {
+            environment: fixed_keys.environment,
+            header_signature: self.header.verify_signature(&fixed_keys),
+            certificate_signature: self
+                .gamecard_cert
+                .as_ref()
+                .map(|cert| cert.verify_signature(&fixed_keys)),
+            initial_data_hash: self.verify_initial_data(),
+        }
+    }
+
+    /// Check the initial data in the key area against the header's `initial_data_hash`
+    ///
+    /// Returns `None` if the image has no key area.
+    pub fn verify_initial_data(&self) -> Option<bool> {
+        let key_area = self.key_area.as_ref()?;
+        let valid = key_area.initial_data_hash() == self.header.initial_data_hash;
+        if !valid {
+            tracing::warn!("Initial data hash mismatch");
+        }
+        Some(valid)
+    }
+
+    /// Decrypt the title key from the initial data in the key area
+    ///
+    /// The KEK is selected by the low nibble of the header's `title_kek_index`. See
+    /// [`KeyArea::decrypt_title_key`].
+    pub fn decrypt_initial_data_title_key(&self, keyset: &Keyset) -> Result<[u8; 0x10], Error> {
+        self.key_area
+            .as_ref()
+            .ok_or_else(|| Error::NotFound("key area".to_string()))?
+            .decrypt_title_key(keyset, self.header.title_kek_index & 0x0F)
+    }
+
+    /// Check the root HFS0 header hash, and the hashes of every partition and file in it
+    ///
+    /// Each partition's header is checked against its root HFS0 entry, and each file
+    /// against its partition's entry, so the report shows where the data was changed.
+    pub fn verify_hashes(&mut self) -> Result<XciHashVerification, Error> {
+        let file_size = self.reader.seek(SeekFrom::End(0))?;
+        let hfs0_offset = self.get_hfs0_offset();
+        match hfs0_offset.checked_add(self.header.hfs0_header_size) {
+            Some(end) if end <= file_size => {}
+            _ => {
+                return Err(Error::InvalidData(format!(
+                    "Root HFS0 header 0x{:X}+0x{:X} is past the end of the 0x{:X} byte file",
+                    hfs0_offset, self.header.hfs0_header_size, file_size
+                )));
+            }
+        }
+
+        let mut root_header = vec![0u8; self.header.hfs0_header_size as usize];
+        self.reader.seek(SeekFrom::Start(hfs0_offset))?;
+        self.reader.read_exact(&mut root_header)?;
+        let root_header_valid =
+            <[u8; 0x20]>::from(Sha256::digest(&root_header)) == self.header.hfs0_header_hash;
+        if !root_header_valid {
+            tracing::warn!("Root HFS0 header hash mismatch");
+        }
+
+        let partition_headers = self.list_hfs0_partitions()?.verify()?;
+
+        let mut partitions = Vec::with_capacity(partition_headers.len());
+        for header in partition_headers {
+            let files = match self.open_hfs0_partition(&header.name) {
+                Ok(Some(mut partition)) => partition.verify()?,
+                Ok(None) => Vec::new(),
+                // A partition header that fails its hash may well not parse either
+                Err(e) if !header.valid => {
+                    tracing::warn!("Failed to open partition {}: {}", header.name, e);
+                    Vec::new()
+                }
+                Err(e) => return Err(e),
+            };
+
+            partitions.push(XciPartitionVerification {
+                name: header.name,
+                header_valid: header.valid,
+                files,
+            });
+        }
+
+        Ok(XciHashVerification {
+            root_header_valid,
+            partitions,
+        })
+    }
+
+    /// Decrypts the gamecard info from the header
+    ///
+    /// This holds the firmware version, access control flags and the version and ID of the
+    /// update partition. See [`XciHeader::decrypt_card_header`].
+    pub fn card_info(&self, keyset: &Keyset) -> Result<CardHeaderEncryptedData, Error> {
+        self.header.decrypt_card_header(keyset)
+    }
+
+    /// Opens the `secure` partition if it exists
+    #[tracing::instrument(skip(self), level = "trace")]
+    pub fn open_secure_partition(&mut self) -> Result<Option<Hfs0<SubFile<&mut R>>>, Error> {
+        self.open_hfs0_partition("secure")
+    }
+
+    /// Opens the `normal` partition if it exists
+    #[tracing::instrument(skip(self), level = "trace")]
+    pub fn open_normal_partition(&mut self) -> Result<Option<Hfs0<SubFile<&mut R>>>, Error> {
+        self.open_hfs0_partition("normal")
+    }
+
+    /// Opens the `logo` partition if it exists
+    #[tracing::instrument(skip(self), level = "trace")]
+    pub fn open_logo_partition(&mut self) -> Result<Option<Hfs0<SubFile<&mut R>>>, Error> {
+        self.open_hfs0_partition("logo")
+    }
+
+    #[tracing::instrument(skip(self), level = "trace")]
+    pub fn open_update_partition(&mut self) -> Result<Option<Hfs0<SubFile<&mut R>>>, Error> {
+        self.open_hfs0_partition("update")
+    }
+}
+
+/// Read the CNMT out of a CNMT NCA
+fn read_cnmt_nca(
+    data: Vec<u8>,
+    keyset: &Keyset,
+    title_keys: Option<&super::TitleKeys>,
+) -> Result<Cnmt, Error> {
+    let mut nca = Nca::from_reader(std::io::Cursor::new(data), keyset, title_keys)?;
+    let mut pfs0 = nca.open_pfs0_filesystem(0)?;
+
+    let cnmt_file = pfs0
+        .list_files()?
+        .into_iter()
+        .find(|file| file.name.ends_with(".cnmt"))
+        .ok_or_else(|| Error::NotFound("CNMT in meta NCA".to_string()))?;
+
+    Ok(Cnmt::from_reader(&mut std::io::Cursor::new(
+        pfs0.read_to_vec(&cnmt_file)?,
+    ))?)
+}
+
+/// Copies exactly `len` bytes from the reader's current position
+fn copy_exact<R: Read, W: Write>(reader: &mut R, writer: &mut W, len: u64) -> Result<(), Error> {
+    let copied = std::io::copy(&mut reader.take(len), writer)?;
+    if copied != len {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("Expected 0x{:X} bytes, only got 0x{:X}", len, copied),
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that the next `len` bytes of the reader are all padding
+///
+/// `offset` is the reader's current position, used for the error message.
+fn check_padding<R: Read>(reader: &mut R, offset: u64, len: u64) -> Result<(), Error> {
+    let mut buf = vec![0u8; 0x100000];
+    let mut checked = 0;
+
+    while checked < len {
+        let chunk = (len - checked).min(buf.len() as u64) as usize;
+        reader.read_exact(&mut buf[..chunk])?;
+
+        if let Some(pos) = buf[..chunk].iter().position(|&b| b != PADDING_BYTE) {
+            return Err(Error::InvalidData(format!(
+                "Found data after the valid data end at 0x{:X}, refusing to trim",
+                offset + checked + pos as u64
+            )));
+        }
+        checked += chunk as u64;
+    }
+
+    Ok(())
+}
+
+/// Writes `len` bytes of padding
+fn write_padding<W: Write>(writer: &mut W, byte: u8, len: u64) -> Result<(), Error> {
+    let buf = vec![byte; 0x100000];
+    let mut written = 0;
+
+    while written < len {
+        let chunk = (len - written).min(buf.len() as u64) as usize;
+        writer.write_all(&buf[..chunk])?;
+        written += chunk as u64;
+    }
+
+    Ok(())
+}
+
+impl<R: Read + Seek> TitleDataExt for Xci<R> {
+    fn get_cnmts(
+        &mut self,
+        keyset: &crate::formats::Keyset,
+        title_keyset: std::option::Option<&crate::formats::title_keyset::TitleKeys>,
+    ) -> Result<Vec<crate::formats::cnmt::Cnmt>, Error> {
+        let mut cnmts = Vec::new();
+
+        let secure = self.open_secure_partition()?;
+        if let Some(mut secure) = secure {
+            let files = secure.list_files()?;
+            for file in files {
+                if file.name.ends_with(".cnmt.nca") {
+                    let mut buf = vec![0u8; file.size as usize];
+                    secure.read_buf(&file, &mut buf)?;
+                    let mut cursor = std::io::Cursor::new(buf);
+                    let mut nca =
+                        crate::formats::nca::Nca::from_reader(&mut cursor, keyset, title_keyset)?;
+                    let mut pfs0 = nca.open_pfs0_filesystem(0)?;
+                    // for each file
+                    let files = pfs0.list_files()?;
+                    for file in files {
+                        if file.name.ends_with(".cnmt") {
+                            let file = pfs0.read_to_vec(&file)?;
+                            let mut cursor = std::io::Cursor::new(file);
+                            let cnmt = crate::formats::cnmt::Cnmt::from_reader(&mut cursor)?;
+                            cnmts.push(cnmt);
+                        }
+                    }
+                } else if file.name.ends_with(".cnmt") {
+                    let file = secure
+                        .get_file(&file.name)?
+                        .ok_or(Error::NotFound(file.name.clone()))?;
+                    let mut buf = vec![0u8; file.size as usize];
+                    secure.read_buf(&file, &mut buf)?;
+                    let mut cursor = std::io::Cursor::new(buf);
+                    let cnmt = crate::formats::cnmt::Cnmt::from_reader(&mut cursor)?;
+                    cnmts.push(cnmt);
+                }
+            }
+        }
+
+        Ok(cnmts)
+    }
+
+    fn title_id(&self) -> Result<u64, Error> {
+        Ok(self.header.package_id)
+    }
+}
+
+/// Title keys from the common tickets in the secure and update partitions count as available
+impl<R: Read + Seek> KeyRequirementsExt for Xci<R> {
+    fn key_requirements(
+        &mut self,
+        keyset: &crate::formats::Keyset,
+        title_keys: Option<&crate::formats::TitleKeys>,
+    ) -> Result<KeyRequirements, Error> {
+        let mut available = TitleKeys::new();
+        if let Some(title_keys) = title_keys {
+            available.merge(title_keys);
+        }
+        for partition_name in ["secure", "update"] {
+            let Some(mut partition) = self.open_hfs0_partition(partition_name)? else {
+                continue;
+            };
+            for file in partition.list_files()? {
+                if file.name.ends_with(".tik") {
+                    add_bundled_title_key(
+                        &mut available,
+                        &format!("{}/{}", partition_name, file.name),
+                        partition.read_to_vec(&file)?,
+                    )?;
+                }
+            }
+        }
+
+        let partitions: Vec<String> = self
+            .list_hfs0_partitions()?
+            .list_files()?
+            .into_iter()
+            .map(|file| file.name)
+            .collect();
+
+        let mut requirements = KeyRequirements::default();
+        for partition_name in partitions {
+            let Some(mut partition) = self.open_hfs0_partition(&partition_name)? else {
+                continue;
+            };
+
+            for file in partition.list_files()? {
+                if !file.name.ends_with(".nca") {
+                    continue;
+                }
+
+                let mut header = vec![0u8; 0x400];
+                partition.read_buf(&file, &mut header)?;
+                requirements
+                    .ncas
+                    .push(NcaKeyRequirements::from_header_bytes(
+                        format!("{}/{}", partition_name, file.name),
+                        &header,
+                        keyset,
+                        Some(&available),
+                    ));
+            }
+        }
+
+        Ok(requirements)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use crate::formats::Keyset;
+
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    #[traced_test]
+    fn test_xci() {
+        // test file from Ace Attorney Investigations Collection
+        // thank you Edgeworth for being my guinea pig
+        let file = File::open("dump/aaic.xci").unwrap();
+        let mut xci = Xci::new(file).unwrap();
+
+        println!("{:#?}", xci.header);
+        trace!("HFS Header Offset {:02X?}", xci.header.hfs0_offset);
+        // println!("{:#?}", xci.gamecard_info);
+        println!("{:?}", xci.gamecard_cert);
+        let keyset = Keyset::from_file("prod.keys").unwrap();
+        let title_keyset = None;
+
+        let cnmts = xci.get_cnmts(&keyset, title_keyset).unwrap();
+        println!("{:#?}", cnmts);
+    }
+
+    pub(crate) fn test_header() -> XciHeader {
+        XciHeader {
+            signature: [0; 0x100],
+            rom_area_offset: 0,
+            backup_area_offset: 0xFFFFFFFF,
+            title_kek_index: 0,
+            rom_size: RomSize::Size1Gb,
+            gamecard_header_version: 0,
+            gamecard_flags: GameCardFlags::AutoBoot,
+            package_id: 0x0123456789ABCDEF,
+            valid_data_end_address: 0,
+            _reserved: 0,
+            gamecard_flags2: 0,
+            application_id_list_entry_count: 0,
+            reversed_iv: [0; 0x10],
+            hfs0_offset: 0xF000,
+            hfs0_header_size: 0,
+            hfs0_header_hash: [0; 0x20],
+            initial_data_hash: [0; 0x20],
+            sel_sec: 1,
+            sel_t1_key: 2,
+            sel_key: 0,
+            lim_area: 0,
+            card_header_encrypted: [0; 0x70],
+        }
+    }
+
+    fn test_cert() -> GamecardCertificate {
+        GamecardCertificate {
+            signature: [0; 0x100],
+            version: 1,
+            kek_index: 0,
+            flags: [0; 7],
+            device_id: [0x11; 0x10],
+            iv: [0x22; 0x10],
+            hw_key: [0x33; 0x10],
+            encrypted_data: [0x44; 0xC0],
+        }
+    }
+
+    #[test]
+    fn test_card_header_size() {
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        test_header().write_le(&mut cursor).unwrap();
+        assert_eq!(cursor.into_inner().len(), 0x200);
+    }
+
+    #[test]
+    fn test_decrypt_card_header() {
+        use cipher::{BlockEncryptMut, KeyIvInit};
+
+        let key = [0x42; 0x10];
+        let iv: [u8; 0x10] = std::array::from_fn(|i| i as u8);
+
+        let info = CardHeaderEncryptedData {
+            firmware_version: FirmwareVersion::Retail4_0_0,
+            access_control_flags: AccessControlFlags::FiftyMhz,
+            read_wait_time: 0x1388,
+            read_wait_time2: 0,
+            write_wait_time: 0,
+            write_wait_time2: 0,
+            firmware_mode: 0x000B0000,
+            update_partition_version: 0x1C000000,
+            compatibility_type: CompatibilityType::Normal,
+            _reserved1: [0; 3],
+            update_partition_hash: [0xAA; 8],
+            update_partition_id: 0x0100000000000816,
+            empty2: [0; 0x38],
+        };
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        info.write_le(&mut cursor).unwrap();
+        let mut plaintext = cursor.into_inner();
+        assert_eq!(plaintext.len(), 0x70);
+
+        cbc::Encryptor::<Aes128>::new(&key.into(), &iv.into())
+            .encrypt_padded_mut::<NoPadding>(&mut plaintext, 0x70)
+            .unwrap();
+
+        let mut header = test_header();
+        header.card_header_encrypted.copy_from_slice(&plaintext);
+        header.reversed_iv = iv;
+        header.reversed_iv.reverse();
+
+        let mut keyset = Keyset::default();
+        assert!(header.decrypt_card_header(&keyset).is_err());
+
+        keyset
+            .raw_keys
+            .insert("xci_header_key".to_string(), key.to_vec().into());
+        let decrypted = header.decrypt_card_header(&keyset).unwrap();
+        assert!(matches!(
+            decrypted.firmware_version,
+            FirmwareVersion::Retail4_0_0
+        ));
+        assert!(matches!(
+            decrypted.access_control_flags,
+            AccessControlFlags::FiftyMhz
+        ));
+        assert_eq!(decrypted.update_partition_version, 0x1C000000);
+        assert_eq!(decrypted.update_partition_id, 0x0100000000000816);
+    }
+
+    #[test]
+    fn test_verify_signatures() {
+        use crate::formats::keyset::TEST_RSA_MODULUS;
+
+        // Signed over bytes 0x100..0x200 of test_header() and test_cert() with the test key
+        const HEADER_SIG: [u8; 0x100] = hex_literal::hex!(
+            "a0023c82b7f976eb55604ec3199ceee4ff04efeed3180fc7fff3d064973c71f4bf7d3b76b26e348f6a195087f436d8a9aa8e628a04a1789c0e74cad06d7feb8138298ca47eb950d8e130880e7e6f823a93d01997fe907399aa22388d4fac421375c81ccdb4d4b690fdc78a4b370f117c20c71d6b31574101fe6d28f134c22b9b41844798eb832c998c7461dca512cc8ae497e74a070a068b3c46c34ff2d2d87e1d992fc2d67b0fb24fd6f599779e2f1a49cec92337b4d0a29a497432e423b4250cda688426612df501d16e001905ace28bf7e31093fcc713c89f9acfacaa74ecd705a3bfa50e58db356cf1f983007ebf88a6c7b0d9c7e8c30ea4f2e99638906d"
+        );
+        const CERT_SIG: [u8; 0x100] = hex_literal::hex!(
+            "065573a27062267e5977fe2ea687539c56f270be0d07ee623b6b92e21370e5f0262676baf3adc295c25a255f260a5d142782290a1beeca2d9f5d8747ae939682aa71cddd0d9cddbdace1acbc97fb5a74814c64c3d8fdfe3b0c474db123af04f7bbb436298daa89b92dd85d1a08af5b98b7ea3943227ee1a39563541e1f38ab7b7cf9831bbd135f4bd2ea22ee6ab7b73c4d48a1b70b49d0e3ad44c7380edc3e32368f4593224effb8480a329006713ae511c5d5a57e9c096408a7cc4bbb631792feb8cd1a82b34423d4496def21b0fa8199e4b8e93e1739fdc88684553ed53d599613902fe7ce00ee0940a3a99f164452e451482f16c831318a2c7761d3ad7e3b"
+        );
+
+        let mut header = test_header();
+        header.signature = HEADER_SIG;
+        let mut cert = test_cert();
+        cert.signature = CERT_SIG;
+
+        let mut keyset = Keyset::default();
+        let fixed_keys = keyset.fixed_keys();
+        assert!(matches!(
+            header.verify_signature(&fixed_keys),
+            SignatureStatus::Unchecked(_)
+        ));
+
+        keyset.raw_keys.insert(
+            "xci_header_fixed_key_modulus".to_string(),
+            TEST_RSA_MODULUS.to_vec().into(),
+        );
+        keyset.raw_keys.insert(
+            "xci_cert_fixed_key_modulus".to_string(),
+            TEST_RSA_MODULUS.to_vec().into(),
+        );
+        let fixed_keys = keyset.fixed_keys();
+        assert_eq!(header.verify_signature(&fixed_keys), SignatureStatus::Valid);
+        assert_eq!(cert.verify_signature(&fixed_keys), SignatureStatus::Valid);
+
+        header.package_id += 1;
+        cert.device_id[0] ^= 1;
+        assert_eq!(
+            header.verify_signature(&fixed_keys),
+            SignatureStatus::Invalid
+        );
+        assert_eq!(cert.verify_signature(&fixed_keys), SignatureStatus::Invalid);
+    }
+
+    #[test]
+    fn test_gamecard_cert_roundtrip() {
+        let bytes = test_cert().to_bytes();
+        assert_eq!(bytes.len(), 0x200);
+
+        let cert: GamecardCertificate = std::io::Cursor::new(&bytes).read_le().unwrap();
+        assert_eq!(cert.version, 1);
+        assert_eq!(cert.device_id, [0x11; 0x10]);
+        assert_eq!(cert.encrypted_data, [0x44; 0xC0]);
+    }
+
+    /// A small card image with 0x2200 bytes of valid data, padded to 0x4000 bytes
+    fn test_image(with_key_area: bool) -> Vec<u8> {
+        let mut header = test_header();
+        header.valid_data_end_address = 0x10;
+
+        let mut image = vec![0u8; if with_key_area { 0x1000 } else { 0 }];
+        image.extend(header.to_bytes());
+        image.resize(image.len() + 0x2000, 0xAB);
+        image.resize(image.len() + 0x1E00, PADDING_BYTE);
+        image
+    }
+
+    #[test]
+    fn test_rom_size_capacity() {
+        assert_eq!(RomSize::Size1Gb.capacity(), 0x3B800000);
+        assert_eq!(RomSize::Size32Gb.capacity(), 32 * 0x3B800000);
+    }
+
+    #[test]
+    fn test_trim() {
+        for with_key_area in [false, true] {
+            let image = test_image(with_key_area);
+            let mut xci = Xci::new(std::io::Cursor::new(image.clone())).unwrap();
+            assert_eq!(xci.key_area.is_some(), with_key_area);
+            assert_eq!(xci.header.package_id, 0x0123456789ABCDEF);
+
+            let mut trimmed = Vec::new();
+            let size = xci.trim(&mut trimmed).unwrap();
+            assert_eq!(size, xci.image_offset() + 0x2200);
+            assert_eq!(trimmed, image[..size as usize]);
+        }
+    }
+
+    #[test]
+    fn test_trim_refuses_data_in_padding() {
+        let mut image = test_image(false);
+        image[0x3000] = 0;
+        let mut xci = Xci::new(std::io::Cursor::new(image)).unwrap();
+
+        let mut trimmed = Vec::new();
+        let err = xci.trim(&mut trimmed).unwrap_err();
+        assert!(err.to_string().contains("0x3000"));
+        assert!(trimmed.is_empty());
+    }
+
+    #[test]
+    fn test_untrim() {
+        struct Counter(u64);
+        impl Write for Counter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0 += buf.len() as u64;
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let image = test_image(true);
+        let mut trimmed = Vec::new();
+        Xci::new(std::io::Cursor::new(image))
+            .unwrap()
+            .trim(&mut trimmed)
+            .unwrap();
+
+        let mut xci = Xci::new(std::io::Cursor::new(trimmed)).unwrap();
+        let mut counter = Counter(0);
+        let size = xci.untrim(&mut counter).unwrap();
+        assert_eq!(size, 0x1000 + 0x3B800000);
+        assert_eq!(counter.0, size);
+    }
+
+    #[test]
+    fn test_verify_hashes() {
+        let build = || {
+            let mut builder = XciBuilder::new(0);
+            builder
+                .add_nca(
+                    XciPartitionKind::Secure,
+                    "a.nca",
+                    std::io::Cursor::new(vec![0xAA; 0x400]),
+                )
+                .add_nca(
+                    XciPartitionKind::Secure,
+                    "b.nca",
+                    std::io::Cursor::new(vec![0xBB; 0x400]),
+                );
+            let mut image = Vec::new();
+            builder.write(&mut image).unwrap();
+            image
+        };
+
+        let mut xci = Xci::new(std::io::Cursor::new(build())).unwrap();
+        let report = xci.verify_hashes().unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.partitions.len(), 3);
+
+        // Change a byte in the hashed region of b.nca, and in the reserved field of the
+        // first root HFS0 entry
+        let mut image = build();
+        let b_offset = image.iter().position(|&b| b == 0xBB).unwrap();
+        image[b_offset + 0x10] = 0;
+        image[0xF028] = 1;
+
+        let mut xci = Xci::new(std::io::Cursor::new(image)).unwrap();
+        let report = xci.verify_hashes().unwrap();
+        assert!(!report.is_ok());
+        assert!(!report.root_header_valid);
+
+        let secure = report
+            .partitions
+            .iter()
+            .find(|p| p.name == "secure")
+            .unwrap();
+        assert!(secure.header_valid);
+        assert_eq!(
+            secure.files,
+            [
+                Hfs0FileVerification {
+                    name: "a.nca".to_string(),
+                    valid: true
+                },
+                Hfs0FileVerification {
+                    name: "b.nca".to_string(),
+                    valid: false
+                },
+            ]
+        );
+        assert!(report.to_string().contains("\n  b.nca: invalid"));
+
+        let mut xci = Xci::new(std::io::Cursor::new(build())).unwrap();
+        xci.header.hfs0_header_size = u64::MAX;
+        assert!(matches!(xci.verify_hashes(), Err(Error::InvalidData(_))));
+    }
+
+    #[test]
+    fn test_key_requirements_use_bundled_tickets() {
+        use crate::formats::nca::{RequiredKey, test_header};
+        use crate::formats::test_support::build_common_ticket;
+
+        let rights_id = hex_literal::hex!("0100000000001000000000000000000a");
+        let key_gen = test_header().get_key_generation();
+        let keyset = Keyset::from_reader(std::io::Cursor::new(format!(
+            "header_key = {}\ntitlekek_{key_gen:02x} = {}\n",
+            "11".repeat(0x20),
+            "22".repeat(0x10),
+        )))
+        .unwrap();
+
+        let mut header = test_header();
+        header.rights_id = rights_id;
+        let nca = header.to_bytes_encrypt(&keyset);
+
+        let build = |ticket_partition: Option<XciPartitionKind>| {
+            let mut builder = XciBuilder::new(0);
+            builder.add_nca(
+                XciPartitionKind::Secure,
+                "a.nca",
+                std::io::Cursor::new(nca.clone()),
+            );
+            if let Some(partition) = ticket_partition {
+                builder.add_nca(
+                    partition,
+                    "a.tik",
+                    std::io::Cursor::new(build_common_ticket(rights_id, [0x42; 0x10])),
+                );
+            }
+            let mut image = Vec::new();
+            builder.write(&mut image).unwrap();
+            Xci::new(std::io::Cursor::new(image)).unwrap()
+        };
+
+        let reqs = build(None).key_requirements(&keyset, None).unwrap();
+        assert_eq!(
+            reqs.missing().into_iter().collect::<Vec<_>>(),
+            vec![RequiredKey::TitleKey { rights_id }]
+        );
+
+        for partition in [XciPartitionKind::Secure, XciPartitionKind::Update] {
+            let reqs = build(Some(partition))
+                .key_requirements(&keyset, None)
+                .unwrap();
+            assert!(reqs.is_satisfied(), "{}", reqs);
+        }
+    }
+
+    #[test]
+    fn test_key_area() {
+        let mut key_area = KeyArea::new(0x0123456789ABCDEF);
+        key_area.challenge_response_auth_data =
+            hex_literal::hex!("cb22dd1237e48984d46185b52a3782a5");
+        key_area.challenge_response_auth_mac =
+            hex_literal::hex!("c998bc9009295184a84578dd629dc815");
+        key_area.challenge_response_auth_nonce = hex_literal::hex!("0102030405060708090a0b0c");
+
+        let mut header = test_header();
+        header.title_kek_index = 0x02;
+        header.initial_data_hash = key_area.initial_data_hash();
+
+        let mut image = key_area.to_bytes();
+        assert_eq!(image.len() as u64, KEY_AREA_SIZE);
+        image.extend(header.to_bytes());
+        image.resize(image.len() + 0x2000, PADDING_BYTE);
+
+        let xci = Xci::new(std::io::Cursor::new(image.clone())).unwrap();
+        let parsed = xci.key_area.as_ref().unwrap();
+        assert_eq!(parsed.package_id, 0x0123456789ABCDEF);
+        assert_eq!(parsed.to_bytes(), image[..0x1000]);
+        assert_eq!(xci.verify_initial_data(), Some(true));
+
+        let keyset = Keyset::from_reader(std::io::Cursor::new(format!(
+            "xci_t1_titlekey_kek_02 = {}\n",
+            "77".repeat(0x10)
+        )))
+        .unwrap();
+        assert_eq!(
+            xci.decrypt_initial_data_title_key(&keyset).unwrap(),
+            [0x5A; 0x10]
+        );
+        assert!(matches!(
+            xci.decrypt_initial_data_title_key(&Keyset::default()),
+            Err(Error::KeyLookupError(_))
+        ));
+
+        // A changed nonce fails both the hash and the MAC
+        image[0x30] ^= 1;
+        let xci = Xci::new(std::io::Cursor::new(image)).unwrap();
+        assert_eq!(xci.verify_initial_data(), Some(false));
+        assert!(matches!(
+            xci.decrypt_initial_data_title_key(&keyset),
+            Err(Error::CryptoError(_))
+        ));
+    }
+}
@@ -1,8 +1,11 @@
 use aes::Aes128;
 use cipher::KeyIvInit;
 use cipher::StreamCipher;
-use std::io::{self, Read, Result, Seek, SeekFrom};
+use std::fs::File;
+use std::io::{self, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use zeroize::Zeroize;
 
 /// Function to align down to 16-byte boundary for AES operations
 pub const fn align_down(value: u64, align: u64) -> u64 {
@@ -40,7 +43,7 @@ impl<R: Read + Seek> Clone for SharedReader<R> {
     }
 }
 
-impl<R: Read + Seek + Clone> SharedReader<R> {
+impl<R: Read + Seek> SharedReader<R> {
     /// Create a new shared reader from a regular reader
     pub fn new(reader: R) -> Self {
         Self {
@@ -199,6 +202,12 @@ impl<R: Read + Seek> Aes128CtrReader<R> {
     }
 }
 
+impl<R: Read + Seek> Drop for Aes128CtrReader<R> {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
 impl<R: Read + Seek> Read for Aes128CtrReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         // Get current position exactly like CNTX does
@@ -269,6 +278,258 @@ impl<R: Read + Seek> Seek for Aes128CtrReader<R> {
     }
 }
 
+/// Part size used by dumping tools for split dumps, just under the FAT32 file size limit
+pub const SPLIT_PART_SIZE: u64 = 0xFFFF0000;
+
+/// Presents an ordered list of parts as one seekable stream
+///
+/// Used to read dumps split into several files, see [`open_split`].
+pub struct ConcatReader<R: Read + Seek> {
+    parts: Vec<R>,
+    /// Offset of the start of each part, followed by the total size
+    offsets: Vec<u64>,
+    position: u64,
+}
+
+impl<R: Read + Seek> ConcatReader<R> {
+    /// Create a reader over the given parts, in order
+    pub fn new(mut parts: Vec<R>) -> Result<Self> {
+        let mut offsets = Vec::with_capacity(parts.len() + 1);
+        let mut total = 0;
+        for part in &mut parts {
+            offsets.push(total);
+            total += part.seek(SeekFrom::End(0))?;
+        }
+        offsets.push(total);
+
+        Ok(Self {
+            parts,
+            offsets,
+            position: 0,
+        })
+    }
+
+    /// Total size of all parts
+    pub fn size(&self) -> u64 {
+        *self.offsets.last().unwrap()
+    }
+
+    /// Number of parts
+    pub fn part_count(&self) -> usize {
+        self.parts.len()
+    }
+}
+
+impl<R: Read + Seek> Read for ConcatReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() || self.position >= self.size() {
+            return Ok(0);
+        }
+
+        // Last part starting at or before the position, skipping empty parts
+        let index = self
+            .offsets
+            .partition_point(|&offset| offset <= self.position)
+            - 1;
+        let part_offset = self.position - self.offsets[index];
+        let part_remaining = self.offsets[index + 1] - self.position;
+
+        let max_read = std::cmp::min(buf.len() as u64, part_remaining) as usize;
+        let part = &mut self.parts[index];
+        part.seek(SeekFrom::Start(part_offset))?;
+        let bytes_read = part.read(&mut buf[..max_read])?;
+
+        self.position += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+impl<R: Read + Seek> Seek for ConcatReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        self.position = new_pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot seek before the start of the stream",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+/// List the parts of a possibly split dump
+///
+/// Recognizes:
+/// - `.xc0`, `.xc1`, ... and `.ns0`, `.ns1`, ... files next to each other, including
+///   `.xc10` and up. Any part can be given, the list always starts at part 0.
+/// - Directories with the archive bit set, as made for FAT32 SD cards, holding parts
+///   named `00`, `01`, ... The archive bit can't be read portably, so a directory counts
+///   as a split dump if it holds a `00` file. Any other directory is an error.
+///
+/// Anything else is a single part.
+pub fn split_parts(path: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+
+    let part_path: Box<dyn Fn(usize) -> PathBuf> = if path.is_dir() {
+        if !path.join("00").is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is a directory without a 00 part, not a split dump",
+                    path.display()
+                ),
+            ));
+        }
+        Box::new(|index| path.join(format!("{:02}", index)))
+    } else if let Some(prefix) = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.trim_end_matches(|c: char| c.is_ascii_digit()))
+        .filter(|prefix| prefix.eq_ignore_ascii_case("xc") || prefix.eq_ignore_ascii_case("ns"))
+    {
+        let prefix = prefix.to_string();
+        Box::new(move |index| path.with_extension(format!("{}{}", prefix, index)))
+    } else {
+        return Ok(vec![path.to_path_buf()]);
+    };
+
+    let parts: Vec<_> = (0..)
+        .map(part_path)
+        .take_while(|part| part.is_file())
+        .collect();
+
+    if parts.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No parts found for split dump {}", path.display()),
+        ));
+    }
+    tracing::trace!("Found {} parts for {}", parts.len(), path.display());
+    Ok(parts)
+}
+
+/// Open a possibly split dump as one stream
+///
+/// See [`split_parts`] for the naming schemes recognized. The result can be passed to
+/// [`Xci::new`](crate::formats::xci::Xci::new) or
+/// [`Pfs0::from_reader`](crate::formats::pfs0::Pfs0::from_reader) directly.
+///
+/// # Example
+/// ```no_run
+/// # use nx_archive::formats::xci::Xci;
+/// # use nx_archive::io::open_split;
+/// let xci = Xci::new(open_split("game.xc0").unwrap()).unwrap();
+/// ```
+pub fn open_split(path: impl AsRef<Path>) -> Result<ConcatReader<File>> {
+    let parts = split_parts(path)?
+        .iter()
+        .map(File::open)
+        .collect::<Result<Vec<_>>>()?;
+    ConcatReader::new(parts)
+}
+
+/// Splits a stream into parts of a fixed size
+///
+/// A new part is created through the callback, with its index, whenever the current one
+/// is full. Parts are only created once there is data for them.
+pub struct SplitWriter<W: Write, F: FnMut(usize) -> Result<W>> {
+    create_part: F,
+    part_size: u64,
+    current: Option<W>,
+    /// Index of the next part to create
+    next_index: usize,
+    /// Bytes written to the current part
+    part_written: u64,
+}
+
+impl<W: Write, F: FnMut(usize) -> Result<W>> SplitWriter<W, F> {
+    /// Create a writer making parts of `part_size` bytes
+    pub fn new(part_size: u64, create_part: F) -> Self {
+        assert!(part_size > 0, "Part size must not be zero");
+        Self {
+            create_part,
+            part_size,
+            current: None,
+            next_index: 0,
+            part_written: 0,
+        }
+    }
+
+    /// Number of parts created so far
+    pub fn part_count(&self) -> usize {
+        self.next_index
+    }
+
+    /// Flush and close the last part
+    pub fn finish(mut self) -> Result<usize> {
+        if let Some(mut part) = self.current.take() {
+            part.flush()?;
+        }
+        Ok(self.next_index)
+    }
+}
+
+impl SplitWriter<File, Box<dyn FnMut(usize) -> Result<File>>> {
+    /// Create a writer making `.xc0`, `.xc1`, ... or `.ns0`, `.ns1`, ... files
+    ///
+    /// The naming follows the extension of `path`: `game.xci` is split into `game.xc0`,
+    /// `game.xc1`, and so on. This is what [`split_parts`] recognizes.
+    pub fn create(path: impl AsRef<Path>, part_size: u64) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let prefix = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("nsp") => "ns",
+            _ => "xc",
+        };
+
+        Self::new(
+            part_size,
+            Box::new(move |index| {
+                File::create(path.with_extension(format!("{}{}", prefix, index)))
+            }),
+        )
+    }
+}
+
+impl<W: Write, F: FnMut(usize) -> Result<W>> Write for SplitWriter<W, F> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.current.is_none() || self.part_written == self.part_size {
+            if let Some(mut part) = self.current.take() {
+                part.flush()?;
+            }
+            self.current = Some((self.create_part)(self.next_index)?);
+            self.next_index += 1;
+            self.part_written = 0;
+        }
+
+        let max_write =
+            std::cmp::min(buf.len() as u64, self.part_size - self.part_written) as usize;
+        let written = self
+            .current
+            .as_mut()
+            .expect("part was created")
+            .write(&buf[..max_write])?;
+
+        self.part_written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match &mut self.current {
+            Some(part) => part.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
 /// Extension trait for readers to easily convert them to shared readers
 pub trait ReaderExt: Read + Seek + Clone + Sized {
     /// Convert this reader into a shared reader
@@ -312,4 +573,86 @@ mod tests {
         println!("Decrypted: {}", String::from_utf8_lossy(&buf));
         assert_eq!(&buf, &test_data[..16]);
     }
+
+    #[test]
+    fn test_concat_reader() {
+        let parts = vec![
+            Cursor::new(b"0123".to_vec()),
+            Cursor::new(Vec::new()),
+            Cursor::new(b"456789".to_vec()),
+            Cursor::new(b"AB".to_vec()),
+        ];
+        let mut reader = ConcatReader::new(parts).unwrap();
+        assert_eq!(reader.size(), 12);
+
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).unwrap();
+        assert_eq!(all, b"0123456789AB");
+
+        reader.seek(SeekFrom::Start(3)).unwrap();
+        let mut buf = [0u8; 6];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"345678");
+
+        reader.seek(SeekFrom::End(-1)).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], b'B');
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_split_writer_and_parts() {
+        let dir = std::env::temp_dir().join(format!("nx-archive-split-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+
+        let mut writer = SplitWriter::create(dir.join("game.xci"), 300);
+        writer.write_all(&data).unwrap();
+        assert_eq!(writer.finish().unwrap(), 4);
+
+        let parts = split_parts(dir.join("game.xc2")).unwrap();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], dir.join("game.xc0"));
+        assert_eq!(std::fs::metadata(&parts[3]).unwrap().len(), 100);
+
+        let mut read = Vec::new();
+        open_split(dir.join("game.xc0"))
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+
+        // Archive bit directory
+        let archive = dir.join("game.nsp");
+        std::fs::create_dir_all(&archive).unwrap();
+        let mut writer = SplitWriter::new(600, |index| {
+            File::create(archive.join(format!("{:02}", index)))
+        });
+        writer.write_all(&data).unwrap();
+        writer.finish().unwrap();
+
+        let mut read = Vec::new();
+        open_split(&archive)
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+
+        // Part numbers past 9
+        let mut writer = SplitWriter::create(dir.join("many.nsp"), 90);
+        writer.write_all(&data).unwrap();
+        assert_eq!(writer.finish().unwrap(), 12);
+        let parts = split_parts(dir.join("many.ns10")).unwrap();
+        assert_eq!(parts.len(), 12);
+        assert_eq!(parts[0], dir.join("many.ns0"));
+        assert_eq!(parts[11], dir.join("many.ns11"));
+
+        // A directory without a 00 part isn't a split dump
+        let other = dir.join("other");
+        std::fs::create_dir_all(&other).unwrap();
+        std::fs::write(other.join("01"), b"x").unwrap();
+        assert!(split_parts(&other).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
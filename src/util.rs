@@ -2,12 +2,18 @@
 
 use std::io::{Read, Seek};
 
+/// Reads a NUL-padded string from a fixed-size field, replacing invalid UTF-8
+pub(crate) fn fixed_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
 /// A trait that combines Read and Seek, used to simplify type bounds.
 pub trait ReadSeek: Read + Seek {}
 impl<T: Read + Seek> ReadSeek for T {}
 
 use crate::{
-    formats::{Keyset, TitleKeys, cnmt::Cnmt},
+    formats::{Keyset, TitleKeys, cnmt::Cnmt, nca::KeyRequirements},
     io::SubFile,
 };
 
@@ -23,6 +29,19 @@ pub trait TitleDataExt {
     }
 }
 
+/// Reports which keys are needed to decrypt the NCAs in a container
+pub trait KeyRequirementsExt {
+    /// List the keys needed by every NCA, and which of them the given keys lack
+    ///
+    /// Unlike opening the NCAs, this doesn't fail when keys are missing. The missing
+    /// keys are what gets reported.
+    fn key_requirements(
+        &mut self,
+        keyset: &Keyset,
+        title_keys: Option<&TitleKeys>,
+    ) -> Result<KeyRequirements, crate::error::Error>;
+}
+
 pub trait FileEntryExt<R: Read + Seek> {
     type FS: VirtualFSExt<R>;
 